@@ -1,3 +1,6 @@
 fn main() {
-    embuild::espidf::sysenv::output();
+    /* the esp-idf environment is only needed when building for the board */
+    if std::env::var("CARGO_FEATURE_ESP").is_ok() {
+        embuild::espidf::sysenv::output();
+    }
 }
@@ -0,0 +1,51 @@
+use crate::battery::BatteryMonitor;
+use crate::config::config::*;
+use crate::delay::*;
+
+use esp_idf_svc::hal::adc::{
+    attenuation::DB_11,
+    oneshot::{
+        config::{AdcChannelConfig, Calibration},
+        AdcChannelDriver, AdcDriver,
+    },
+    ADC1,
+};
+use spin::Mutex;
+
+pub async fn battery_monitor(battery_level: &Mutex<u8>) -> ! {
+    /* the matrix takes the peripherals, the ADC and the battery pin are not part of it */
+    let adc = AdcDriver::new(unsafe { ADC1::new() }).expect("Not able to init the ADC.");
+
+    /* the calibration stored in the eFuse corrects the reading of each chip to millivolts */
+    let config = AdcChannelConfig {
+        attenuation: DB_11,
+        calibration: Calibration::Curve,
+        ..Default::default()
+    };
+    let mut battery_pin = AdcChannelDriver::new(&adc, unsafe { BatteryPin::new() }, &config)
+        .expect("Not able to set the battery pin as ADC input.");
+
+    let mut battery_monitor = BatteryMonitor::new();
+
+    loop {
+        match adc.read(&mut battery_pin) {
+            Ok(pin_mv) => {
+                let level = battery_monitor.update(pin_mv);
+
+                #[cfg(feature = "debug")]
+                log::info!("Battery level: {}%", level);
+
+                /* store the level, then release the lock */
+                if let Some(mut battery_level) = battery_level.try_lock() {
+                    *battery_level = level;
+                }
+            }
+            Err(_error) => {
+                #[cfg(feature = "debug")]
+                log::info!("Error reading the battery voltage: {:?}", _error);
+            }
+        }
+
+        delay_ms(BATTERY_SAMPLE_PERIOD.as_millis()).await;
+    }
+}
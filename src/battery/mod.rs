@@ -0,0 +1,164 @@
+/*
+Battery level of a single LiPo cell, measured through a voltage divider on an ADC pin.
+
+The calibrated ADC reading of the pin voltage is scaled by the divider to the battery voltage,
+smoothed and looked up in the discharge curve to get the percentage reported to the host.
+*/
+
+#[cfg(feature = "esp")]
+mod adc;
+
+#[cfg(feature = "esp")]
+pub use adc::*;
+
+use crate::config::config::{BATTERY_DIVIDER, BATTERY_SMOOTHING};
+
+/* the resting voltage of a LiPo cell against its charge, from full to empty */
+pub const LIPO_DISCHARGE_CURVE: [(u16, u8); 11] = [
+    (4200, 100),
+    (4100, 90),
+    (4000, 80),
+    (3920, 70),
+    (3860, 60),
+    (3820, 50),
+    (3790, 40),
+    (3760, 30),
+    (3730, 20),
+    (3680, 10),
+    (3300, 0),
+];
+
+/* the battery voltage before the divider */
+pub fn battery_mv(pin_mv: u16, divider: (u32, u32)) -> u16 {
+    (pin_mv as u32 * divider.0 / divider.1).min(u16::MAX as u32) as u16
+}
+
+/* interpolate the percentage between the points of the curve, the curve goes from full to empty */
+pub fn voltage_to_percentage(mv: u16, curve: &[(u16, u8)]) -> u8 {
+    let (Some(&(full_mv, full)), Some(&(empty_mv, empty))) = (curve.first(), curve.last()) else {
+        return 0;
+    };
+
+    if mv >= full_mv {
+        return full;
+    }
+
+    if mv <= empty_mv {
+        return empty;
+    }
+
+    for points in curve.windows(2) {
+        let ((high_mv, high), (low_mv, low)) = (points[0], points[1]);
+
+        if mv >= low_mv {
+            let span = (high_mv - low_mv) as u32;
+            let above = (mv - low_mv) as u32;
+            return low + ((high - low) as u32 * above / span) as u8;
+        }
+    }
+
+    empty
+}
+
+/* exponential moving average, a single reading does not move the reported level much */
+pub fn smooth(average: u16, sample: u16, smoothing: u16) -> u16 {
+    let average = average as i32;
+    let step = (sample as i32 - average) / smoothing.max(1) as i32;
+
+    (average + step) as u16
+}
+
+pub struct BatteryMonitor {
+    /* the smoothed battery voltage, None until the first sample */
+    average_mv: Option<u16>,
+}
+
+impl Default for BatteryMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BatteryMonitor {
+    pub fn new() -> Self {
+        BatteryMonitor { average_mv: None }
+    }
+
+    /* add a reading of the pin voltage and get the battery percentage */
+    pub fn update(&mut self, pin_mv: u16) -> u8 {
+        let sample_mv = battery_mv(pin_mv, BATTERY_DIVIDER);
+
+        let average_mv = match self.average_mv {
+            Some(average_mv) => smooth(average_mv, sample_mv, BATTERY_SMOOTHING),
+            None => sample_mv,
+        };
+        self.average_mv = Some(average_mv);
+
+        voltage_to_percentage(average_mv, &LIPO_DISCHARGE_CURVE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divider() {
+        assert_eq!(battery_mv(2000, (2, 1)), 4000);
+        assert_eq!(battery_mv(1500, (3, 2)), 2250);
+        assert_eq!(battery_mv(u16::MAX, (2, 1)), u16::MAX);
+    }
+
+    #[test]
+    fn discharge_curve() {
+        let table = [
+            /* past both ends */
+            (4300, 100),
+            (4200, 100),
+            (3300, 0),
+            (3000, 0),
+            /* on the points of the curve */
+            (4000, 80),
+            (3730, 20),
+            /* between the points */
+            (4150, 95),
+            (3960, 75),
+            (3490, 5),
+        ];
+
+        for (mv, percentage) in table {
+            assert_eq!(
+                voltage_to_percentage(mv, &LIPO_DISCHARGE_CURVE),
+                percentage,
+                "{} mV",
+                mv
+            );
+        }
+
+        assert_eq!(voltage_to_percentage(3800, &[]), 0);
+    }
+
+    #[test]
+    fn smoothing() {
+        assert_eq!(smooth(4000, 4080, 8), 4010);
+        assert_eq!(smooth(4000, 3920, 8), 3990);
+        assert_eq!(smooth(4000, 3000, 1), 3000);
+        /* no smoothing instead of a division by 0 */
+        assert_eq!(smooth(4000, 3000, 0), 3000);
+    }
+
+    #[test]
+    fn monitor() {
+        let mut battery_monitor = BatteryMonitor::new();
+
+        /* the first sample is taken as it is, pin voltage * 2 */
+        assert_eq!(battery_monitor.update(2000), 80);
+
+        /* a single low reading moves the level a little, 4000 - 700 / 8 mV */
+        assert_eq!(battery_monitor.update(1650), 68);
+
+        /* a lasting change is reached after a few samples */
+        let level = (0..50).map(|_| battery_monitor.update(1600)).last();
+        assert_eq!(level, Some(0));
+    }
+}
@@ -0,0 +1,343 @@
+/*
+Host-only keyboard simulator: replays scripted matrix events through the debounce selected with
+KeyDebouncer and the key processing, and prints the HID reports that would be sent to the host.
+
+to run: cargo run --bin simulator --no-default-features --features host,qwerty,left-side --target x86_64-unknown-linux-gnu -- script.txt
+
+Script format, statements are separated by ';' or new lines, '#' starts a comment:
+    t=0 press 1,3; t=40 release 1,3
+*/
+
+use anyhow::{anyhow, bail, Context};
+use embassy_time::Instant;
+use esp32_rustboard::config::config::{KeyDebouncer, DEBOUNCE_DELAY, TAPPING_TERM};
+use esp32_rustboard::config::enums::{HidKeys, KeyType};
+use esp32_rustboard::config::layers::{LayerState, Layers};
+use esp32_rustboard::debounce::Debouncer;
+use esp32_rustboard::hid::{ConsumerReport, HidSink, KeyReport, NkroReport};
+use esp32_rustboard::matrix::{Key, MatrixEvent};
+use esp32_rustboard::processor::KeyProcessor;
+use esp32_rustboard::storage::NoStore;
+use std::io::Read;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    Press,
+    Release,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ScriptEvent {
+    time_ms: u64,
+    action: Action,
+    key: Key,
+}
+
+/* prints every report that differs from the previous one */
+struct PrintingSink {
+    time_ms: u64,
+    last_report: KeyReport,
+    last_nkro_report: NkroReport,
+    last_consumer_report: ConsumerReport,
+}
+
+impl HidSink for PrintingSink {
+    fn send_report(&mut self, key_report: &KeyReport) {
+        if *key_report != self.last_report {
+            let modifiers = key_report.modifiers;
+            let keys = key_report.keys;
+            println!(
+                "t={:>6}ms  report   modifiers: {:#04x} keys: {:02x?}",
+                self.time_ms, modifiers, keys
+            );
+            self.last_report = *key_report;
+        }
+    }
+
+    fn send_nkro_report(&mut self, nkro_report: &NkroReport) {
+        if *nkro_report != self.last_nkro_report {
+            /* the pressed usages below the modifiers */
+            let keys: Vec<u8> = (0..0xE0)
+                .filter(|usage| nkro_report.is_pressed(*usage))
+                .collect();
+            println!(
+                "t={:>6}ms  nkro     modifiers: {:#04x} keys: {:02x?}",
+                self.time_ms,
+                nkro_report.modifiers(),
+                keys
+            );
+            self.last_nkro_report = *nkro_report;
+        }
+    }
+
+    fn send_consumer_report(&mut self, consumer_report: &ConsumerReport) {
+        if *consumer_report != self.last_consumer_report {
+            let usages = consumer_report.usages;
+            println!("t={:>6}ms  consumer usages: {:04x?}", self.time_ms, usages);
+            self.last_consumer_report = *consumer_report;
+        }
+    }
+}
+
+fn parse_statement(statement: &str) -> anyhow::Result<ScriptEvent> {
+    let mut tokens = statement.split_whitespace();
+
+    let time_ms = tokens
+        .next()
+        .and_then(|token| token.strip_prefix("t="))
+        .ok_or_else(|| anyhow!("expected 't=<ms>'"))?
+        .parse::<u64>()
+        .context("invalid time")?;
+
+    let action = match tokens.next() {
+        Some("press") => Action::Press,
+        Some("release") => Action::Release,
+        Some(other) => bail!("unknown action '{}'", other),
+        None => bail!("expected 'press' or 'release'"),
+    };
+
+    /* allow both "1,3" and "1, 3" */
+    let position: String = tokens.collect();
+    let (row, col) = position
+        .split_once(',')
+        .ok_or_else(|| anyhow!("expected '<row>,<col>'"))?;
+
+    Ok(ScriptEvent {
+        time_ms,
+        action,
+        key: Key::new(
+            row.trim().parse().context("invalid row")?,
+            col.trim().parse().context("invalid col")?,
+        ),
+    })
+}
+
+fn parse_script(script: &str) -> anyhow::Result<Vec<ScriptEvent>> {
+    let mut events = Vec::new();
+
+    for (line_number, line) in script.lines().enumerate() {
+        /* strip the comments */
+        let line = line.split('#').next().unwrap_or_default();
+
+        for statement in line.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+            events.push(
+                parse_statement(statement)
+                    .with_context(|| format!("line {}: '{}'", line_number + 1, statement))?,
+            );
+        }
+    }
+
+    /* replay in time order, statements at the same time keep their order */
+    events.sort_by_key(|event| event.time_ms);
+
+    Ok(events)
+}
+
+fn describe_key(layers: &Layers, valid_key: &HidKeys) -> String {
+    match KeyType::check_type(valid_key) {
+        KeyType::Macro => format!(
+            "{:?} (macro: {:?})",
+            valid_key,
+            HidKeys::get_macro_sequence(valid_key).as_slice()
+        ),
+        KeyType::TapHold => match layers.get_tap_hold(valid_key) {
+            Some(tap_hold) => format!(
+                "{:?} (tap: {:?}, hold: {:?})",
+                valid_key, tap_hold.tap, tap_hold.hold
+            ),
+            None => format!("{:?}", valid_key),
+        },
+        _ => format!("{:?}", valid_key),
+    }
+}
+
+fn print_layer_change(time_ms: u64, layer_before: &LayerState, layer_after: &LayerState) {
+    if layer_before != layer_after {
+        println!(
+            "t={:>6}ms  layer    {} (active {:#010b}, default {}) -> {} (active {:#010b}, default {})",
+            time_ms,
+            layer_before.highest().0,
+            layer_before.active,
+            layer_before.default.0,
+            layer_after.highest().0,
+            layer_after.active,
+            layer_after.default.0
+        );
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    /* read the script from the given file, or from stdin */
+    let script = match std::env::args().nth(1) {
+        Some(path) => {
+            std::fs::read_to_string(&path).with_context(|| format!("reading '{}'", path))?
+        }
+        None => {
+            let mut script = String::new();
+            std::io::stdin().read_to_string(&mut script)?;
+            script
+        }
+    };
+
+    let events = parse_script(&script)?;
+
+    /* load the layout selected by the features, a copy is kept to describe the keys */
+    let mut layers = Layers::new();
+    layers.load_layout(&mut NoStore);
+    let mut layout = Layers::new();
+    layout.load_layout(&mut NoStore);
+
+    let mut key_processor = KeyProcessor::new(layers);
+    let mut debouncer = KeyDebouncer::default();
+    let mut hid = PrintingSink {
+        time_ms: 0,
+        last_report: KeyReport::new(),
+        last_nkro_report: NkroReport::new(),
+        last_consumer_report: ConsumerReport::new(),
+    };
+
+    /* run the time based decisions every millisecond, like the board does */
+    let mut time_ms: u64 = 0;
+    let end_ms = events.last().map_or(0, |event| event.time_ms)
+        + DEBOUNCE_DELAY.as_millis()
+        + TAPPING_TERM.as_millis();
+
+    for event in events.iter().map(Some).chain(std::iter::once(None)) {
+        let next_ms = event.map_or(end_ms, |event| event.time_ms);
+
+        while time_ms <= next_ms {
+            hid.time_ms = time_ms;
+            let layer_before = *key_processor.layer_state();
+            for event in debouncer.expired(Instant::from_millis(time_ms)) {
+                key_processor.process_event(&event, &mut hid);
+            }
+            key_processor.tick(Instant::from_millis(time_ms), &mut hid);
+            print_layer_change(time_ms, &layer_before, key_processor.layer_state());
+            time_ms += 1;
+        }
+
+        let Some(event) = event else {
+            break;
+        };
+
+        let now = Instant::from_millis(event.time_ms);
+        hid.time_ms = event.time_ms;
+        let layer_before = *key_processor.layer_state();
+
+        /* the decisions due before the event come first, as in the processing task */
+        key_processor.tick(now, &mut hid);
+
+        match event.action {
+            Action::Press => {
+                println!(
+                    "t={:>6}ms  press    ({},{}) -> {}",
+                    event.time_ms,
+                    event.key.row,
+                    event.key.col,
+                    layout
+                        .get(&event.key.row, &event.key.col, &layer_before)
+                        .copied()
+                        .map_or("unmapped".to_string(), |valid_key| describe_key(
+                            &layout, &valid_key
+                        ))
+                );
+            }
+            Action::Release => {
+                println!(
+                    "t={:>6}ms  release  ({},{})",
+                    event.time_ms, event.key.row, event.key.col
+                );
+            }
+        }
+
+        /* the scanned change goes through the debounce, like on the board */
+        let matrix_event = MatrixEvent::new(event.key, event.action == Action::Press, now);
+        if let Some(matrix_event) = debouncer.update(&matrix_event) {
+            key_processor.process_event(&matrix_event, &mut hid);
+        }
+
+        print_layer_change(event.time_ms, &layer_before, key_processor.layer_state());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_statements() {
+        let event = parse_statement("t=40 press 1,3").unwrap();
+        assert_eq!(event.time_ms, 40);
+        assert_eq!(event.action, Action::Press);
+        assert_eq!(event.key, Key::new(1, 3));
+
+        let event = parse_statement("t=0   release 2, 11").unwrap();
+        assert_eq!(event.time_ms, 0);
+        assert_eq!(event.action, Action::Release);
+        assert_eq!(event.key, Key::new(2, 11));
+    }
+
+    #[test]
+    fn bad_keys() {
+        for statement in [
+            "t=0 press",
+            "t=0 press 1",
+            "t=0 press a",
+            "t=0 press 1,x",
+            "t=0 press x,1",
+            "t=0 press 1,300",
+            "t=0 tap 1,3",
+            "t=0",
+        ] {
+            assert!(parse_statement(statement).is_err(), "{}", statement);
+        }
+    }
+
+    #[test]
+    fn bad_timings() {
+        for statement in [
+            "press 1,3",
+            "0 press 1,3",
+            "t= press 1,3",
+            "t=-5 press 1,3",
+            "t=1.5 press 1,3",
+            "t=ten press 1,3",
+            "time=0 press 1,3",
+        ] {
+            assert!(parse_statement(statement).is_err(), "{}", statement);
+        }
+    }
+
+    /* comments and empty statements are skipped, the events are replayed in time order */
+    #[test]
+    fn complete_script() {
+        let events = parse_script(
+            "# tap two keys\n\
+             t=0 press 1,3; t=40 release 1,3\n\
+             \n\
+             t=20 press 2,0;; t=60 release 2,0 # the second key\n\
+             t=20 press 0,0",
+        )
+        .unwrap();
+
+        let events: Vec<(u64, Action, Key)> = events
+            .iter()
+            .map(|event| (event.time_ms, event.action, event.key))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (0, Action::Press, Key::new(1, 3)),
+                (20, Action::Press, Key::new(2, 0)),
+                (20, Action::Press, Key::new(0, 0)),
+                (40, Action::Release, Key::new(1, 3)),
+                (60, Action::Release, Key::new(2, 0)),
+            ]
+        );
+
+        let error = parse_script("t=0 press 1,3\nt=10 press 1,").unwrap_err();
+        assert!(error.to_string().starts_with("line 2:"), "{}", error);
+    }
+}
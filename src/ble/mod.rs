@@ -2,27 +2,35 @@
 #![allow(dead_code)]
 extern crate alloc;
 
-use crate::config::enums::{HidKeys, HidModifiers, KeyType};
-use crate::config::{config::*, layers::*};
-use crate::debounce::{Debounce, KEY_PRESSED, KEY_RELEASED};
+use crate::config::{config::*, enums::ProfileAction};
 use crate::delay::*;
-use crate::matrix::Key;
+use crate::events::{HidChannel, HidEvent, HostRequest, HostRequestChannel};
+use crate::hid::{BleStatus, ConsumerReport, HidSink, HostLedState, KeyReport, NkroReport};
+use crate::profiles::{profile_address, HostProfiles, ADDRESS_SIZE};
+use crate::storage::{command::KeymapCommand, KeymapStore, NvsKeymapStore};
+use crate::via::RAW_REPORT_SIZE;
 
 use alloc::sync::Arc;
+use embassy_futures::select::{select, Either};
 use esp32_nimble::{
-    enums::*, hid::*, utilities::mutex::Mutex, BLEAdvertisementData, BLECharacteristic, BLEDevice,
-    BLEHIDDevice, BLEServer,
+    enums::*, hid::*, utilities::mutex::Mutex, utilities::BleUuid, uuid128, BLEAddress,
+    BLEAdvertisementData, BLECharacteristic, BLEConnDesc, BLEDevice, BLEHIDDevice, BLEServer,
+    NimbleProperties,
 };
 use esp_idf_sys::{
     esp_ble_power_type_t_ESP_BLE_PWR_TYPE_ADV, esp_ble_power_type_t_ESP_BLE_PWR_TYPE_DEFAULT,
-    esp_ble_power_type_t_ESP_BLE_PWR_TYPE_SCAN,
+    esp_ble_power_type_t_ESP_BLE_PWR_TYPE_SCAN, esp_mac_type_t_ESP_MAC_BT, esp_read_mac,
 };
-use heapless::{FnvIndexMap, Vec};
+use heapless::Deque;
 use spin::Mutex as spinMutex;
-use zerocopy::{Immutable, IntoBytes};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex as stdMutex;
+use zerocopy::IntoBytes;
 
 const KEYBOARD_ID: u8 = 0x01;
 const MEDIA_KEYS_ID: u8 = 0x02;
+const RAW_HID_ID: u8 = 0x03;
+const NKRO_ID: u8 = 0x04;
 
 const HID_REPORT_DISCRIPTOR: &[u8] = hid!(
     (USAGE_PAGE, 0x01), // USAGE_PAGE (Generic Desktop Ctrls)
@@ -59,60 +67,167 @@ const HID_REPORT_DISCRIPTOR: &[u8] = hid!(
     (USAGE_MAXIMUM, 0x65), //   USAGE_MAXIMUM (0x65)
     (HIDINPUT, 0x00),  //   INPUT (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
     (END_COLLECTION),  // END_COLLECTION
-                       // // ------------------------------------------------- Media Keys
-                       // (USAGE_PAGE, 0x0C),         // USAGE_PAGE (Consumer)
-                       // (USAGE, 0x01),              // USAGE (Consumer Control)
-                       // (COLLECTION, 0x01),         // COLLECTION (Application)
-                       // (REPORT_ID, MEDIA_KEYS_ID), //   REPORT_ID (3)
-                       // (USAGE_PAGE, 0x0C),         //   USAGE_PAGE (Consumer)
-                       // (LOGICAL_MINIMUM, 0x00),    //   LOGICAL_MINIMUM (0)
-                       // (LOGICAL_MAXIMUM, 0x01),    //   LOGICAL_MAXIMUM (1)
-                       // (REPORT_SIZE, 0x01),        //   REPORT_SIZE (1)
-                       // (REPORT_COUNT, 0x10),       //   REPORT_COUNT (16)
-                       // (USAGE, 0xB5),              //   USAGE (Scan Next Track)     ; bit 0: 1
-                       // (USAGE, 0xB6),              //   USAGE (Scan Previous Track) ; bit 1: 2
-                       // (USAGE, 0xB7),              //   USAGE (Stop)                ; bit 2: 4
-                       // (USAGE, 0xCD),              //   USAGE (Play/Pause)          ; bit 3: 8
-                       // (USAGE, 0xE2),              //   USAGE (Mute)                ; bit 4: 16
-                       // (USAGE, 0xE9),              //   USAGE (Volume Increment)    ; bit 5: 32
-                       // (USAGE, 0xEA),              //   USAGE (Volume Decrement)    ; bit 6: 64
-                       // (USAGE, 0x23, 0x02),        //   Usage (WWW Home)            ; bit 7: 128
-                       // (USAGE, 0x94, 0x01),        //   Usage (My Computer) ; bit 0: 1
-                       // (USAGE, 0x92, 0x01),        //   Usage (Calculator)  ; bit 1: 2
-                       // (USAGE, 0x2A, 0x02),        //   Usage (WWW fav)     ; bit 2: 4
-                       // (USAGE, 0x21, 0x02),        //   Usage (WWW search)  ; bit 3: 8
-                       // (USAGE, 0x26, 0x02),        //   Usage (WWW stop)    ; bit 4: 16
-                       // (USAGE, 0x24, 0x02),        //   Usage (WWW back)    ; bit 5: 32
-                       // (USAGE, 0x83, 0x01),        //   Usage (Media sel)   ; bit 6: 64
-                       // (USAGE, 0x8A, 0x01),        //   Usage (Mail)        ; bit 7: 128
-                       // (HIDINPUT, 0x02), // INPUT (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-                       // (END_COLLECTION), // END_COLLECTION
+    // ------------------------------------------------- Media Keys
+    (USAGE_PAGE, 0x0C),            // USAGE_PAGE (Consumer)
+    (USAGE, 0x01),                 // USAGE (Consumer Control)
+    (COLLECTION, 0x01),            // COLLECTION (Application)
+    (REPORT_ID, MEDIA_KEYS_ID),    //   REPORT_ID (2)
+    (USAGE_PAGE, 0x0C),            //   USAGE_PAGE (Consumer)
+    (LOGICAL_MINIMUM, 0x00),       //   LOGICAL_MINIMUM (0)
+    (LOGICAL_MAXIMUM, 0xFF, 0x03), //   LOGICAL_MAXIMUM (0x3FF)
+    (USAGE_MINIMUM, 0x00),         //   USAGE_MINIMUM (0)
+    (USAGE_MAXIMUM, 0xFF, 0x03),   //   USAGE_MAXIMUM (0x3FF)
+    (REPORT_SIZE, 0x10),           //   REPORT_SIZE (16)
+    (REPORT_COUNT, 0x02),          //   REPORT_COUNT (2) ; 2 usages at once
+    (HIDINPUT, 0x00), //   INPUT (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    (END_COLLECTION), // END_COLLECTION
+    // ------------------------------------------------- Raw HID (VIA)
+    (USAGE_PAGE, 0x60, 0xFF),      // USAGE_PAGE (Vendor Defined 0xFF60)
+    (USAGE, 0x61),                 // USAGE (Vendor Usage 0x61)
+    (COLLECTION, 0x01),            // COLLECTION (Application)
+    (REPORT_ID, RAW_HID_ID),       //   REPORT_ID (3)
+    (USAGE, 0x62),                 //   USAGE (Vendor Usage 0x62) ; data in
+    (LOGICAL_MINIMUM, 0x00),       //   LOGICAL_MINIMUM (0)
+    (LOGICAL_MAXIMUM, 0xFF, 0x00), //   LOGICAL_MAXIMUM (255)
+    (REPORT_SIZE, 0x08),           //   REPORT_SIZE (8)
+    (REPORT_COUNT, 0x20),          //   REPORT_COUNT (32)
+    (HIDINPUT, 0x02), //   INPUT (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    (USAGE, 0x63),    //   USAGE (Vendor Usage 0x63) ; data out
+    (LOGICAL_MINIMUM, 0x00), //   LOGICAL_MINIMUM (0)
+    (LOGICAL_MAXIMUM, 0xFF, 0x00), //   LOGICAL_MAXIMUM (255)
+    (REPORT_SIZE, 0x08), //   REPORT_SIZE (8)
+    (REPORT_COUNT, 0x20), //   REPORT_COUNT (32)
+    (HIDOUTPUT, 0x02), //   OUTPUT (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+    (END_COLLECTION),  // END_COLLECTION
+    // ------------------------------------------------- N-Key Rollover
+    (USAGE_PAGE, 0x01),      // USAGE_PAGE (Generic Desktop Ctrls)
+    (USAGE, 0x06),           // USAGE (Keyboard)
+    (COLLECTION, 0x01),      // COLLECTION (Application)
+    (REPORT_ID, NKRO_ID),    //   REPORT_ID (4)
+    (USAGE_PAGE, 0x07),      //   USAGE_PAGE (Kbrd/Keypad)
+    (USAGE_MINIMUM, 0x00),   //   USAGE_MINIMUM (0)
+    (USAGE_MAXIMUM, 0xE7),   //   USAGE_MAXIMUM (0xE7) ; the modifiers are the last 8 bits
+    (LOGICAL_MINIMUM, 0x00), //   LOGICAL_MINIMUM (0)
+    (LOGICAL_MAXIMUM, 0x01), //   LOGICAL_MAXIMUM (1)
+    (REPORT_SIZE, 0x01),     //   REPORT_SIZE (1)
+    (REPORT_COUNT, 0xE8),    //   REPORT_COUNT (232) ; one bit for every usage
+    (HIDINPUT, 0x02), //   INPUT (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    (END_COLLECTION), // END_COLLECTION
 );
 
-#[derive(IntoBytes, Immutable)]
-#[repr(packed, C)]
-struct KeyReport {
-    modifiers: u8,
-    reserved: u8,
-    keys: [u8; 6],
+/* keymap commands written by the host, filled from the NimBLE host task */
+static KEYMAP_COMMANDS: stdMutex<Deque<KeymapCommand, KEYMAP_COMMANDS_QUEUE_SIZE>> =
+    stdMutex::new(Deque::new());
+
+fn keymap_service_uuid() -> BleUuid {
+    uuid128!("5d6a0b40-6f3c-4c8e-9e7a-52b1f3a7c101")
+}
+
+fn keymap_characteristic_uuid() -> BleUuid {
+    uuid128!("5d6a0b40-6f3c-4c8e-9e7a-52b1f3a7c102")
+}
+
+fn on_keymap_write(data: &[u8]) {
+    match KeymapCommand::decode(data) {
+        Ok(command) => {
+            if let Ok(mut keymap_commands) = KEYMAP_COMMANDS.lock() {
+                if keymap_commands.push_back(command).is_err() {
+                    #[cfg(feature = "debug")]
+                    log::info!("Keymap command queue full, command dropped!");
+                }
+            }
+        }
+        Err(_error) => {
+            #[cfg(feature = "debug")]
+            log::info!("Invalid keymap command: {:?}", _error);
+        }
+    }
+}
+
+/* VIA requests written by the host, filled from the NimBLE host task */
+static VIA_REQUESTS: stdMutex<Deque<[u8; RAW_REPORT_SIZE], VIA_REQUESTS_QUEUE_SIZE>> =
+    stdMutex::new(Deque::new());
+
+fn on_raw_hid_write(data: &[u8]) {
+    /* the shorter reports are padded with zeros, like the host does */
+    let mut request = [0; RAW_REPORT_SIZE];
+    let len = data.len().min(RAW_REPORT_SIZE);
+    request[..len].copy_from_slice(&data[..len]);
+
+    if let Ok(mut via_requests) = VIA_REQUESTS.lock() {
+        if via_requests.push_back(request).is_err() {
+            #[cfg(feature = "debug")]
+            log::info!("VIA request queue full, request dropped!");
+        }
+    }
+}
+
+/* set when the host selects the boot protocol, only the boot keyboard report is understood then */
+static BOOT_PROTOCOL: AtomicBool = AtomicBool::new(false);
+
+fn on_protocol_mode_write(data: &[u8]) {
+    /* 0 is the boot protocol, 1 the report protocol */
+    if let Some(&protocol_mode) = data.first() {
+        BOOT_PROTOCOL.store(protocol_mode == 0, Ordering::Relaxed);
+    }
+}
+
+/* the LED bits of the last keyboard output report written by the host */
+static HOST_LEDS: AtomicU8 = AtomicU8::new(0);
+
+fn on_keyboard_output_write(data: &[u8]) {
+    /* the report id is not part of the written value */
+    if let Some(&leds) = data.first() {
+        HOST_LEDS.store(leds, Ordering::Relaxed);
+    }
+}
+
+/* hosts that completed the pairing, filled from the NimBLE host task */
+static AUTHENTICATED_HOSTS: stdMutex<Deque<(u16, [u8; ADDRESS_SIZE]), PROFILE_EVENTS_QUEUE_SIZE>> =
+    stdMutex::new(Deque::new());
+
+fn on_authentication_complete(desc: &BLEConnDesc) {
+    /* the identity address stays the same when the host address is resolvable */
+    let host = desc.id_address().as_le_bytes();
+
+    if let Ok(mut authenticated_hosts) = AUTHENTICATED_HOSTS.lock() {
+        if authenticated_hosts
+            .push_back((desc.conn_handle(), host))
+            .is_err()
+        {
+            #[cfg(feature = "debug")]
+            log::info!("Profile event queue full, host {:02x?} dropped!", host);
+        }
+    }
+}
+
+/* the address of the ble controller, every profile address is derived from it */
+fn base_address() -> [u8; ADDRESS_SIZE] {
+    let mut mac = [0; ADDRESS_SIZE];
+    unsafe {
+        esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_BT);
+    }
+
+    /* the mac is read most significant byte first, the ble addresses are little endian */
+    mac.reverse();
+    mac
 }
 
 pub struct BleKeyboard {
     server: &'static mut BLEServer,
     input_keyboard: Arc<Mutex<BLECharacteristic>>,
     output_keyboard: Arc<Mutex<BLECharacteristic>>,
+    input_boot_keyboard: Arc<Mutex<BLECharacteristic>>,
+    output_boot_keyboard: Arc<Mutex<BLECharacteristic>>,
     input_media_keys: Arc<Mutex<BLECharacteristic>>,
-    key_report: KeyReport,
-}
-
-#[derive(Clone, Copy, Debug)]
-pub enum BleStatus {
-    Connected,
-    NotConnected,
+    input_nkro: Arc<Mutex<BLECharacteristic>>,
+    input_raw_hid: Arc<Mutex<BLECharacteristic>>,
+    /* the hid device, for the battery level updates */
+    hid: BLEHIDDevice,
 }
 
 impl BleKeyboard {
-    pub fn new() -> Self {
+    pub fn new(profiles: &HostProfiles) -> Self {
         let device = BLEDevice::take();
         device
             .security()
@@ -120,12 +235,46 @@ impl BleKeyboard {
             .set_io_cap(SecurityIOCap::NoInputNoOutput)
             .resolve_rpa();
 
+        /* every profile is a different device for the hosts */
+        device.set_own_addr_type(OwnAddrType::Random);
+        device
+            .set_rnd_addr(profile_address(base_address(), profiles.selected()))
+            .expect("Error setting the profile address!");
+
         let server = device.get_server();
+        server.on_authentication_complete(|desc, result| {
+            if result.is_ok() {
+                on_authentication_complete(desc);
+            }
+        });
         let mut hid = BLEHIDDevice::new(server);
 
         let input_keyboard = hid.input_report(KEYBOARD_ID);
         let output_keyboard = hid.output_report(KEYBOARD_ID);
+        /* the host on the boot protocol ignores the report map and only uses these two */
+        let input_boot_keyboard = hid.boot_input();
+        let output_boot_keyboard = hid.boot_output();
         let input_media_keys = hid.input_report(MEDIA_KEYS_ID);
+        let input_nkro = hid.input_report(NKRO_ID);
+        let input_raw_hid = hid.input_report(RAW_HID_ID);
+        let output_raw_hid = hid.output_report(RAW_HID_ID);
+
+        output_raw_hid
+            .lock()
+            .on_write(|args| on_raw_hid_write(args.recv_data()));
+
+        output_keyboard
+            .lock()
+            .on_write(|args| on_keyboard_output_write(args.recv_data()));
+
+        /* the boot keyboard output report carries the same LED bits */
+        output_boot_keyboard
+            .lock()
+            .on_write(|args| on_keyboard_output_write(args.recv_data()));
+
+        hid.protocol_mode()
+            .lock()
+            .on_write(|args| on_protocol_mode_write(args.recv_data()));
 
         hid.manufacturer("Espressif");
         hid.pnp(0x02, 0x05ac, 0x820a, 0x0210);
@@ -133,13 +282,22 @@ impl BleKeyboard {
 
         hid.report_map(HID_REPORT_DISCRIPTOR);
 
+        /* full until the battery monitor takes its first sample */
         hid.set_battery_level(100);
 
-        #[cfg(feature = "left-side")]
-        let name = "RUSTBOARD_LEFT";
+        /* the keymap can be remapped by the host, only over an encrypted link */
+        server
+            .create_service(keymap_service_uuid())
+            .lock()
+            .create_characteristic(
+                keymap_characteristic_uuid(),
+                NimbleProperties::WRITE | NimbleProperties::WRITE_ENC,
+            )
+            .lock()
+            .on_write(|args| on_keymap_write(args.recv_data()));
 
-        #[cfg(feature = "right-side")]
-        let name = "RUSTBOARD_RIGHT";
+        /* only the central half talks to the host, both halves appear as one keyboard */
+        let name = "RUSTBOARD";
 
         let ble_advertising = device.get_advertising();
         ble_advertising
@@ -158,12 +316,12 @@ impl BleKeyboard {
             server,
             input_keyboard,
             output_keyboard,
+            input_boot_keyboard,
+            output_boot_keyboard,
             input_media_keys,
-            key_report: KeyReport {
-                modifiers: 0,
-                reserved: 0,
-                keys: [0; 6],
-            },
+            input_nkro,
+            input_raw_hid,
+            hid,
         }
     }
 
@@ -171,12 +329,159 @@ impl BleKeyboard {
         self.server.connected_count() > 0
     }
 
-    fn send_report(&mut self) {
-        self.input_keyboard
-            .lock()
-            .set_value(&self.key_report.as_bytes()) // .set_from(&self.key_report)
-            .notify();
-        esp_idf_svc::hal::delay::Ets::delay_ms(1);
+    /* drop the connected hosts, they connect again if they are bonded to the profile */
+    fn disconnect(&mut self) {
+        let conn_handles: heapless::Vec<u16, PROFILE_EVENTS_QUEUE_SIZE> = self
+            .server
+            .connections()
+            .map(|desc| desc.conn_handle())
+            .take(PROFILE_EVENTS_QUEUE_SIZE)
+            .collect();
+
+        for conn_handle in conn_handles {
+            self.server.disconnect(conn_handle).ok();
+        }
+    }
+
+    /* advertise with the address of the selected profile */
+    fn advertise_profile(&mut self, profiles: &HostProfiles) {
+        let device = BLEDevice::take();
+        let ble_advertising = device.get_advertising();
+
+        self.disconnect();
+        ble_advertising.lock().stop().ok();
+
+        if device
+            .set_rnd_addr(profile_address(base_address(), profiles.selected()))
+            .is_err()
+        {
+            #[cfg(feature = "debug")]
+            log::info!(
+                "Error setting the address of profile {}",
+                profiles.selected()
+            );
+        }
+
+        /* the split link connects with the public address, the hosts only see the profile address */
+        device.set_own_addr_type(OwnAddrType::Random);
+        ble_advertising.lock().start().ok();
+    }
+
+    /* delete the bond of a host that is no longer in a profile */
+    fn delete_bond(&mut self, host: &[u8; ADDRESS_SIZE]) {
+        let device = BLEDevice::take();
+
+        let bonded_address = device.bonded_addresses().ok().and_then(|addresses| {
+            addresses
+                .into_iter()
+                .find(|address: &BLEAddress| address.as_le_bytes() == *host)
+        });
+
+        if let Some(address) = bonded_address {
+            if let Err(_error) = device.delete_bond(&address) {
+                #[cfg(feature = "debug")]
+                log::info!("Error deleting the bond of {:02x?}: {:?}", host, _error);
+            }
+        }
+    }
+
+    /* deliver a report to the host, the profile keys work without a host */
+    fn handle_hid_event<S: KeymapStore>(
+        &mut self,
+        event: HidEvent,
+        profiles: &mut HostProfiles,
+        store: &mut S,
+    ) {
+        match event {
+            HidEvent::Profile(action, profile) => {
+                self.apply_profile_action((action, profile), profiles, store);
+            }
+            /* the reports of the keys pressed while no host is connected are dropped */
+            _ if !self.connected() => {}
+            HidEvent::Keys(key_report) => self.send_report(&key_report),
+            HidEvent::Nkro(nkro_report) => self.send_nkro_report(&nkro_report),
+            HidEvent::Consumer(consumer_report) => self.send_consumer_report(&consumer_report),
+            HidEvent::Raw(report) => self.send_raw_report(&report),
+        }
+    }
+
+    /* apply a profile key, the profiles are saved when they change */
+    fn apply_profile_action<S: KeymapStore>(
+        &mut self,
+        action: (ProfileAction, u8),
+        profiles: &mut HostProfiles,
+        store: &mut S,
+    ) {
+        #[cfg(feature = "debug")]
+        log::info!("Profile action: {:?}", action);
+
+        match action {
+            (ProfileAction::Select, profile) => {
+                if profiles.select(profile) {
+                    self.advertise_profile(profiles);
+                }
+            }
+            (ProfileAction::Clear, profile) => {
+                if let Some(host) = profiles.clear(profile) {
+                    self.delete_bond(&host);
+                }
+
+                if profile == profiles.selected() {
+                    self.disconnect();
+                }
+            }
+            (ProfileAction::Pairing, _) => {
+                /* the bonded host is dropped so a new host can connect */
+                profiles.start_pairing();
+                self.disconnect();
+            }
+        }
+
+        profiles.save(store);
+    }
+
+    /* bond the hosts that paired to the selected profile, drop the hosts of other profiles */
+    fn check_authenticated_hosts<S: KeymapStore>(
+        &mut self,
+        profiles: &mut HostProfiles,
+        store: &mut S,
+    ) {
+        let authenticated_host = AUTHENTICATED_HOSTS
+            .try_lock()
+            .ok()
+            .and_then(|mut authenticated_hosts| authenticated_hosts.pop_front());
+
+        if let Some((conn_handle, host)) = authenticated_host {
+            if !profiles.accepts(&host) {
+                #[cfg(feature = "debug")]
+                log::info!(
+                    "Host {:02x?} not bonded to profile {}",
+                    host,
+                    profiles.selected()
+                );
+
+                self.server.disconnect(conn_handle).ok();
+                return;
+            }
+
+            if let Some(replaced) = profiles.bond(host) {
+                self.delete_bond(&replaced);
+            }
+
+            profiles.save(store);
+        }
+    }
+
+    pub fn host_leds(&self) -> HostLedState {
+        HostLedState::from_report(HOST_LEDS.load(Ordering::Relaxed))
+    }
+
+    fn set_battery_level(&mut self, level: u8) {
+        self.hid.set_battery_level(level);
+    }
+
+    fn send_raw_report(&mut self, report: &[u8]) {
+        self.input_raw_hid.lock().set_value(report).notify();
     }
 
     fn set_ble_power_save(&mut self) {
@@ -198,129 +503,109 @@ impl BleKeyboard {
     }
 }
 
-fn send_keys(ble_keyboard: &mut BleKeyboard, valid_key: &HidKeys, layer_state: &mut Layer) {
-    /* get the key type */
-    match KeyType::check_type(valid_key) {
-        KeyType::Macro => {
-            let macro_valid_keys = HidKeys::get_macro_sequence(valid_key);
-            for valid_key in macro_valid_keys.iter() {
-                send_keys(ble_keyboard, valid_key, layer_state);
-            }
-        }
-        KeyType::Layer => {
-            /* check and set the layer */
-            *layer_state = Layer::Upper;
-
-            /* release all keys */
-            ble_keyboard
-                .key_report
-                .keys
-                .iter_mut()
-                .for_each(|value| *value = 0);
-
-            /* release modifiers */
-            ble_keyboard.key_report.modifiers = 0;
-        }
-        KeyType::Modifier => {
-            ble_keyboard.key_report.modifiers |= HidModifiers::get_modifier(valid_key);
-        }
-        KeyType::Key => {
-            /* check if the key count is less than 6 */
-            if !ble_keyboard.key_report.keys.contains(&(*valid_key as u8)) {
-                /* find the first key slot in the array that is
-                 * free */
-                match ble_keyboard
-                    .key_report
-                    .keys
-                    .iter()
-                    .position(|&value| value == 0)
-                {
-                    Some(index) => {
-                        /* add the new key to that position */
-                        ble_keyboard.key_report.keys[index] = *valid_key as u8
-                    }
-                    None => { /* there is no free key slot available */ }
-                }
-            }
-        }
+impl HidSink for BleKeyboard {
+    fn send_report(&mut self, key_report: &KeyReport) {
+        /* the boot keyboard report is the same 8 bytes, without the report id */
+        let input_keyboard = if BOOT_PROTOCOL.load(Ordering::Relaxed) {
+            &self.input_boot_keyboard
+        } else {
+            &self.input_keyboard
+        };
+
+        input_keyboard
+            .lock()
+            .set_value(key_report.as_bytes()) // .set_from(&self.key_report)
+            .notify();
+        esp_idf_svc::hal::delay::Ets::delay_ms(1);
+    }
+
+    fn send_nkro_report(&mut self, nkro_report: &NkroReport) {
+        self.input_nkro
+            .lock()
+            .set_value(nkro_report.as_bytes())
+            .notify();
+        esp_idf_svc::hal::delay::Ets::delay_ms(1);
+    }
+
+    fn send_consumer_report(&mut self, consumer_report: &ConsumerReport) {
+        self.input_media_keys
+            .lock()
+            .set_value(consumer_report.as_bytes())
+            .notify();
+        esp_idf_svc::hal::delay::Ets::delay_ms(1);
     }
 }
 
-fn remove_keys(ble_keyboard: &mut BleKeyboard, valid_key: &HidKeys, layer_state: &mut Layer) {
-    /* get the key type */
-    match KeyType::check_type(valid_key) {
-        KeyType::Macro => {
-            let macro_valid_keys = HidKeys::get_macro_sequence(valid_key);
-            for valid_key in macro_valid_keys.iter() {
-                remove_keys(ble_keyboard, valid_key, layer_state);
-            }
-        }
-        KeyType::Layer => {
-            /* check and set the layer */
-            *layer_state = Layer::Base;
-
-            /* release all keys */
-            ble_keyboard
-                .key_report
-                .keys
-                .iter_mut()
-                .for_each(|value| *value = 0);
-
-            /* release modifiers */
-            ble_keyboard.key_report.modifiers = 0;
-        }
-        KeyType::Modifier => {
-            /* remove the modifier */
-            ble_keyboard.key_report.modifiers &= !HidModifiers::get_modifier(valid_key);
-        }
-        KeyType::Key => {
-            /* find the key slot of the released key */
-            match ble_keyboard
-                .key_report
-                .keys
-                .iter()
-                .position(|&value| value == *valid_key as u8)
-            {
-                Some(index) => {
-                    /* remove the key from the key slot */
-                    ble_keyboard.key_report.keys[index] = 0
-                }
-                None => { /* do nothing */ }
-            }
+/* move the host writes queued by the NimBLE host task to the processing task, as long as there is room */
+fn forward_host_requests<T: Copy, const N: usize>(
+    queue: &stdMutex<Deque<T, N>>,
+    host_requests: &HostRequestChannel,
+    host_request: impl Fn(T) -> HostRequest,
+) {
+    if let Ok(mut queue) = queue.try_lock() {
+        while !host_requests.is_full() {
+            match queue.pop_front() {
+                Some(value) => host_requests.try_send(host_request(value)).ok(),
+                None => break,
+            };
         }
     }
 }
 
 pub async fn ble_send_keys(
-    keys_pressed: &spinMutex<FnvIndexMap<Key, Debounce, PRESSED_KEYS_INDEXMAP_SIZE>>,
+    mut store: NvsKeymapStore,
+    hid_events: &HidChannel,
+    host_requests: &HostRequestChannel,
     ble_status: &spinMutex<BleStatus>,
+    host_leds: &spinMutex<HostLedState>,
+    battery_level: &spinMutex<u8>,
 ) -> ! {
-    /* construct ble */
-    let mut ble_keyboard = BleKeyboard::new();
-
-    /* initialize layers */
-    let mut layers = Layers::new();
+    /* the host profiles, with the profile selected before the reboot */
+    let mut profiles = HostProfiles::load(&mut store);
 
-    /* load the specified layout */
-    layers.load_layout();
+    /* construct ble */
+    let mut ble_keyboard = BleKeyboard::new(&profiles);
 
-    /* layer state */
-    let mut layer_state = Layer::Base;
+    /* the protocol last forwarded to the processing task */
+    let mut boot_protocol: bool = false;
 
-    /* vec to store the keys needed to be removed */
-    let mut pressed_keys_to_remove: Vec<Key, 6> = Vec::new();
+    /* the battery level last reported to the host */
+    let mut battery_level_local: u8 = 100;
 
     /* flag to set the power mode of the esp */
     let mut power_save_flag: bool = true;
 
     /* Run the main loop */
     loop {
-        if ble_keyboard.connected() {
-            /* check and store the ble status, then release the lock */
-            if let Some(mut ble_status) = ble_status.try_lock() {
-                *ble_status = BleStatus::Connected;
+        ble_keyboard.check_authenticated_hosts(&mut profiles, &mut store);
+
+        /* check and store the ble status, then release the lock */
+        if let Some(mut ble_status) = ble_status.try_lock() {
+            *ble_status = profiles.status(ble_keyboard.connected());
+        }
+
+        /* update the battery service when the battery monitor measured a new level */
+        if let Some(battery_level) = battery_level.try_lock() {
+            if *battery_level != battery_level_local {
+                battery_level_local = *battery_level;
+                ble_keyboard.set_battery_level(battery_level_local);
             }
+        }
+
+        /* the keymap commands and the VIA requests are applied by the processing task */
+        forward_host_requests(&KEYMAP_COMMANDS, host_requests, HostRequest::Keymap);
+        forward_host_requests(&VIA_REQUESTS, host_requests, HostRequest::Via);
+
+        let boot_protocol_host = BOOT_PROTOCOL.load(Ordering::Relaxed);
+        if boot_protocol_host != boot_protocol
+            && host_requests
+                .try_send(HostRequest::BootProtocol(boot_protocol_host))
+                .is_ok()
+        {
+            boot_protocol = boot_protocol_host;
+        }
 
+        if ble_keyboard.connected() {
             /* check if power save has been set */
             if power_save_flag {
                 /* set ble power to lowest possible */
@@ -329,76 +614,38 @@ pub async fn ble_send_keys(
                 power_save_flag = false;
             }
 
-            /* try to lock the hashmap */
-            if let Some(mut keys_pressed) = keys_pressed.try_lock() {
-                /* check if there are pressed keys */
-                if !keys_pressed.is_empty() {
-                    /* iter trough the pressed keys */
-                    for (key, debounce) in keys_pressed.iter_mut() {
-                        /*check the key debounce state */
-                        match debounce.key_state {
-                            KEY_PRESSED => {
-                                /* get the pressed key */
-                                if let Some(valid_key) =
-                                    layers.get(&key.row, &key.col, &layer_state)
-                                {
-                                    send_keys(&mut ble_keyboard, valid_key, &mut layer_state);
-                                }
-                            }
-                            /* check if the key is calculated for debounce */
-                            KEY_RELEASED => {
-                                /* get the mapped key from the hashmap */
-                                if let Some(valid_key) =
-                                    layers.get(&key.row, &key.col, &layer_state)
-                                {
-                                    remove_keys(&mut ble_keyboard, valid_key, &mut layer_state);
-                                }
-                                /* if key has been debounced, add it to be removed */
-                                pressed_keys_to_remove
-                                    .push(*key)
-                                    .expect("Error adding a key to be removed!");
-                            }
-
-                            _ => { /* do nothing */ }
-                        }
-                    }
-
+            /* share the lock LEDs set by the host */
+            let host_leds_local = ble_keyboard.host_leds();
+            if let Some(mut host_leds) = host_leds.try_lock() {
+                if *host_leds != host_leds_local {
                     #[cfg(feature = "debug")]
-                    /* debug log */
-                    log::info!(
-                        "ble_keyboard.key_report.keys: {:?}",
-                        ble_keyboard.key_report.keys
-                    );
-
-                    /* sent the new report */
-                    ble_keyboard.send_report();
-
-                    /* remove the sent keys and empty the vec */
-                    while let Some(key) = pressed_keys_to_remove.pop() {
-                        keys_pressed.remove(&key).unwrap();
-                    }
+                    log::info!("Host LEDs: {:?}", host_leds_local);
+
+                    *host_leds = host_leds_local;
                 }
             }
-            /* there must be a delay so the WDT in not triggered */
-            delay_ms(1).await;
         } else {
             #[cfg(feature = "debug")]
             /* debug log */
             log::info!("Keyboard not connected!");
 
-            /* check and store the ble status, then release the lock */
-            if let Some(mut ble_status) = ble_status.try_lock() {
-                *ble_status = BleStatus::NotConnected;
-            }
-
             /* check the power save flag */
             if !power_save_flag {
                 /* if false, set to true */
                 power_save_flag = true;
             }
+        }
+
+        /* wait for the reports, the host writes are checked at least every 10ms (100ms when not connected) */
+        let timeout = if ble_keyboard.connected() { 10 } else { 100 };
 
-            /* sleep for 100ms */
-            delay_ms(100).await;
+        if let Either::First(event) = select(hid_events.receive(), delay_ms(timeout)).await {
+            ble_keyboard.handle_hid_event(event, &mut profiles, &mut store);
+
+            /* the reports built from one event go out together */
+            while let Ok(event) = hid_events.try_receive() {
+                ble_keyboard.handle_hid_event(event, &mut profiles, &mut store);
+            }
         }
     }
 }
@@ -1,5 +1,6 @@
 use embassy_time::Duration;
 
+#[cfg(feature = "esp")]
 use esp_idf_sys::{
     esp_power_level_t_ESP_PWR_LVL_N0, esp_power_level_t_ESP_PWR_LVL_N12,
     esp_power_level_t_ESP_PWR_LVL_N15, esp_power_level_t_ESP_PWR_LVL_N18,
@@ -12,14 +13,128 @@ use esp_idf_sys::{
 };
 
 /* USER CONFIGURABLE PARAMETERS */
-pub const ROWS: usize = 4;
-pub const COLS: usize = 6;
+
+/* BOARD DEFINITION, the gpio numbers of the matrix of one half */
+pub const ROW_PINS: [i32; 4] = [0, 1, 2, 3];
+pub const COL_PINS: [i32; 6] = [21, 20, 10, 7, 6, 5];
+pub const DIODE_DIRECTION: DiodeDirection = DiodeDirection::Row2Col;
+pub const ROWS: usize = ROW_PINS.len();
+pub const COLS: usize = COL_PINS.len();
+
 pub const DEBOUNCE_DELAY: Duration = Duration::from_millis(50);
+pub type KeyDebouncer = crate::debounce::EagerDebouncer; /* EagerDebouncer, DeferredDebouncer or IntegratorDebouncer */
+pub const IDLE_DELAY: Duration = Duration::from_millis(5000); /* the scan rate drops after 5 seconds without a key */
+pub const IDLE_SCAN_PERIOD: Duration = Duration::from_millis(20);
 pub const SLEEP_DELAY: Duration = Duration::from_millis(300000); /* 5 minutes */
 pub const SLEEP_DELAY_INIT: Duration = Duration::from_millis(60000); /* 1 minute */
-pub const PRESSED_KEYS_INDEXMAP_SIZE: usize = 16;
-pub const LAYER_INDEXMAP_SIZE: usize = 32;
+pub const DEEP_SLEEP_DELAY: Duration = Duration::from_millis(1800000); /* 30 minutes */
+pub const DEEP_SLEEP_WAKE_PINS: [i32; 1] = [5]; /* the read lines that wake the chip from deep sleep, gpio 0 - 5 only */
+pub const RESUME_TIMEOUT: Duration = Duration::from_millis(1000); /* the ble host has to be back by then, or the chip restarts */
+pub const MATRIX_EVENTS_QUEUE_SIZE: usize = 32;
+pub const HID_EVENTS_QUEUE_SIZE: usize = 32;
+pub const HOST_REQUESTS_QUEUE_SIZE: usize = 8;
+pub const NKRO: bool = true; /* start with the n-key rollover report, switched at runtime with the report mode keys */
+pub const LAYERS: usize = 4;
+pub const LAYER_INDEXMAP_SIZE: usize = 64;
+pub const TAP_HOLD_KEYS: usize = 8;
+pub const TAP_HOLD_BUFFER_SIZE: usize = 16;
+pub const TAPPING_TERM: Duration = Duration::from_millis(200);
+pub const PERMISSIVE_HOLD: bool = false; /* hold when another key is pressed and released inside the tapping term */
+pub const HOLD_ON_OTHER_KEY_PRESS: bool = false; /* hold as soon as another key is pressed */
+pub const COMBOS: usize = 16;
+pub const COMBO_KEYS: usize = 4; /* the most keys of a combo */
+pub const COMBO_TERM: Duration = Duration::from_millis(30); /* the keys of a combo are pressed inside it */
+pub const TAP_DANCE_KEYS: usize = 8;
+pub const TAP_DANCE_TAPS: usize = 4; /* the most tap counts of a tap dance key */
+pub const MACROS: usize = 16;
+pub const MACRO_QUEUE_SIZE: usize = 4; /* the macro keys pressed while a macro plays */
+pub const MACRO_REPORTS_PER_TICK: usize = 16; /* a long macro is sent in parts, so the reports of other keys get in between */
+pub const DYNAMIC_MACROS: usize = 2; /* the slots recorded from the keyboard */
+pub const DYNAMIC_MACRO_SIZE: usize = 64; /* the key presses and releases of a slot */
+pub const DYNAMIC_MACROS_SAVED: bool = true; /* the recorded slots are kept in the NVS between boots */
+pub const HOST_LAYOUT: HostLayout = HostLayout::Us; /* the keyboard layout set on the host, for the text of the macros */
+pub const ONE_SHOT_TIMEOUT: Duration = Duration::from_millis(3000); /* a one shot key not used by then is cancelled */
+pub const KEYMAP_COMMANDS_QUEUE_SIZE: usize = 8;
+pub const VIA_MACRO_COUNT: u8 = 0; /* the macro keys play the layout macros, VIA does not edit them */
+pub const VIA_MACRO_BUFFER_SIZE: usize = 512;
+pub const VIA_REQUESTS_QUEUE_SIZE: usize = 4;
+pub const VIA_SAVE_DELAY: Duration = Duration::from_millis(1000); /* after the last keymap change */
+pub const HOST_PROFILES: usize = 3; /* up to 5, one bonded host per profile */
+pub const PROFILE_EVENTS_QUEUE_SIZE: usize = 4;
+pub const SPLIT_EVENTS_QUEUE_SIZE: usize = 32;
+pub const SPLIT_SCAN_TIMEOUT_MS: i32 = 5000;
 pub const ESP_POWER_LEVEL: EspPowerLevel = EspPowerLevel::Negative0;
+#[cfg(feature = "esp")]
+pub type BatteryPin = esp_idf_svc::hal::gpio::Gpio4; /* an ADC1 pin, not used by the matrix */
+pub const BATTERY_DIVIDER: (u32, u32) = (2, 1); /* battery voltage = pin voltage * 2 / 1 */
+pub const BATTERY_SAMPLE_PERIOD: Duration = Duration::from_millis(60000); /* 1 minute */
+pub const BATTERY_SMOOTHING: u16 = 8; /* every sample moves the average by 1/8 of the difference */
+
+/* a matrix pin is either a row or a column, once */
+const _: () = assert!(
+    pins_unique(&ROW_PINS, &COL_PINS),
+    "a gpio is used twice in ROW_PINS and COL_PINS"
+);
+
+const fn pins_unique(rows: &[i32], cols: &[i32]) -> bool {
+    let mut i = 0;
+    while i < rows.len() + cols.len() {
+        let pin = if i < rows.len() {
+            rows[i]
+        } else {
+            cols[i - rows.len()]
+        };
+
+        let mut j = i + 1;
+        while j < rows.len() + cols.len() {
+            let other = if j < rows.len() {
+                rows[j]
+            } else {
+                cols[j - rows.len()]
+            };
+            if pin == other {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+/* the way the diodes of the matrix point, the driven lines are set high and the read lines pulled down */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiodeDirection {
+    /* from the row to the column: the rows are driven, the columns are read */
+    Row2Col,
+    /* from the column to the row: the columns are driven, the rows are read */
+    Col2Row,
+}
+
+impl DiodeDirection {
+    /* the gpios that are set high one after the other while scanning */
+    pub const fn driven_pins(self) -> &'static [i32] {
+        match self {
+            DiodeDirection::Row2Col => &ROW_PINS,
+            DiodeDirection::Col2Row => &COL_PINS,
+        }
+    }
+
+    /* the gpios that are read, they also wake the chip from light sleep */
+    pub const fn read_pins(self) -> &'static [i32] {
+        match self {
+            DiodeDirection::Row2Col => &COL_PINS,
+            DiodeDirection::Col2Row => &ROW_PINS,
+        }
+    }
+}
+
+/* the keyboard layout the host translates the keys with */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostLayout {
+    Us,
+    Uk,
+}
 
 pub enum EspPowerLevel {
     Negative24,
@@ -40,6 +155,7 @@ pub enum EspPowerLevel {
     Positive21,
 }
 
+#[cfg(feature = "esp")]
 impl EspPowerLevel {
     pub fn convert(self) -> u32 {
         match self {
@@ -62,3 +178,20 @@ impl EspPowerLevel {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_pins_rejected() {
+        assert!(pins_unique(&ROW_PINS, &COL_PINS));
+        assert!(pins_unique(&[0, 1], &[2, 3]));
+
+        /* twice in the rows, twice in the columns, a row that is also a column */
+        assert!(!pins_unique(&[0, 0], &[2, 3]));
+        assert!(!pins_unique(&[0, 1], &[3, 3]));
+        assert!(!pins_unique(&[0, 1], &[2, 1]));
+        assert!(!pins_unique(&[4], &[4]));
+    }
+}
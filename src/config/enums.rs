@@ -1,10 +1,17 @@
 /* Scan codes - HID Keyboard: https://gist.github.com/MightyPork/6da26e382a7ad91b5496ee55fdc73db2 */
 
+use crate::config::{
+    config::{HostLayout, DYNAMIC_MACROS, HOST_PROFILES, LAYERS, MACROS},
+    layers::Layer,
+};
 use heapless::Vec;
+use zerocopy::{IntoBytes, TryFromBytes};
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(TryFromBytes, Clone, Copy, Debug, PartialEq)]
+#[repr(u16)]
 pub enum HidKeys {
     None = 0x00,
+    Transparent = 0x01, /* use the key of the next active layer below */
     Undefined = 0x03,
     A = 0x04,
     B = 0x05,
@@ -168,7 +175,7 @@ pub enum HidKeys {
     Crsel = 0xA3,
     Exsel = 0xA4,
 
-    /* dummy layer */
+    /* dummy layer, same as LayerMomentary1 */
     LayerKey = 0xA5,
 
     /* dummy modifiers */
@@ -190,6 +197,144 @@ pub enum HidKeys {
     MacroCaret = 0xD0,
     MacroAmpersand = 0xD1,
     MacroStar = 0xD2,
+
+    /* dummy tap hold keys, the tap and hold keys are set in the layout */
+    TapHold0 = 0xD3,
+    TapHold1 = 0xD4,
+    TapHold2 = 0xD5,
+    TapHold3 = 0xD6,
+    TapHold4 = 0xD7,
+    TapHold5 = 0xD8,
+    TapHold6 = 0xD9,
+    TapHold7 = 0xDA,
+
+    MacroColon = 0xDB,
+
+    /* dummy layer keys, the low nibble is the layer */
+    LayerMomentary0 = 0x0200,
+    LayerMomentary1 = 0x0201,
+    LayerMomentary2 = 0x0202,
+    LayerMomentary3 = 0x0203,
+    LayerMomentary4 = 0x0204,
+    LayerMomentary5 = 0x0205,
+    LayerMomentary6 = 0x0206,
+    LayerMomentary7 = 0x0207,
+
+    LayerToggle0 = 0x0210,
+    LayerToggle1 = 0x0211,
+    LayerToggle2 = 0x0212,
+    LayerToggle3 = 0x0213,
+    LayerToggle4 = 0x0214,
+    LayerToggle5 = 0x0215,
+    LayerToggle6 = 0x0216,
+    LayerToggle7 = 0x0217,
+
+    LayerTo0 = 0x0220,
+    LayerTo1 = 0x0221,
+    LayerTo2 = 0x0222,
+    LayerTo3 = 0x0223,
+    LayerTo4 = 0x0224,
+    LayerTo5 = 0x0225,
+    LayerTo6 = 0x0226,
+    LayerTo7 = 0x0227,
+
+    LayerDefault0 = 0x0230,
+    LayerDefault1 = 0x0231,
+    LayerDefault2 = 0x0232,
+    LayerDefault3 = 0x0233,
+    LayerDefault4 = 0x0234,
+    LayerDefault5 = 0x0235,
+    LayerDefault6 = 0x0236,
+    LayerDefault7 = 0x0237,
+
+    /* dummy consumer keys, sent in the consumer control report */
+    MediaNextTrack = 0x0300,
+    MediaPreviousTrack = 0x0301,
+    MediaStop = 0x0302,
+    MediaPlayPause = 0x0303,
+    MediaMute = 0x0304,
+    MediaVolumeUp = 0x0305,
+    MediaVolumeDown = 0x0306,
+    MediaBrightnessUp = 0x0307,
+    MediaBrightnessDown = 0x0308,
+    MediaMail = 0x0309,
+    MediaCalculator = 0x030A,
+    MediaMyComputer = 0x030B,
+    MediaWwwSearch = 0x030C,
+    MediaWwwHome = 0x030D,
+    MediaWwwBack = 0x030E,
+    MediaWwwForward = 0x030F,
+
+    /* dummy report mode keys, switch between the 6 key and the n-key rollover report */
+    ReportModeSixKro = 0x0400,
+    ReportModeNkro = 0x0401,
+    ReportModeToggle = 0x0402,
+
+    /* dummy host profile keys, select or clear the bond of a profile, pair a new host */
+    ProfileSelect0 = 0x0500,
+    ProfileSelect1 = 0x0501,
+    ProfileSelect2 = 0x0502,
+    ProfileSelect3 = 0x0503,
+    ProfileSelect4 = 0x0504,
+    ProfileClear0 = 0x0510,
+    ProfileClear1 = 0x0511,
+    ProfileClear2 = 0x0512,
+    ProfileClear3 = 0x0513,
+    ProfileClear4 = 0x0514,
+    ProfilePairing = 0x0520,
+
+    /* dummy power key, puts the half to deep sleep once every key is released */
+    PowerDeepSleep = 0x0600,
+
+    /* dummy one shot keys, applied to the next key, a double tap locks them, the low nibble is the modifier or the layer */
+    OneShotControl = 0x0701,
+    OneShotShift = 0x0702,
+    OneShotAlt = 0x0704,
+    OneShotSuper = 0x0708,
+
+    OneShotLayer0 = 0x0710,
+    OneShotLayer1 = 0x0711,
+    OneShotLayer2 = 0x0712,
+    OneShotLayer3 = 0x0713,
+    OneShotLayer4 = 0x0714,
+    OneShotLayer5 = 0x0715,
+    OneShotLayer6 = 0x0716,
+    OneShotLayer7 = 0x0717,
+
+    /* dummy tap dance keys, the keys of every tap count are set next to the layout */
+    TapDance0 = 0x0800,
+    TapDance1 = 0x0801,
+    TapDance2 = 0x0802,
+    TapDance3 = 0x0803,
+    TapDance4 = 0x0804,
+    TapDance5 = 0x0805,
+    TapDance6 = 0x0806,
+    TapDance7 = 0x0807,
+
+    /* dummy macro keys, the steps of every macro are set next to the layout */
+    Macro0 = 0x0900,
+    Macro1 = 0x0901,
+    Macro2 = 0x0902,
+    Macro3 = 0x0903,
+    Macro4 = 0x0904,
+    Macro5 = 0x0905,
+    Macro6 = 0x0906,
+    Macro7 = 0x0907,
+    Macro8 = 0x0908,
+    Macro9 = 0x0909,
+    Macro10 = 0x090A,
+    Macro11 = 0x090B,
+    Macro12 = 0x090C,
+    Macro13 = 0x090D,
+    Macro14 = 0x090E,
+    Macro15 = 0x090F,
+
+    /* dummy dynamic macro keys, record the keys sent into a slot till the stop key, play a slot back */
+    DynamicMacroRecord0 = 0x0A00,
+    DynamicMacroRecord1 = 0x0A01,
+    DynamicMacroStop = 0x0A10,
+    DynamicMacroPlay0 = 0x0A20,
+    DynamicMacroPlay1 = 0x0A21,
 }
 
 pub enum KeyType {
@@ -197,6 +342,16 @@ pub enum KeyType {
     Modifier,
     Key,
     Layer,
+    TapHold,
+    Consumer,
+    ReportMode,
+    Profile,
+    Power,
+    OneShot,
+    TapDance,
+    MacroSequence,
+    DynamicMacro,
+    Unused, /* the dummy keys of the features that are not configured, they do nothing */
 }
 
 impl KeyType {
@@ -213,20 +368,170 @@ impl KeyType {
             | HidKeys::MacroModul
             | HidKeys::MacroCaret
             | HidKeys::MacroAmpersand
-            | HidKeys::MacroStar => KeyType::Macro,
+            | HidKeys::MacroStar
+            | HidKeys::MacroColon => KeyType::Macro,
 
             HidKeys::LayerKey => KeyType::Layer,
 
+            key if LayerAction::get_layer_action(&key).is_some() => KeyType::Layer,
+
+            /* the layer keys above the configured layers */
+            key if key as u16 & 0xFFC0 == 0x0200 => KeyType::Unused,
+
+            HidKeys::TapHold0
+            | HidKeys::TapHold1
+            | HidKeys::TapHold2
+            | HidKeys::TapHold3
+            | HidKeys::TapHold4
+            | HidKeys::TapHold5
+            | HidKeys::TapHold6
+            | HidKeys::TapHold7 => KeyType::TapHold,
+
+            HidKeys::TapDance0
+            | HidKeys::TapDance1
+            | HidKeys::TapDance2
+            | HidKeys::TapDance3
+            | HidKeys::TapDance4
+            | HidKeys::TapDance5
+            | HidKeys::TapDance6
+            | HidKeys::TapDance7 => KeyType::TapDance,
+
             HidKeys::ModifierShift
             | HidKeys::ModifierControl
             | HidKeys::ModifierAlt
             | HidKeys::ModifierSuper => KeyType::Modifier,
 
+            key if HidConsumer::get_consumer_usage(&key) != 0 => KeyType::Consumer,
+
+            HidKeys::ReportModeSixKro | HidKeys::ReportModeNkro | HidKeys::ReportModeToggle => {
+                KeyType::ReportMode
+            }
+
+            key if ProfileAction::get_profile_action(&key).is_some() => KeyType::Profile,
+
+            /* the profile keys above the configured profiles */
+            key if key as u16 & 0xFF00 == 0x0500 => KeyType::Unused,
+
+            HidKeys::PowerDeepSleep => KeyType::Power,
+
+            key if OneShot::get_one_shot(&key).is_some() => KeyType::OneShot,
+
+            /* the one shot layer keys above the configured layers */
+            key if key as u16 & 0xFFF0 == 0x0710 => KeyType::Unused,
+
+            key if HidKeys::get_macro_index(&key).is_some() => KeyType::MacroSequence,
+
+            /* the macro keys above the configured macros */
+            key if key as u16 & 0xFFF0 == 0x0900 => KeyType::Unused,
+
+            key if DynamicMacroAction::get_dynamic_macro_action(&key).is_some() => {
+                KeyType::DynamicMacro
+            }
+
+            /* the dynamic macro keys above the configured slots */
+            key if key as u16 & 0xFF00 == 0x0A00 => KeyType::Unused,
+
             _ => KeyType::Key,
         }
     }
 }
 
+pub enum LayerAction {
+    Momentary, /* active while held */
+    Toggle,    /* switched on and off with every press */
+    To,        /* the only active layer above the default layer */
+    Default,   /* the layer everything falls through to */
+}
+
+impl LayerAction {
+    pub fn get_layer_action(key: &HidKeys) -> Option<(LayerAction, Layer)> {
+        if *key == HidKeys::LayerKey {
+            return Some((LayerAction::Momentary, Layer::UPPER));
+        }
+
+        let code = *key as u16;
+        let layer = Layer((code & 0x000F) as u8);
+
+        /* the layer keys exist for 8 layers, only the configured ones are used */
+        if code & 0x000F >= LAYERS as u16 {
+            return None;
+        }
+
+        match code & 0xFFF0 {
+            0x0200 => Some((LayerAction::Momentary, layer)),
+            0x0210 => Some((LayerAction::Toggle, layer)),
+            0x0220 => Some((LayerAction::To, layer)),
+            0x0230 => Some((LayerAction::Default, layer)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProfileAction {
+    Select,  /* switch to the host of the profile */
+    Clear,   /* forget the host bonded to the profile */
+    Pairing, /* accept a new host on the selected profile */
+}
+
+impl ProfileAction {
+    pub fn get_profile_action(key: &HidKeys) -> Option<(ProfileAction, u8)> {
+        let code = *key as u16;
+        let profile = (code & 0x000F) as u8;
+
+        /* the profile keys exist for 5 profiles, only the configured ones are used */
+        match code & 0xFFF0 {
+            0x0500 if (profile as usize) < HOST_PROFILES => Some((ProfileAction::Select, profile)),
+            0x0510 if (profile as usize) < HOST_PROFILES => Some((ProfileAction::Clear, profile)),
+            0x0520 if profile == 0 => Some((ProfileAction::Pairing, 0)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OneShot {
+    Modifiers(u8), /* the HidModifiers bits, applied to the next key */
+    Layer(Layer),  /* active for the next key */
+}
+
+impl OneShot {
+    pub fn get_one_shot(key: &HidKeys) -> Option<OneShot> {
+        let code = *key as u16;
+
+        /* the one shot layer keys exist for 8 layers, only the configured ones are used */
+        match code & 0xFFF0 {
+            0x0700 if code & 0x000F != 0 => Some(OneShot::Modifiers((code & 0x000F) as u8)),
+            0x0710 if ((code & 0x000F) as usize) < LAYERS => {
+                Some(OneShot::Layer(Layer((code & 0x000F) as u8)))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DynamicMacroAction {
+    Record(usize), /* record the keys sent into the slot, a second record key stops */
+    Stop,          /* stop the recording */
+    Play(usize),   /* play the recorded slot */
+}
+
+impl DynamicMacroAction {
+    pub fn get_dynamic_macro_action(key: &HidKeys) -> Option<DynamicMacroAction> {
+        let code = *key as u16;
+        let slot = (code & 0x000F) as usize;
+
+        /* the dynamic macro keys exist for 2 slots, only the configured ones are used */
+        match code & 0xFFF0 {
+            0x0A00 if slot < DYNAMIC_MACROS => Some(DynamicMacroAction::Record(slot)),
+            0x0A10 if slot == 0 => Some(DynamicMacroAction::Stop),
+            0x0A20 if slot < DYNAMIC_MACROS => Some(DynamicMacroAction::Play(slot)),
+            _ => None,
+        }
+    }
+}
+
 pub enum HidModifiers {
     None = 0x00,
     Control = 0x01,
@@ -247,7 +552,132 @@ impl HidModifiers {
     }
 }
 
+/* Consumer page usages: https://usb.org/sites/default/files/hut1_5.pdf (chapter 15) */
+pub enum HidConsumer {
+    None = 0x0000,
+    ScanNextTrack = 0x00B5,
+    ScanPreviousTrack = 0x00B6,
+    Stop = 0x00B7,
+    PlayPause = 0x00CD,
+    Mute = 0x00E2,
+    VolumeIncrement = 0x00E9,
+    VolumeDecrement = 0x00EA,
+    BrightnessIncrement = 0x006F,
+    BrightnessDecrement = 0x0070,
+    Mail = 0x018A,
+    Calculator = 0x0192,
+    MyComputer = 0x0194,
+    WwwSearch = 0x0221,
+    WwwHome = 0x0223,
+    WwwBack = 0x0224,
+    WwwForward = 0x0225,
+}
+
+impl HidConsumer {
+    pub fn get_consumer_usage(key: &HidKeys) -> u16 {
+        /* set the consumer usage */
+        match *key {
+            HidKeys::MediaNextTrack => HidConsumer::ScanNextTrack as u16,
+            HidKeys::MediaPreviousTrack => HidConsumer::ScanPreviousTrack as u16,
+            HidKeys::MediaStop => HidConsumer::Stop as u16,
+            HidKeys::MediaPlayPause => HidConsumer::PlayPause as u16,
+            HidKeys::MediaMute => HidConsumer::Mute as u16,
+            HidKeys::MediaVolumeUp => HidConsumer::VolumeIncrement as u16,
+            HidKeys::MediaVolumeDown => HidConsumer::VolumeDecrement as u16,
+            HidKeys::MediaBrightnessUp => HidConsumer::BrightnessIncrement as u16,
+            HidKeys::MediaBrightnessDown => HidConsumer::BrightnessDecrement as u16,
+            HidKeys::MediaMail => HidConsumer::Mail as u16,
+            HidKeys::MediaCalculator => HidConsumer::Calculator as u16,
+            HidKeys::MediaMyComputer => HidConsumer::MyComputer as u16,
+            HidKeys::MediaWwwSearch => HidConsumer::WwwSearch as u16,
+            HidKeys::MediaWwwHome => HidConsumer::WwwHome as u16,
+            HidKeys::MediaWwwBack => HidConsumer::WwwBack as u16,
+            HidKeys::MediaWwwForward => HidConsumer::WwwForward as u16,
+            _ => HidConsumer::None as u16,
+        }
+    }
+}
+
 impl HidKeys {
+    /* the key with the given code, used by the stored keymaps */
+    pub fn from_code(code: u16) -> Option<HidKeys> {
+        HidKeys::try_read_from_bytes(code.as_bytes()).ok()
+    }
+
+    /* the index of a macro key in the macro table */
+    pub fn get_macro_index(key: &HidKeys) -> Option<usize> {
+        /* the macro keys exist for 16 macros, only the configured ones are used */
+        let index = (*key as u16).checked_sub(HidKeys::Macro0 as u16)? as usize;
+        (index < 16.min(MACROS)).then_some(index)
+    }
+
+    /* the key that types the character on the host layout, and if shift is needed */
+    pub fn from_ascii(character: u8, host_layout: HostLayout) -> Option<(HidKeys, bool)> {
+        /* the keys that differ on the uk layout */
+        if host_layout == HostLayout::Uk {
+            match character {
+                b'"' => return Some((HidKeys::Num2, true)),
+                b'@' => return Some((HidKeys::Quote, true)),
+                b'#' => return Some((HidKeys::NonusHash, false)),
+                b'~' => return Some((HidKeys::NonusHash, true)),
+                b'\\' => return Some((HidKeys::NonusBslash, false)),
+                b'|' => return Some((HidKeys::NonusBslash, true)),
+                _ => {}
+            }
+        }
+
+        let key = match character {
+            b'a'..=b'z' => {
+                return Some((HidKeys::from_code(0x04 + (character - b'a') as u16)?, false))
+            }
+            b'A'..=b'Z' => {
+                return Some((HidKeys::from_code(0x04 + (character - b'A') as u16)?, true))
+            }
+            b'1'..=b'9' => {
+                return Some((HidKeys::from_code(0x1E + (character - b'1') as u16)?, false))
+            }
+            b'0' => (HidKeys::Num0, false),
+            b'!' => (HidKeys::Num1, true),
+            b'@' => (HidKeys::Num2, true),
+            b'#' => (HidKeys::Num3, true),
+            b'$' => (HidKeys::Num4, true),
+            b'%' => (HidKeys::Num5, true),
+            b'^' => (HidKeys::Num6, true),
+            b'&' => (HidKeys::Num7, true),
+            b'*' => (HidKeys::Num8, true),
+            b'(' => (HidKeys::Num9, true),
+            b')' => (HidKeys::Num0, true),
+            b'\n' => (HidKeys::Enter, false),
+            b'\t' => (HidKeys::Tab, false),
+            b' ' => (HidKeys::Space, false),
+            b'-' => (HidKeys::Minus, false),
+            b'_' => (HidKeys::Minus, true),
+            b'=' => (HidKeys::Equal, false),
+            b'+' => (HidKeys::Equal, true),
+            b'[' => (HidKeys::Lbracket, false),
+            b'{' => (HidKeys::Lbracket, true),
+            b']' => (HidKeys::Rbracket, false),
+            b'}' => (HidKeys::Rbracket, true),
+            b'\\' => (HidKeys::Backslash, false),
+            b'|' => (HidKeys::Backslash, true),
+            b';' => (HidKeys::SemiColon, false),
+            b':' => (HidKeys::SemiColon, true),
+            b'\'' => (HidKeys::Quote, false),
+            b'"' => (HidKeys::Quote, true),
+            b'`' => (HidKeys::Grave, false),
+            b'~' => (HidKeys::Grave, true),
+            b',' => (HidKeys::Comma, false),
+            b'<' => (HidKeys::Comma, true),
+            b'.' => (HidKeys::Period, false),
+            b'>' => (HidKeys::Period, true),
+            b'/' => (HidKeys::Slash, false),
+            b'?' => (HidKeys::Slash, true),
+            _ => return None,
+        };
+
+        Some(key)
+    }
+
     pub fn get_macro_sequence(key: &HidKeys) -> Vec<HidKeys, 16> {
         let mut vec: Vec<HidKeys, 16> = Vec::new();
 
@@ -313,7 +743,59 @@ impl HidKeys {
                 vec.push(HidKeys::Num9).unwrap();
                 vec
             }
+            HidKeys::MacroColon => {
+                vec.push(HidKeys::ModifierShift).unwrap();
+                vec.push(HidKeys::SemiColon).unwrap();
+                vec
+            }
             _ => vec,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* the character, the layout of the host, the key and if shift is needed */
+    type AsciiCase = (u8, HostLayout, Option<(HidKeys, bool)>);
+
+    #[test]
+    fn from_ascii() {
+        let cases: &[AsciiCase] = &[
+            (b'a', HostLayout::Us, Some((HidKeys::A, false))),
+            (b'Z', HostLayout::Us, Some((HidKeys::Z, true))),
+            (b'1', HostLayout::Us, Some((HidKeys::Num1, false))),
+            (b'\n', HostLayout::Us, Some((HidKeys::Enter, false))),
+            (b'"', HostLayout::Us, Some((HidKeys::Quote, true))),
+            (b'@', HostLayout::Us, Some((HidKeys::Num2, true))),
+            (b'#', HostLayout::Us, Some((HidKeys::Num3, true))),
+            (b'~', HostLayout::Us, Some((HidKeys::Grave, true))),
+            (b'\\', HostLayout::Us, Some((HidKeys::Backslash, false))),
+            (b'|', HostLayout::Us, Some((HidKeys::Backslash, true))),
+            /* the letters, the digits and most symbols are the same keys on the uk layout */
+            (b'a', HostLayout::Uk, Some((HidKeys::A, false))),
+            (b'Z', HostLayout::Uk, Some((HidKeys::Z, true))),
+            (b'\'', HostLayout::Uk, Some((HidKeys::Quote, false))),
+            (b'"', HostLayout::Uk, Some((HidKeys::Num2, true))),
+            (b'@', HostLayout::Uk, Some((HidKeys::Quote, true))),
+            (b'#', HostLayout::Uk, Some((HidKeys::NonusHash, false))),
+            (b'~', HostLayout::Uk, Some((HidKeys::NonusHash, true))),
+            (b'\\', HostLayout::Uk, Some((HidKeys::NonusBslash, false))),
+            (b'|', HostLayout::Uk, Some((HidKeys::NonusBslash, true))),
+            /* no key types these */
+            (0x7F, HostLayout::Us, None),
+            (0xE9, HostLayout::Uk, None),
+        ];
+
+        for (character, host_layout, expected) in cases {
+            assert_eq!(
+                HidKeys::from_ascii(*character, *host_layout),
+                *expected,
+                "{:?} on {:?}",
+                *character as char,
+                host_layout
+            );
+        }
+    }
+}
@@ -1,31 +1,282 @@
 use crate::config::{config::*, enums::*, layout::*};
+use crate::matrix::{Key, SPLIT_COLS, SPLIT_KEYS};
+use crate::storage::{load_keymap, KeymapStore};
 
-use heapless::FnvIndexMap;
-pub enum Layer {
-    Base,
-    Upper,
+use heapless::{FnvIndexMap, Vec};
+
+/* the layer keys exist for up to 8 layers and the active layers are kept in a u8 */
+const _: () = assert!(LAYERS <= 8, "at most 8 layers are supported");
+
+/* the profile keys exist for 5 profiles */
+const _: () = assert!(HOST_PROFILES <= 5, "at most 5 host profiles are supported");
+
+/* the dynamic macro keys exist for 2 slots */
+const _: () = assert!(
+    DYNAMIC_MACROS <= 2,
+    "at most 2 dynamic macros are supported"
+);
+
+/* a layer set from a grid holds every key of both halves */
+const _: () = assert!(
+    LAYER_INDEXMAP_SIZE >= SPLIT_KEYS,
+    "LAYER_INDEXMAP_SIZE has to hold every key of both halves"
+);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Layer(pub u8);
+
+impl Layer {
+    pub const BASE: Layer = Layer(0);
+    pub const UPPER: Layer = Layer(1);
+}
+
+/* the active layers, the highest active layer is looked up first */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LayerState {
+    pub active: u8,
+    pub default: Layer,
+}
+
+impl LayerState {
+    pub fn new() -> Self {
+        LayerState {
+            active: 0,
+            default: Layer::BASE,
+        }
+    }
+
+    pub fn is_active(&self, layer: Layer) -> bool {
+        layer == self.default || self.active & (1 << layer.0) != 0
+    }
+
+    pub fn activate(&mut self, layer: Layer) {
+        self.active |= 1 << layer.0;
+    }
+
+    pub fn deactivate(&mut self, layer: Layer) {
+        self.active &= !(1 << layer.0);
+    }
+
+    pub fn toggle(&mut self, layer: Layer) {
+        self.active ^= 1 << layer.0;
+    }
+
+    /* turn off every layer but the default one and the given one */
+    pub fn to(&mut self, layer: Layer) {
+        self.active = 1 << layer.0;
+    }
+
+    pub fn set_default(&mut self, layer: Layer) {
+        self.default = layer;
+    }
+
+    /* the highest active layer */
+    pub fn highest(&self) -> Layer {
+        (0..LAYERS as u8)
+            .rev()
+            .map(Layer)
+            .find(|layer| self.is_active(*layer))
+            .unwrap_or(self.default)
+    }
+
+    pub fn press(&mut self, key: &HidKeys) {
+        match LayerAction::get_layer_action(key) {
+            Some((LayerAction::Momentary, layer)) => self.activate(layer),
+            Some((LayerAction::Toggle, layer)) => self.toggle(layer),
+            Some((LayerAction::To, layer)) => self.to(layer),
+            Some((LayerAction::Default, layer)) => self.set_default(layer),
+            None => { /* not a layer key */ }
+        }
+    }
+
+    pub fn release(&mut self, key: &HidKeys) {
+        /* only the momentary layers end with the key */
+        if let Some((LayerAction::Momentary, layer)) = LayerAction::get_layer_action(key) {
+            self.deactivate(layer);
+        }
+    }
+}
+
+/* a key that sends the tap key when tapped and the hold key (a modifier or a layer key) when held */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TapHold {
+    pub tap: HidKeys,
+    pub hold: HidKeys,
+}
+
+impl TapHold {
+    pub const fn new(tap: HidKeys, hold: HidKeys) -> Self {
+        TapHold { tap, hold }
+    }
+}
+
+/*
+a key that sends taps[n - 1] when tapped n times, each tap inside TAPPING_TERM of the previous one,
+and the hold key when held past TAPPING_TERM on the first tap, see the tap_dances! macro
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TapDance {
+    pub taps: [HidKeys; TAP_DANCE_TAPS],
+    pub hold: HidKeys,
+}
+
+pub type TapDances = [TapDance; TAP_DANCE_KEYS];
+
+impl TapDance {
+    pub const NONE: TapDance = TapDance {
+        taps: [HidKeys::None; TAP_DANCE_TAPS],
+        hold: HidKeys::None,
+    };
+
+    /* None for more than TAP_DANCE_TAPS taps, the tap_dances! macro checks it when compiled */
+    pub fn new(taps: &[HidKeys], hold: HidKeys) -> Option<Self> {
+        let mut tap_dance = TapDance::NONE;
+
+        tap_dance.taps.get_mut(..taps.len())?.copy_from_slice(taps);
+        tap_dance.hold = hold;
+
+        Some(tap_dance)
+    }
+
+    /* the tap counts with a key */
+    pub fn tap_count(&self) -> usize {
+        self.taps
+            .iter()
+            .take_while(|key| **key != HidKeys::None)
+            .count()
+    }
+
+    /* the key of the tap count, the last one past it */
+    pub fn tap(&self, count: usize) -> HidKeys {
+        self.taps[count.clamp(1, self.tap_count().max(1)) - 1]
+    }
+
+    pub const fn get_index(key: &HidKeys) -> Option<usize> {
+        /* the tap dance keys are consecutive */
+        let index = (*key as u16).wrapping_sub(HidKeys::TapDance0 as u16) as usize;
+
+        if index < TAP_DANCE_KEYS {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
+
+/* a step of a macro, the macros are played over consecutive reports */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MacroStep {
+    Tap(HidKeys),   /* press and release */
+    Press(HidKeys), /* held till a Release step */
+    Release(HidKeys),
+    Text(&'static str), /* typed as on the HOST_LAYOUT, every character is pressed and released */
+    Delay(u32),         /* ms before the next step */
 }
+
+/* the steps of HidKeys::Macro0 .. HidKeys::Macro15, see the macros! macro */
+pub type Macros = [&'static [MacroStep]; MACROS];
+
+/* a key event recorded by the dynamic macro keys, played back as a Press or a Release step */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RecordedKey {
+    pub key: HidKeys,
+    pub pressed: bool,
+}
+
+/* the recorded slots of HidKeys::DynamicMacroPlay0 .. HidKeys::DynamicMacroPlay1 */
+pub type DynamicMacro = Vec<RecordedKey, DYNAMIC_MACRO_SIZE>;
+pub type DynamicMacros = [DynamicMacro; DYNAMIC_MACROS];
+
+/* keys pressed together inside COMBO_TERM send the action instead, see the combos! macro */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Combo {
+    pub keys: Vec<Key, COMBO_KEYS>,
+    pub action: HidKeys,
+    /* the layers the combo is active on, a bit per layer */
+    pub layers: u8,
+}
+
+pub type Combos = Vec<Combo, COMBOS>;
+
+impl Combo {
+    pub const ALL_LAYERS: u8 = 0xFF;
+
+    pub fn new(keys: &[Key], action: HidKeys, layers: u8) -> Self {
+        Combo {
+            keys: Vec::from_slice(keys).expect("Error creating a combo, COMBO_KEYS is too small!"),
+            action,
+            layers,
+        }
+    }
+
+    /* active when the highest active layer is one of the combo layers */
+    pub fn is_active(&self, layer_state: &LayerState) -> bool {
+        self.layers & (1 << layer_state.highest().0) != 0
+    }
+}
+
 pub struct Layers {
-    pub base: FnvIndexMap<(i8, i8), HidKeys, LAYER_INDEXMAP_SIZE>,
-    pub upper: FnvIndexMap<(i8, i8), HidKeys, LAYER_INDEXMAP_SIZE>,
+    /* missing positions and HidKeys::Transparent fall through to the next active layer */
+    pub layers: [FnvIndexMap<(i8, i8), HidKeys, LAYER_INDEXMAP_SIZE>; LAYERS],
+    /* the keys behind HidKeys::TapHold0 .. HidKeys::TapHold7 */
+    pub tap_holds: [TapHold; TAP_HOLD_KEYS],
+}
+
+impl Default for Layers {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Layers {
     pub fn new() -> Self {
         Layers {
-            base: FnvIndexMap::new(),
-            upper: FnvIndexMap::new(),
+            layers: core::array::from_fn(|_| FnvIndexMap::new()),
+            tap_holds: [TapHold::new(HidKeys::None, HidKeys::None); TAP_HOLD_KEYS],
         }
     }
-    pub fn load_layout(&mut self) {
-        *self = provide_layout();
+    pub fn load_layout<S: KeymapStore>(&mut self, store: &mut S) {
+        /* the keymap remapped at runtime takes precedence over the compiled layout */
+        *self = load_keymap(store).unwrap_or_else(provide_layout);
+    }
+
+    pub fn layer(
+        &mut self,
+        layer: Layer,
+    ) -> &mut FnvIndexMap<(i8, i8), HidKeys, LAYER_INDEXMAP_SIZE> {
+        &mut self.layers[layer.0 as usize]
     }
 
-    pub fn get(&mut self, row: &i8, col: &i8, layer_state: &Layer) -> Option<&HidKeys> {
-        /* provide the key depending on the layer */
-        match layer_state {
-            Layer::Base => self.base.get(&(*row, *col)),
-            Layer::Upper => self.upper.get(&(*row, *col)),
+    /* set every key of a layer from a grid of both halves, see the keymap! macro */
+    pub fn set_layer(&mut self, layer: Layer, keys: &[[HidKeys; SPLIT_COLS]; ROWS]) {
+        let layer = self.layer(layer);
+        layer.clear();
+
+        for (row, cols) in keys.iter().enumerate() {
+            for (col, key) in cols.iter().enumerate() {
+                layer
+                    .insert((row as i8, col as i8), *key)
+                    .expect("Error setting a key of the layer!");
+            }
         }
     }
+
+    pub fn get(&mut self, row: &i8, col: &i8, layer_state: &LayerState) -> Option<&HidKeys> {
+        /* provide the key of the highest active layer that is not transparent */
+        self.layers
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(layer, _)| layer_state.is_active(Layer(*layer as u8)))
+            .find_map(|(_, keys)| {
+                keys.get(&(*row, *col))
+                    .filter(|key| **key != HidKeys::Transparent)
+            })
+    }
+
+    pub fn get_tap_hold(&self, key: &HidKeys) -> Option<&TapHold> {
+        /* the tap hold keys are consecutive */
+        let index = (*key as u16).checked_sub(HidKeys::TapHold0 as u16)?;
+        self.tap_holds.get(index as usize)
+    }
 }
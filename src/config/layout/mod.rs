@@ -2,6 +2,177 @@ pub mod dvorak;
 pub mod qwerty;
 use crate::config::layers::*;
 
+/*
+Declares the layers of a layout, one grid per layer, the rows of both halves side by side:
+
+    keymap! {
+        Layer::BASE => [
+            [Escape, Quote, ..., Slash],
+            ...
+        ],
+        Layer::UPPER => [ ... ],
+    }
+
+Every grid has ROWS rows of COLS * 2 keys, the columns of the right half follow the left half columns.
+A row or a column too many or missing, or a name that is not a HidKeys variant, does not compile.
+*/
+#[macro_export]
+macro_rules! keymap {
+    ($($layer:expr => [$([$($key:ident),* $(,)?]),* $(,)?]),* $(,)?) => {{
+        let mut layers = $crate::config::layers::Layers::new();
+        $(
+            let keys: [
+                [$crate::config::enums::HidKeys; $crate::matrix::SPLIT_COLS];
+                $crate::config::config::ROWS
+            ] = [$([$($crate::config::enums::HidKeys::$key),*]),*];
+            layers.set_layer($layer, &keys);
+        )*
+        layers
+    }};
+}
+
+/*
+Declares the combos of a layout, the (row, col) of 2 or more keys of the grid and the key they send:
+
+    combos! {
+        [(1, 2), (1, 3)] => Escape,
+        [(1, 1), (1, 2), (1, 3)] => LayerToggle1 on [Layer::BASE],
+    }
+
+A combo of less than 2 or more than COMBO_KEYS keys does not compile.
+A combo without layers is active on every layer, otherwise when the highest active layer is one of them.
+When several combos match the keys pressed, the one with the most keys wins, then the one declared first.
+*/
+#[macro_export]
+macro_rules! combos {
+    ($([$(($row:expr, $col:expr)),+ $(,)?] => $action:ident $(on [$($layer:expr),+ $(,)?])?),* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut combos = $crate::config::layers::Combos::new();
+        $(
+            /* checked when the layout is compiled */
+            const _: () = assert!(
+                [$(stringify!($row)),+].len() >= 2,
+                "a combo has at least 2 keys"
+            );
+            const _: () = assert!(
+                [$(stringify!($row)),+].len() <= $crate::config::config::COMBO_KEYS,
+                "a combo has at most COMBO_KEYS keys"
+            );
+
+            let keys = [$($crate::matrix::Key::new($row, $col)),+];
+
+            #[allow(unused_mut)]
+            let mut layers = $crate::config::layers::Combo::ALL_LAYERS;
+            $(
+                layers = 0;
+                $(
+                    let layer: $crate::config::layers::Layer = $layer;
+                    layers |= 1 << layer.0;
+                )+
+            )?
+
+            combos
+                .push($crate::config::layers::Combo::new(
+                    &keys,
+                    $crate::config::enums::HidKeys::$action,
+                    layers,
+                ))
+                .expect("Error adding a combo, COMBOS is too small!");
+        )*
+        combos
+    }};
+}
+
+/*
+Declares the tap dance keys of a layout, the key of every tap count and optionally the key held on the first tap:
+
+    tap_dances! {
+        TapDance0 => [SemiColon, MacroColon] hold ModifierControl,
+        TapDance1 => [Escape, Grave, Tab],
+    }
+
+A key that is not a tap dance key, or more than TAP_DANCE_TAPS taps, does not compile.
+*/
+#[macro_export]
+macro_rules! tap_dances {
+    ($($key:ident => [$($tap:ident),+ $(,)?] $(hold $hold:ident)?),* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut tap_dances: $crate::config::layers::TapDances =
+            [$crate::config::layers::TapDance::NONE; $crate::config::config::TAP_DANCE_KEYS];
+        $(
+            #[allow(unused_mut)]
+            let mut hold = $crate::config::enums::HidKeys::None;
+            $(hold = $crate::config::enums::HidKeys::$hold;)?
+
+            /* checked when the layout is compiled */
+            const _: () = assert!(
+                $crate::config::layers::TapDance::get_index(&$crate::config::enums::HidKeys::$key)
+                    .is_some(),
+                "a tap dance is declared for a TapDance0 .. TapDance7 key"
+            );
+            const _: () = assert!(
+                [$(stringify!($tap)),+].len() <= $crate::config::config::TAP_DANCE_TAPS,
+                "a tap dance has at most TAP_DANCE_TAPS taps"
+            );
+
+            if let (Some(index), Some(tap_dance)) = (
+                $crate::config::layers::TapDance::get_index(&$crate::config::enums::HidKeys::$key),
+                $crate::config::layers::TapDance::new(
+                    &[$($crate::config::enums::HidKeys::$tap),+],
+                    hold,
+                ),
+            ) {
+                tap_dances[index] = tap_dance;
+            }
+        )*
+        tap_dances
+    }};
+}
+
+/*
+Declares the macros of a layout, the steps played when the macro key is pressed:
+
+    macros! {
+        Macro0 => [text "hello world", tap Enter],
+        Macro1 => [press ModifierControl, tap K, tap C, release ModifierControl],
+        Macro2 => [tap F5, delay 500, text "ok"],
+    }
+*/
+#[macro_export]
+macro_rules! macros {
+    ($($key:ident => [$($step:ident $arg:tt),* $(,)?]),* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut macros: $crate::config::layers::Macros = [&[]; $crate::config::config::MACROS];
+        $({
+            const STEPS: &[$crate::config::layers::MacroStep] = &[$($crate::macro_step!($step $arg)),*];
+
+            let index = $crate::config::enums::HidKeys::get_macro_index(&$crate::config::enums::HidKeys::$key)
+                .expect("Error adding a macro, not a macro key!");
+            macros[index] = STEPS;
+        })*
+        macros
+    }};
+}
+
+#[macro_export]
+macro_rules! macro_step {
+    (tap $key:ident) => {
+        $crate::config::layers::MacroStep::Tap($crate::config::enums::HidKeys::$key)
+    };
+    (press $key:ident) => {
+        $crate::config::layers::MacroStep::Press($crate::config::enums::HidKeys::$key)
+    };
+    (release $key:ident) => {
+        $crate::config::layers::MacroStep::Release($crate::config::enums::HidKeys::$key)
+    };
+    (text $text:literal) => {
+        $crate::config::layers::MacroStep::Text($text)
+    };
+    (delay $ms:literal) => {
+        $crate::config::layers::MacroStep::Delay($ms)
+    };
+}
+
 pub fn provide_layout() -> Layers {
     #[cfg(feature = "dvorak")]
     {
@@ -13,3 +184,128 @@ pub fn provide_layout() -> Layers {
         qwerty::layout()
     }
 }
+
+pub fn provide_combos() -> Combos {
+    #[cfg(feature = "dvorak")]
+    {
+        dvorak::combos()
+    }
+
+    #[cfg(feature = "qwerty")]
+    {
+        qwerty::combos()
+    }
+}
+
+pub fn provide_tap_dances() -> TapDances {
+    #[cfg(feature = "dvorak")]
+    {
+        dvorak::tap_dances()
+    }
+
+    #[cfg(feature = "qwerty")]
+    {
+        qwerty::tap_dances()
+    }
+}
+
+pub fn provide_macros() -> Macros {
+    #[cfg(feature = "dvorak")]
+    {
+        dvorak::macros()
+    }
+
+    #[cfg(feature = "qwerty")]
+    {
+        qwerty::macros()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config::COLS;
+    use crate::config::enums::HidKeys;
+
+    /* the layer, the row and the column on that half (true for the right half), the key */
+    type LayoutCase = (Layer, i8, i8, bool, HidKeys);
+
+    /* the keys both layouts share */
+    const COMMON: &[LayoutCase] = &[
+        (Layer::BASE, 0, 0, false, HidKeys::Escape),
+        (Layer::BASE, 0, 5, true, HidKeys::Slash),
+        (Layer::BASE, 3, 4, false, HidKeys::Space),
+        (Layer::BASE, 3, 5, false, HidKeys::ModifierShift),
+        (Layer::BASE, 3, 1, true, HidKeys::Enter),
+        (Layer::UPPER, 0, 0, false, HidKeys::Transparent),
+        (Layer::UPPER, 1, 0, false, HidKeys::Transparent),
+        (Layer::UPPER, 2, 0, false, HidKeys::Transparent),
+        (Layer::UPPER, 3, 4, false, HidKeys::Transparent),
+        (Layer::UPPER, 3, 5, false, HidKeys::Transparent),
+        (Layer::UPPER, 3, 0, true, HidKeys::Transparent),
+        (Layer::UPPER, 3, 1, true, HidKeys::Transparent),
+        (Layer::UPPER, 1, 1, true, HidKeys::Left),
+    ];
+
+    fn check_layout(mut layers: Layers, table: &[LayoutCase]) {
+        for (layer, row, col, right, expected) in COMMON.iter().chain(table.iter()) {
+            let col = if *right { col + COLS as i8 } else { *col };
+
+            assert_eq!(
+                layers.layer(*layer).get(&(*row, col)),
+                Some(expected),
+                "{:?} ({},{})",
+                layer,
+                row,
+                col
+            );
+        }
+    }
+
+    #[test]
+    fn qwerty_layout() {
+        check_layout(
+            qwerty::layout(),
+            &[
+                /* the layer key positions are left empty */
+                (Layer::BASE, 3, 3, false, HidKeys::Undefined),
+                (Layer::BASE, 3, 2, true, HidKeys::Undefined),
+                (Layer::BASE, 3, 0, true, HidKeys::ModifierAlt),
+                (Layer::UPPER, 0, 1, false, HidKeys::Num1),
+                (Layer::UPPER, 1, 4, false, HidKeys::Copy),
+            ],
+        );
+    }
+
+    #[test]
+    fn dvorak_layout() {
+        check_layout(
+            dvorak::layout(),
+            &[
+                (Layer::BASE, 3, 3, false, HidKeys::LayerMomentary1),
+                (Layer::BASE, 3, 2, true, HidKeys::LayerMomentary1),
+                (Layer::BASE, 3, 0, true, HidKeys::Tab),
+                /* the layer keys stay on the upper layer, so releasing them goes back to the base layer */
+                (Layer::UPPER, 3, 3, false, HidKeys::LayerMomentary1),
+                (Layer::UPPER, 3, 2, true, HidKeys::LayerMomentary1),
+                (Layer::UPPER, 0, 2, false, HidKeys::Num7),
+            ],
+        );
+    }
+
+    /* the transparent keys of the upper layer fall through to the base layer */
+    #[test]
+    fn upper_layer_falls_through() {
+        for mut layers in [qwerty::layout(), dvorak::layout()] {
+            let mut layer_state = LayerState::new();
+            layer_state.activate(Layer::UPPER);
+
+            assert_eq!(layers.get(&3, &4, &layer_state), Some(&HidKeys::Space));
+            assert_eq!(layers.get(&0, &0, &layer_state), Some(&HidKeys::Escape));
+            assert_eq!(
+                layers.get(&1, &(1 + COLS as i8), &layer_state),
+                Some(&HidKeys::Left)
+            );
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use crate::config::config::DEBOUNCE_DELAY;
+use crate::debounce::Debouncer;
+use crate::matrix::{KeyStates, MatrixEvent, SPLIT_KEYS};
+
+use embassy_time::Instant;
+use heapless::Vec;
+
+#[derive(Clone, Copy)]
+struct DeferredState {
+    /* the key is reported as pressed */
+    reported: bool,
+    /* the key is held according to the last scan */
+    held: bool,
+    /* the last time the scanned state changed */
+    changed_time: Instant,
+}
+
+impl Default for DeferredState {
+    fn default() -> Self {
+        DeferredState {
+            reported: false,
+            held: false,
+            changed_time: Instant::from_ticks(0),
+        }
+    }
+}
+
+/* deferred per key: a press and a release are both reported once they are stable */
+#[derive(Default)]
+pub struct DeferredDebouncer {
+    keys: KeyStates<DeferredState>,
+}
+
+impl Debouncer for DeferredDebouncer {
+    fn update(&mut self, event: &MatrixEvent) -> Option<MatrixEvent> {
+        let state = self.keys.get_mut(&event.key)?;
+
+        /* every change restarts the delay */
+        state.held = event.pressed;
+        state.changed_time = event.time;
+        None
+    }
+
+    fn expired(&mut self, now: Instant) -> Vec<MatrixEvent, SPLIT_KEYS> {
+        let mut changed: Vec<MatrixEvent, SPLIT_KEYS> = Vec::new();
+
+        for (key, state) in self.keys.iter_mut() {
+            if state.reported != state.held && now >= state.changed_time + DEBOUNCE_DELAY {
+                state.reported = state.held;
+                changed.push(MatrixEvent::new(key, state.held, now)).ok();
+            }
+        }
+
+        changed
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.keys
+            .iter()
+            .filter(|(_, state)| state.reported != state.held)
+            .map(|(_, state)| state.changed_time + DEBOUNCE_DELAY)
+            .min()
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn waveforms() {
+        let delay = DEBOUNCE_DELAY.as_millis();
+
+        /* both changes are sent once the last bounce is over */
+        assert_eq!(
+            debounce::<DeferredDebouncer>(BOUNCY_TAP, 400),
+            vec![(4 + delay, true), (202 + delay, false)]
+        );
+
+        /* the drop out restarts the delay of the press */
+        assert_eq!(
+            debounce::<DeferredDebouncer>(GLITCH_WHILE_HELD, 400),
+            vec![(32 + delay, true), (300 + delay, false)]
+        );
+
+        assert_eq!(debounce::<DeferredDebouncer>(NOISE_SPIKE, 400), vec![]);
+    }
+}
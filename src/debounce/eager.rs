@@ -0,0 +1,106 @@
+use crate::config::config::DEBOUNCE_DELAY;
+use crate::debounce::Debouncer;
+use crate::matrix::{KeyStates, MatrixEvent, SPLIT_KEYS};
+
+use embassy_time::Instant;
+use heapless::Vec;
+
+#[derive(Clone, Copy)]
+struct EagerState {
+    /* the key is reported as pressed */
+    reported: bool,
+    /* the key is held according to the last scan */
+    held: bool,
+    /* the last time the key was scanned as released */
+    released_time: Instant,
+}
+
+impl Default for EagerState {
+    fn default() -> Self {
+        EagerState {
+            reported: false,
+            held: false,
+            released_time: Instant::from_ticks(0),
+        }
+    }
+}
+
+/* eager per key: no press latency, the release is deferred */
+#[derive(Default)]
+pub struct EagerDebouncer {
+    keys: KeyStates<EagerState>,
+}
+
+impl Debouncer for EagerDebouncer {
+    fn update(&mut self, event: &MatrixEvent) -> Option<MatrixEvent> {
+        let state = self.keys.get_mut(&event.key)?;
+        state.held = event.pressed;
+
+        if !event.pressed {
+            state.released_time = event.time;
+            return None;
+        }
+
+        /* a press of a key that is still reported pressed is chatter */
+        if state.reported {
+            return None;
+        }
+
+        state.reported = true;
+        Some(*event)
+    }
+
+    fn expired(&mut self, now: Instant) -> Vec<MatrixEvent, SPLIT_KEYS> {
+        let mut released: Vec<MatrixEvent, SPLIT_KEYS> = Vec::new();
+
+        for (key, state) in self.keys.iter_mut() {
+            if state.reported && !state.held && now >= state.released_time + DEBOUNCE_DELAY {
+                state.reported = false;
+                released.push(MatrixEvent::new(key, false, now)).ok();
+            }
+        }
+
+        released
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.keys
+            .iter()
+            .filter(|(_, state)| state.reported && !state.held)
+            .map(|(_, state)| state.released_time + DEBOUNCE_DELAY)
+            .min()
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn waveforms() {
+        let delay = DEBOUNCE_DELAY.as_millis();
+
+        /* the press is sent at the first edge, the release once the last bounce is over */
+        assert_eq!(
+            debounce::<EagerDebouncer>(BOUNCY_TAP, 400),
+            vec![(0, true), (202 + delay, false)]
+        );
+
+        /* a drop out shorter than the delay is not a release */
+        assert_eq!(
+            debounce::<EagerDebouncer>(GLITCH_WHILE_HELD, 400),
+            vec![(0, true), (300 + delay, false)]
+        );
+
+        /* no press latency means a spike is sent as a keystroke */
+        assert_eq!(
+            debounce::<EagerDebouncer>(NOISE_SPIKE, 400),
+            vec![(0, true), (5 + delay, false)]
+        );
+    }
+}
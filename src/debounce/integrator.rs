@@ -0,0 +1,138 @@
+use crate::config::config::DEBOUNCE_DELAY;
+use crate::debounce::Debouncer;
+use crate::matrix::{KeyStates, MatrixEvent, SPLIT_KEYS};
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+#[derive(Clone, Copy)]
+struct IntegratorState {
+    /* the key is reported as pressed */
+    reported: bool,
+    /* the key is held according to the last scan */
+    held: bool,
+    /* the time the key spent pressed, minus the time it spent released, between 0 and DEBOUNCE_DELAY */
+    counter: Duration,
+    /* the last time the counter was brought up to date */
+    counted_time: Instant,
+}
+
+impl Default for IntegratorState {
+    fn default() -> Self {
+        IntegratorState {
+            reported: false,
+            held: false,
+            counter: Duration::from_ticks(0),
+            counted_time: Instant::from_ticks(0),
+        }
+    }
+}
+
+impl IntegratorState {
+    /* count the time since the last update with the scanned state of that time */
+    fn count(&mut self, now: Instant) {
+        let elapsed = now
+            .checked_duration_since(self.counted_time)
+            .unwrap_or_default();
+
+        self.counter = if self.held {
+            (self.counter + elapsed).min(DEBOUNCE_DELAY)
+        } else {
+            self.counter.checked_sub(elapsed).unwrap_or_default()
+        };
+        self.counted_time = now;
+    }
+
+    /* the change reached by the counter */
+    fn settle(&mut self) -> Option<bool> {
+        if !self.reported && self.counter >= DEBOUNCE_DELAY {
+            self.reported = true;
+            Some(true)
+        } else if self.reported && self.counter == Duration::from_ticks(0) {
+            self.reported = false;
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /* when the counter reaches the other state, None if it moves towards the reported state */
+    fn deadline(&self) -> Option<Instant> {
+        match (self.reported, self.held) {
+            (false, true) => Some(self.counted_time + (DEBOUNCE_DELAY - self.counter)),
+            (true, false) => Some(self.counted_time + self.counter),
+            _ => None,
+        }
+    }
+}
+
+/* counter based integrator: the bounces move the counter back instead of restarting the delay */
+#[derive(Default)]
+pub struct IntegratorDebouncer {
+    keys: KeyStates<IntegratorState>,
+}
+
+impl Debouncer for IntegratorDebouncer {
+    fn update(&mut self, event: &MatrixEvent) -> Option<MatrixEvent> {
+        let state = self.keys.get_mut(&event.key)?;
+
+        state.count(event.time);
+        state.held = event.pressed;
+
+        state
+            .settle()
+            .map(|pressed| MatrixEvent::new(event.key, pressed, event.time))
+    }
+
+    fn expired(&mut self, now: Instant) -> Vec<MatrixEvent, SPLIT_KEYS> {
+        let mut changed: Vec<MatrixEvent, SPLIT_KEYS> = Vec::new();
+
+        for (key, state) in self.keys.iter_mut() {
+            if state.deadline().is_some() {
+                state.count(now);
+
+                if let Some(pressed) = state.settle() {
+                    changed.push(MatrixEvent::new(key, pressed, now)).ok();
+                }
+            }
+        }
+
+        changed
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.keys
+            .iter()
+            .filter_map(|(_, state)| state.deadline())
+            .min()
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn waveforms() {
+        let delay = DEBOUNCE_DELAY.as_millis();
+
+        /* every bounce takes back the time it was counted */
+        assert_eq!(
+            debounce::<IntegratorDebouncer>(BOUNCY_TAP, 400),
+            vec![(4 + delay, true), (202 + delay, false)]
+        );
+
+        /* the drop out takes 2 ms off the counter instead of restarting the delay, the press is 4 ms late */
+        assert_eq!(
+            debounce::<IntegratorDebouncer>(GLITCH_WHILE_HELD, 400),
+            vec![(4 + delay, true), (300 + delay, false)]
+        );
+
+        assert_eq!(debounce::<IntegratorDebouncer>(NOISE_SPIKE, 400), vec![]);
+    }
+}
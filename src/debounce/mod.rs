@@ -1,37 +1,38 @@
-use crate::{
-    config::config::{DEBOUNCE_DELAY, PRESSED_KEYS_INDEXMAP_SIZE},
-    delay::delay_ms,
-    matrix::Key,
-};
+/*
+Debounce of the matrix events.
+
+The scanner reports every change it sees, the chatter of a switch included. A debouncer turns them
+into one press and one release per keystroke, the algorithm is selected with KeyDebouncer in config.rs:
+ - EagerDebouncer: a press is reported right away, a release once the key stayed released for DEBOUNCE_DELAY
+ - DeferredDebouncer: a press or a release is reported once the key stayed in that state for DEBOUNCE_DELAY
+ - IntegratorDebouncer: a counter goes up while the key is pressed and down while it is released,
+   the change is reported when the counter reaches DEBOUNCE_DELAY or 0
+
+The events of one key keep their order, a press is always reported before its release.
+*/
+
+mod deferred;
+mod eager;
+mod integrator;
+
+pub use deferred::DeferredDebouncer;
+pub use eager::EagerDebouncer;
+pub use integrator::IntegratorDebouncer;
+
+use crate::matrix::{MatrixEvent, SPLIT_KEYS};
 use embassy_time::Instant;
-use heapless::FnvIndexMap;
-use spin::mutex::Mutex;
+use heapless::Vec;
 
-pub const KEY_PRESSED: u8 = 1;
-pub const KEY_RELEASED: u8 = 2;
+pub trait Debouncer: Default {
+    /* take a scanned event, returns the change to report right away */
+    fn update(&mut self, event: &MatrixEvent) -> Option<MatrixEvent>;
 
-#[derive(Debug)]
-pub struct Debounce {
-    pub key_pressed_time: Instant,
-    pub key_state: u8,
-}
+    /* the changes that are settled by now */
+    fn expired(&mut self, now: Instant) -> Vec<MatrixEvent, SPLIT_KEYS>;
+
+    /* when the next change is settled, expired has to run then */
+    fn next_deadline(&self) -> Option<Instant>;
 
-pub async fn calculate_debounce(
-    keys_pressed: &Mutex<FnvIndexMap<Key, Debounce, PRESSED_KEYS_INDEXMAP_SIZE>>,
-) -> ! {
-    loop {
-        /* try to get a lock on keys_pressed */
-        if let Some(mut keys_pressed) = keys_pressed.try_lock() {
-            /* itter throught the pressed keys */
-            for (_key, debounce) in keys_pressed.iter_mut() {
-                /* check if the key has passed the debounce delay or has been released */
-                if Instant::now() >= debounce.key_pressed_time + DEBOUNCE_DELAY {
-                    /* set the key_state to RELEASED */
-                    debounce.key_state = KEY_RELEASED;
-                }
-            }
-        }
-        /* there must be a delay so WDT is not triggered */
-        delay_ms(1).await;
-    }
+    /* forget every key, nothing is reported */
+    fn clear(&mut self);
 }
@@ -0,0 +1,139 @@
+/*
+Channels between the tasks.
+
+ scanner, split link --MatrixChannel--> processing task --HidChannel--> ble task
+                                        processing task <--HostRequestChannel-- ble task
+
+The processing task is the only owner of the debounce, the keymap and the reports.
+Every channel is first in, first out: the events of one sender are received in the order they were sent,
+the events of two senders (the scanner and the split link) are interleaved in the order they were sent.
+A sender waits while its channel is full, no event is dropped.
+The fixed size buffers around the channels never panic when they are full: the event is dropped
+and counted by a DropCounter, so a limit that is hit shows in the logs.
+*/
+
+use crate::config::config::*;
+use crate::config::enums::ProfileAction;
+use crate::hid::{ConsumerReport, HidSink, KeyReport, NkroReport};
+use crate::matrix::MatrixEvent;
+use crate::storage::command::KeymapCommand;
+use crate::via::RAW_REPORT_SIZE;
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel};
+use heapless::Deque;
+
+/* every task runs on the same executor, the channels are never shared between threads */
+pub type RawMutex = NoopRawMutex;
+
+/* what the processing task asks the ble task to do */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HidEvent {
+    Keys(KeyReport),
+    Nkro(NkroReport),
+    Consumer(ConsumerReport),
+    Raw([u8; RAW_REPORT_SIZE]),
+    Profile(ProfileAction, u8),
+}
+
+/* what the host wrote, forwarded by the ble task to the processing task */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostRequest {
+    Keymap(KeymapCommand),
+    Via([u8; RAW_REPORT_SIZE]),
+    BootProtocol(bool),
+}
+
+pub type MatrixChannel = Channel<RawMutex, MatrixEvent, MATRIX_EVENTS_QUEUE_SIZE>;
+pub type HidChannel = Channel<RawMutex, HidEvent, HID_EVENTS_QUEUE_SIZE>;
+pub type HostRequestChannel = Channel<RawMutex, HostRequest, HOST_REQUESTS_QUEUE_SIZE>;
+
+/* the events dropped at a capacity limit, it can be a static shared with the NimBLE callbacks */
+pub struct DropCounter {
+    name: &'static str,
+    dropped: AtomicU32,
+}
+
+impl DropCounter {
+    pub const fn new(name: &'static str) -> Self {
+        DropCounter {
+            name,
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    /* count one dropped event, returns the total */
+    pub fn count(&self) -> u32 {
+        let dropped = self
+            .dropped
+            .fetch_add(1, Ordering::Relaxed)
+            .saturating_add(1);
+
+        #[cfg(feature = "debug")]
+        log::info!(
+            "{} full, event dropped ({} dropped so far)!",
+            self.name,
+            dropped
+        );
+
+        dropped
+    }
+
+    pub fn dropped(&self) -> u32 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/* collects the reports of one event, they are sent on the hid channel once the event is processed */
+pub struct HidEvents {
+    events: Deque<HidEvent, HID_EVENTS_QUEUE_SIZE>,
+    dropped: DropCounter,
+}
+
+impl Default for HidEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HidEvents {
+    pub fn new() -> Self {
+        HidEvents {
+            events: Deque::new(),
+            dropped: DropCounter::new("Hid event buffer"),
+        }
+    }
+
+    pub fn push(&mut self, event: HidEvent) {
+        if self.events.push_back(event).is_err() {
+            self.dropped.count();
+        }
+    }
+
+    /* the reports dropped since the start */
+    pub fn dropped(&self) -> u32 {
+        self.dropped.dropped()
+    }
+
+    pub fn pop(&mut self) -> Option<HidEvent> {
+        self.events.pop_front()
+    }
+}
+
+impl HidSink for HidEvents {
+    fn send_report(&mut self, key_report: &KeyReport) {
+        self.push(HidEvent::Keys(*key_report));
+    }
+
+    fn send_nkro_report(&mut self, nkro_report: &NkroReport) {
+        self.push(HidEvent::Nkro(*nkro_report));
+    }
+
+    fn send_consumer_report(&mut self, consumer_report: &ConsumerReport) {
+        self.push(HidEvent::Consumer(*consumer_report));
+    }
+}
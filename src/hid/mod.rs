@@ -0,0 +1,467 @@
+use zerocopy::{Immutable, IntoBytes};
+
+/* the highest usage of the n-key rollover bitmap, the modifiers are the last 8 bits */
+pub const NKRO_MAX_USAGE: u8 = 0xE7;
+pub const NKRO_REPORT_SIZE: usize = (NKRO_MAX_USAGE as usize + 1) / 8;
+const NKRO_MODIFIERS_BYTE: usize = 0xE0 / 8;
+
+/* the boot keyboard input report (0x2A22) has no report id, the key report is sent as is */
+pub const BOOT_REPORT_SIZE: usize = 8;
+const _: () = assert!(core::mem::size_of::<KeyReport>() == BOOT_REPORT_SIZE);
+
+/* the boot protocol compatible report, up to six keys at once */
+#[derive(IntoBytes, Immutable, Clone, Copy, Debug, Default, PartialEq)]
+#[repr(packed, C)]
+pub struct KeyReport {
+    pub modifiers: u8,
+    pub reserved: u8,
+    pub keys: [u8; 6],
+}
+
+impl KeyReport {
+    pub fn new() -> Self {
+        KeyReport {
+            modifiers: 0,
+            reserved: 0,
+            keys: [0; 6],
+        }
+    }
+
+    /* returns false when all the key slots are taken and the key is dropped */
+    pub fn press(&mut self, usage: u8) -> bool {
+        if self.keys.contains(&usage) {
+            return true;
+        }
+
+        /* find the first key slot in the array that is free */
+        match self.keys.iter().position(|&value| value == 0) {
+            Some(index) => {
+                /* add the new key to that position */
+                self.keys[index] = usage;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn release(&mut self, usage: u8) {
+        /* find the key slot of the released key */
+        if let Some(index) = self.keys.iter().position(|&value| value == usage) {
+            /* remove the key from the key slot */
+            self.keys[index] = 0
+        }
+    }
+}
+
+/* the n-key rollover report, one bit for every usage from 0x00 to NKRO_MAX_USAGE */
+#[derive(IntoBytes, Immutable, Clone, Copy, Debug, PartialEq)]
+#[repr(packed, C)]
+pub struct NkroReport {
+    pub keys: [u8; NKRO_REPORT_SIZE],
+}
+
+impl NkroReport {
+    pub fn new() -> Self {
+        NkroReport {
+            keys: [0; NKRO_REPORT_SIZE],
+        }
+    }
+
+    pub fn press(&mut self, usage: u8) {
+        if usage <= NKRO_MAX_USAGE {
+            self.keys[usage as usize / 8] |= 1 << (usage % 8);
+        }
+    }
+
+    pub fn release(&mut self, usage: u8) {
+        if usage <= NKRO_MAX_USAGE {
+            self.keys[usage as usize / 8] &= !(1 << (usage % 8));
+        }
+    }
+
+    pub fn is_pressed(&self, usage: u8) -> bool {
+        usage <= NKRO_MAX_USAGE && self.keys[usage as usize / 8] & (1 << (usage % 8)) != 0
+    }
+
+    /* the modifiers are the usages 0xE0 to 0xE7, in the same order as in the key report */
+    pub fn modifiers(&self) -> u8 {
+        self.keys[NKRO_MODIFIERS_BYTE]
+    }
+
+    pub fn set_modifiers(&mut self, modifiers: u8) {
+        self.keys[NKRO_MODIFIERS_BYTE] = modifiers;
+    }
+}
+
+impl Default for NkroReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/* the consumer control report, up to two media keys at once */
+#[derive(IntoBytes, Immutable, Clone, Copy, Debug, Default, PartialEq)]
+#[repr(packed, C)]
+pub struct ConsumerReport {
+    pub usages: [u16; 2],
+}
+
+impl ConsumerReport {
+    pub fn new() -> Self {
+        ConsumerReport { usages: [0; 2] }
+    }
+
+    pub fn press(&mut self, usage: u16) {
+        let mut usages = self.usages;
+
+        /* the media keys go in the first free slot */
+        if !usages.contains(&usage) {
+            if let Some(index) = usages.iter().position(|&value| value == 0) {
+                usages[index] = usage;
+            }
+        }
+
+        self.usages = usages;
+    }
+
+    pub fn release(&mut self, usage: u16) {
+        let mut usages = self.usages;
+
+        /* free the slot of the released media key */
+        if let Some(index) = usages.iter().position(|&value| value == usage) {
+            usages[index] = 0;
+        }
+
+        self.usages = usages;
+    }
+}
+
+/* which report carries the keys to the host */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReportMode {
+    SixKro,
+    Nkro,
+}
+
+/* the reports built from the pressed keys, the key report and the n-key rollover report are kept in sync */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HidReports {
+    pub key_report: KeyReport,
+    pub nkro_report: NkroReport,
+    pub consumer_report: ConsumerReport,
+}
+
+impl HidReports {
+    pub fn new() -> Self {
+        HidReports {
+            key_report: KeyReport::new(),
+            nkro_report: NkroReport::new(),
+            consumer_report: ConsumerReport::new(),
+        }
+    }
+
+    /* returns false when the key did not fit in the key report */
+    pub fn press_key(&mut self, usage: u8) -> bool {
+        self.nkro_report.press(usage);
+        self.key_report.press(usage)
+    }
+
+    pub fn release_key(&mut self, usage: u8) {
+        self.nkro_report.release(usage);
+        self.key_report.release(usage);
+    }
+
+    pub fn press_modifiers(&mut self, modifiers: u8) {
+        self.key_report.modifiers |= modifiers;
+        self.nkro_report.set_modifiers(self.key_report.modifiers);
+    }
+
+    pub fn release_modifiers(&mut self, modifiers: u8) {
+        self.key_report.modifiers &= !modifiers;
+        self.nkro_report.set_modifiers(self.key_report.modifiers);
+    }
+}
+
+/* the lock LEDs set by the host in the keyboard output report */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HostLedState {
+    pub num_lock: bool,
+    pub caps_lock: bool,
+    pub scroll_lock: bool,
+    pub compose: bool,
+    pub kana: bool,
+}
+
+impl HostLedState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /* the LED bits in the order of the report descriptor, the padding bits are ignored */
+    pub fn from_report(leds: u8) -> Self {
+        HostLedState {
+            num_lock: leds & 0x01 != 0,
+            caps_lock: leds & 0x02 != 0,
+            scroll_lock: leds & 0x04 != 0,
+            compose: leds & 0x08 != 0,
+            kana: leds & 0x10 != 0,
+        }
+    }
+
+    pub fn to_report(&self) -> u8 {
+        self.num_lock as u8
+            | (self.caps_lock as u8) << 1
+            | (self.scroll_lock as u8) << 2
+            | (self.compose as u8) << 3
+            | (self.kana as u8) << 4
+    }
+}
+
+/* the connection to the host, with the selected host profile */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BleStatus {
+    Connected(u8),   /* connected to the host of the profile */
+    Advertising(u8), /* waiting for the host bonded to the profile */
+    Pairing(u8),     /* waiting for a new host to bond to the profile */
+    NotConnected,
+}
+
+/* something that delivers the key reports to the host (ble on the board, a recorder on the host) */
+pub trait HidSink {
+    fn send_report(&mut self, key_report: &KeyReport);
+    fn send_nkro_report(&mut self, nkro_report: &NkroReport);
+    fn send_consumer_report(&mut self, consumer_report: &ConsumerReport);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{enums::HidKeys, layers::Layer};
+    use crate::events::HidEvent;
+    use crate::matrix::Key;
+    use crate::testing::*;
+
+    /* the usages pressed (true) and released (false), the keys of the key report, the keys kept */
+    type KeyReportCase = (&'static [(u8, bool)], [u8; 6], &'static [bool]);
+
+    /* the usages pressed, the bytes of the bitmap that are set */
+    type NkroReportCase = (&'static [u8], &'static [(usize, u8)]);
+
+    /* the LED bits, num lock, caps lock, scroll lock, compose and kana */
+    type HostLedCase = (u8, [bool; 5]);
+
+    #[test]
+    fn key_report() {
+        let table: &[KeyReportCase] = &[
+            (&[(0x04, true)], [0x04, 0, 0, 0, 0, 0], &[true]),
+            /* the same key twice takes one slot */
+            (
+                &[(0x04, true), (0x04, true)],
+                [0x04, 0, 0, 0, 0, 0],
+                &[true, true],
+            ),
+            /* a released slot is taken by the next key */
+            (
+                &[(0x04, true), (0x05, true), (0x04, false), (0x06, true)],
+                [0x06, 0x05, 0, 0, 0, 0],
+                &[true, true, true, true],
+            ),
+            /* the seventh key is dropped, the others are kept */
+            (
+                &[
+                    (0x04, true),
+                    (0x05, true),
+                    (0x06, true),
+                    (0x07, true),
+                    (0x08, true),
+                    (0x09, true),
+                    (0x0A, true),
+                ],
+                [0x04, 0x05, 0x06, 0x07, 0x08, 0x09],
+                &[true, true, true, true, true, true, false],
+            ),
+            /* releasing a key that was dropped changes nothing */
+            (
+                &[
+                    (0x04, true),
+                    (0x05, true),
+                    (0x06, true),
+                    (0x07, true),
+                    (0x08, true),
+                    (0x09, true),
+                    (0x0A, true),
+                    (0x0A, false),
+                ],
+                [0x04, 0x05, 0x06, 0x07, 0x08, 0x09],
+                &[true, true, true, true, true, true, false, true],
+            ),
+        ];
+
+        for (events, keys, kept) in table.iter() {
+            let mut report = KeyReport::new();
+
+            let results: Vec<bool> = events
+                .iter()
+                .map(|(usage, pressed)| {
+                    if *pressed {
+                        report.press(*usage)
+                    } else {
+                        report.release(*usage);
+                        true
+                    }
+                })
+                .collect();
+
+            assert_eq!(report.keys, *keys, "{:?}", events);
+            assert_eq!(results, *kept, "{:?}", events);
+        }
+    }
+
+    #[test]
+    fn nkro_report() {
+        let table: &[NkroReportCase] = &[
+            (&[0x04], &[(0, 0x10)]),
+            (&[0x04, 0x05, 0x0C], &[(0, 0x30), (1, 0x10)]),
+            /* the modifiers are the last byte */
+            (&[0xE0, 0xE7], &[(NKRO_MODIFIERS_BYTE, 0x81)]),
+            /* past the bitmap */
+            (&[0xE8, 0xFF], &[]),
+            /* more keys than the key report takes */
+            (
+                &[0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x1E],
+                &[(0, 0xF0), (1, 0x0F), (3, 0x40)],
+            ),
+        ];
+
+        for (usages, bytes) in table.iter() {
+            let mut report = NkroReport::new();
+            usages.iter().for_each(|usage| report.press(*usage));
+
+            let mut expected = [0; NKRO_REPORT_SIZE];
+            bytes
+                .iter()
+                .for_each(|(index, bits)| expected[*index] = *bits);
+
+            assert_eq!(report.keys, expected, "{:?}", usages);
+            assert!(usages
+                .iter()
+                .all(|usage| report.is_pressed(*usage) == (*usage <= NKRO_MAX_USAGE)));
+
+            usages.iter().for_each(|usage| report.release(*usage));
+            assert_eq!(report, NkroReport::new());
+        }
+    }
+
+    #[test]
+    fn modifiers_in_both_reports() {
+        let mut reports = HidReports::new();
+
+        reports.press_modifiers(0x03);
+        reports.press_key(0x04);
+        assert_eq!(reports.key_report.modifiers, 0x03);
+        assert_eq!(reports.nkro_report.modifiers(), 0x03);
+
+        reports.release_modifiers(0x01);
+        reports.release_key(0x04);
+        assert_eq!(reports.nkro_report.modifiers(), 0x02);
+        assert!(!reports.nkro_report.is_pressed(0x04));
+        assert_eq!(reports.key_report.keys, [0; 6]);
+    }
+
+    /* the host on the boot protocol gets the key report, even in the n-key rollover mode */
+    #[test]
+    fn boot_protocol_fallback() {
+        const A: Key = Key { row: 1, col: 0 };
+
+        let mut key_processor = processor(&[(Layer::BASE, A, HidKeys::A)]);
+        let mut sink = RecordingSink::default();
+
+        key_processor.set_report_mode(ReportMode::Nkro, &mut sink);
+        key_processor.set_boot_protocol(true, &mut sink);
+        assert_eq!(key_processor.report_mode(), ReportMode::SixKro);
+
+        sink.clear();
+        press(&mut key_processor, &mut sink, A, 0);
+        assert_eq!(sink.key_reports(), vec![(0, vec![0x04])]);
+
+        /* back on the report protocol the held key moves to the n-key rollover report */
+        sink.clear();
+        key_processor.set_boot_protocol(false, &mut sink);
+        assert_eq!(sink.key_reports(), vec![(0, vec![])]);
+        assert!(matches!(
+            sink.events.last(),
+            Some(HidEvent::Nkro(report)) if report.is_pressed(0x04)
+        ));
+    }
+
+    /* the boot keyboard report is the modifiers, a reserved byte and six keys */
+    #[test]
+    fn boot_report_bytes() {
+        const SHIFT: Key = Key { row: 3, col: 0 };
+        const A: Key = Key { row: 1, col: 0 };
+
+        let mut key_processor = processor(&[
+            (Layer::BASE, SHIFT, HidKeys::ModifierShift),
+            (Layer::BASE, A, HidKeys::A),
+        ]);
+        let mut sink = RecordingSink::default();
+
+        key_processor.set_report_mode(ReportMode::Nkro, &mut sink);
+        key_processor.set_boot_protocol(true, &mut sink);
+
+        sink.clear();
+        press(&mut key_processor, &mut sink, SHIFT, 0);
+        press(&mut key_processor, &mut sink, A, 10);
+
+        /* only the key reports are sent, as the 8 bytes of the boot report */
+        let boot_reports: Vec<&[u8]> = sink
+            .events
+            .iter()
+            .map(|event| match event {
+                HidEvent::Keys(report) => report.as_bytes(),
+                _ => panic!("{:?} sent on the boot protocol", event),
+            })
+            .collect();
+        assert!(boot_reports
+            .iter()
+            .all(|report| report.len() == BOOT_REPORT_SIZE));
+        assert_eq!(
+            boot_reports.last(),
+            Some(&[0x02, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00].as_slice())
+        );
+    }
+
+    #[test]
+    fn host_leds() {
+        let table: &[HostLedCase] = &[
+            (0x00, [false, false, false, false, false]),
+            (0x01, [true, false, false, false, false]),
+            (0x02, [false, true, false, false, false]),
+            (0x04, [false, false, true, false, false]),
+            (0x08, [false, false, false, true, false]),
+            (0x10, [false, false, false, false, true]),
+            (0x03, [true, true, false, false, false]),
+            (0x1F, [true, true, true, true, true]),
+            /* the padding bits are ignored */
+            (0xE2, [false, true, false, false, false]),
+        ];
+
+        for (leds, expected) in table.iter() {
+            let state = HostLedState::from_report(*leds);
+
+            assert_eq!(
+                [
+                    state.num_lock,
+                    state.caps_lock,
+                    state.scroll_lock,
+                    state.compose,
+                    state.kana
+                ],
+                *expected,
+                "{:#04x}",
+                leds
+            );
+            assert_eq!(state.to_report(), leds & 0x1F, "{:#04x}", leds);
+        }
+    }
+}
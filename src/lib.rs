@@ -1,7 +1,20 @@
+pub mod battery;
+#[cfg(feature = "esp")]
 pub mod ble;
 pub mod config;
 pub mod debounce;
+pub mod events;
+pub mod hid;
 pub mod matrix;
+pub mod power;
+pub mod processor;
+pub mod profiles;
+pub mod split;
+pub mod storage;
+pub mod via;
+
+#[cfg(test)]
+mod testing;
 
 pub mod delay {
     use embassy_time::{Duration, Timer};
@@ -4,17 +4,35 @@ to flash: espflash flash ./target/riscv32imc-esp-espidf/release/esp32_rustboard
 */
 
 use anyhow;
-use ble::BleStatus;
-use embassy_futures::select::select3;
+#[cfg(feature = "right-side")]
+use embassy_futures::select::select;
+#[cfg(feature = "left-side")]
+use embassy_futures::select::{select, select4};
+use embassy_sync::signal::Signal;
 use esp32_rustboard::*;
 use esp_idf_hal::task::block_on;
-use heapless::FnvIndexMap;
 use spin::Mutex;
 
+#[cfg(feature = "left-side")]
+use crate::battery::battery_monitor;
+#[cfg(feature = "left-side")]
 use crate::ble::ble_send_keys;
-use crate::config::config::*;
-use crate::debounce::*;
-use crate::matrix::{scan_grid, Key};
+use crate::events::*;
+use crate::hid::BleStatus;
+#[cfg(feature = "left-side")]
+use crate::hid::HostLedState;
+use crate::matrix::{scan_grid, PinMatrix};
+use crate::power::EspPower;
+#[cfg(feature = "left-side")]
+use crate::processor::process_keys;
+#[cfg(feature = "left-side")]
+use crate::split::split_receive_keys;
+#[cfg(feature = "right-side")]
+use crate::split::split_send_keys;
+#[cfg(feature = "left-side")]
+use crate::storage::NvsKeymapStore;
+#[cfg(feature = "left-side")]
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
 
 fn main() -> anyhow::Result<()> {
     esp_idf_svc::sys::link_patches();
@@ -22,19 +40,92 @@ fn main() -> anyhow::Result<()> {
     /* Bind the log crate to the ESP Logging facilities */
     esp_idf_svc::log::EspLogger::initialize_default();
 
-    /* initialize keys pressed hashmap */
-    let keys_pressed: Mutex<FnvIndexMap<Key, Debounce, PRESSED_KEYS_INDEXMAP_SIZE>> =
-        Mutex::new(FnvIndexMap::new());
+    /* the scanned key events, to the processing task (the split link on the right half) */
+    let matrix_events: MatrixChannel = MatrixChannel::new();
+
+    /* keys received by the split link, so the scanner does not go to sleep */
+    let activity: Signal<RawMutex, ()> = Signal::new();
+
+    /* the deep sleep key, from the processing task to the scanner */
+    let deep_sleep: Signal<RawMutex, ()> = Signal::new();
+
+    /* the reports of the processing task, to the ble task */
+    #[cfg(feature = "left-side")]
+    let hid_events: HidChannel = HidChannel::new();
+
+    /* the keymap commands and VIA requests of the host, to the processing task */
+    #[cfg(feature = "left-side")]
+    let host_requests: HostRequestChannel = HostRequestChannel::new();
 
     /* ble connection information shared variable */
     let ble_status: Mutex<BleStatus> = Mutex::new(BleStatus::NotConnected);
 
-    /* run the tasks concurrently */
+    /* the keymap, the host profiles and the bonded split peripheral are kept in the nvs */
+    #[cfg(feature = "left-side")]
+    let nvs_partition = EspDefaultNvsPartition::take().expect("Error taking the NVS partition!");
+
+    /* the lock LEDs set by the host, for indicators or key behaviour */
+    #[cfg(feature = "left-side")]
+    let host_leds: Mutex<HostLedState> = Mutex::new(HostLedState::new());
+
+    /* the battery percentage measured by the battery monitor */
+    #[cfg(feature = "left-side")]
+    let battery_level: Mutex<u8> = Mutex::new(100);
+
+    /* the sleep of the scanner, first as it releases the matrix lines held through a deep sleep */
+    let mut power = EspPower::new();
+
+    /* construct the matrix */
+    let mut matrix = PinMatrix::new();
+
+    /* run the tasks concurrently, the left half is the central that talks to the host */
+    #[cfg(feature = "left-side")]
+    block_on(async {
+        select(
+            select4(
+                ble_send_keys(
+                    NvsKeymapStore::new(nvs_partition.clone()),
+                    &hid_events,
+                    &host_requests,
+                    &ble_status,
+                    &host_leds,
+                    &battery_level,
+                ),
+                process_keys(
+                    NvsKeymapStore::new(nvs_partition.clone()),
+                    &matrix_events,
+                    &host_requests,
+                    &hid_events,
+                    &deep_sleep,
+                ),
+                scan_grid(
+                    &mut matrix,
+                    &mut power,
+                    &matrix_events,
+                    &activity,
+                    &deep_sleep,
+                    &ble_status,
+                ),
+                split_receive_keys(nvs_partition, &matrix_events, &activity),
+            ),
+            battery_monitor(&battery_level),
+        )
+        .await;
+    });
+
+    /* the right half only reports its keys to the left half */
+    #[cfg(feature = "right-side")]
     block_on(async {
-        select3(
-            ble_send_keys(&keys_pressed, &ble_status),
-            scan_grid(&keys_pressed, &ble_status),
-            calculate_debounce(&keys_pressed),
+        select(
+            split_send_keys(&matrix_events, &ble_status),
+            scan_grid(
+                &mut matrix,
+                &mut power,
+                &matrix_events,
+                &activity,
+                &deep_sleep,
+                &ble_status,
+            ),
         )
         .await;
     });
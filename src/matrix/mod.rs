@@ -1,20 +1,28 @@
-use crate::ble::BleStatus;
-use crate::debounce::KEY_PRESSED;
+use crate::config::config::*;
 use crate::delay::*;
-use crate::{config::config::*, debounce::Debounce};
-use embassy_time::Instant;
-use esp_idf_svc::hal::gpio::*;
-use esp_idf_svc::hal::peripherals::Peripherals;
-
+use crate::events::{MatrixChannel, RawMutex};
+use crate::hid::BleStatus;
 #[cfg(feature = "sleep-mode")]
-use esp_idf_sys::{
-    self as _, esp_bt_controller_disable, gpio_int_type_t_GPIO_INTR_HIGH_LEVEL,
-    gpio_num_t_GPIO_NUM_10, gpio_num_t_GPIO_NUM_20, gpio_num_t_GPIO_NUM_6, gpio_num_t_GPIO_NUM_7,
-};
+use crate::power::{deep_sleep, sleep_until_key, PowerTier};
+use crate::power::{queue_wake_keys, PowerControl, PowerTiers};
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
 
-use heapless::FnvIndexMap;
+use heapless::Vec;
 use spin::Mutex;
 
+#[cfg(feature = "esp")]
+mod pin_matrix;
+
+#[cfg(feature = "esp")]
+pub use pin_matrix::PinMatrix;
+
+pub const MATRIX_KEYS: usize = ROWS * COLS;
+
+/* both halves, the columns of the right half follow the left half columns */
+pub const SPLIT_COLS: usize = COLS * 2;
+pub const SPLIT_KEYS: usize = ROWS * SPLIT_COLS;
+
 #[derive(Eq, Hash, PartialEq, Clone, Copy, Debug)]
 pub struct Key {
     pub row: i8,
@@ -22,179 +30,199 @@ pub struct Key {
 }
 
 impl Key {
-    fn new(row: i8, col: i8) -> Key {
+    pub fn new(row: i8, col: i8) -> Key {
         Key { row, col }
     }
 }
-pub struct PinMatrix<'a> {
-    pub rows: [PinDriver<'a, AnyIOPin, Output>; ROWS],
-    pub cols: [PinDriver<'a, AnyIOPin, Input>; COLS],
-    #[cfg(feature = "sleep-mode")]
-    pub enter_sleep_delay: Instant,
+
+/* the key at the crossing of a driven line and a read line, see DIODE_DIRECTION */
+pub fn matrix_key(driven: usize, read: usize) -> Key {
+    direction_key(DIODE_DIRECTION, driven, read)
 }
 
-impl PinMatrix<'_> {
-    pub fn new() -> PinMatrix<'static> {
-        let peripherals = Peripherals::take().expect("Not able to init peripherals.");
-
-        PinMatrix {
-            rows: [
-                PinDriver::output(peripherals.pins.gpio0.downgrade())
-                    .expect("Not able to set port as output."),
-                PinDriver::output(peripherals.pins.gpio1.downgrade())
-                    .expect("Not able to set port as output."),
-                PinDriver::output(peripherals.pins.gpio2.downgrade())
-                    .expect("Not able to set port as output."),
-                PinDriver::output(peripherals.pins.gpio3.downgrade())
-                    .expect("Not able to set port as output."),
-            ],
-            cols: [
-                PinDriver::input(peripherals.pins.gpio21.downgrade())
-                    .expect("Not able to set port as input."),
-                PinDriver::input(peripherals.pins.gpio20.downgrade())
-                    .expect("Not able to set port as input."),
-                PinDriver::input(peripherals.pins.gpio10.downgrade())
-                    .expect("Not able to set port as input."),
-                PinDriver::input(peripherals.pins.gpio7.downgrade())
-                    .expect("Not able to set port as input."),
-                PinDriver::input(peripherals.pins.gpio6.downgrade())
-                    .expect("Not able to set port as input."),
-                PinDriver::input(peripherals.pins.gpio5.downgrade())
-                    .expect("Not able to set port as input."),
-            ],
-            #[cfg(feature = "sleep-mode")]
-            enter_sleep_delay: Instant::now() + SLEEP_DELAY_INIT,
-        }
+fn direction_key(direction: DiodeDirection, driven: usize, read: usize) -> Key {
+    match direction {
+        DiodeDirection::Row2Col => Key::new(driven as i8, read as i8),
+        DiodeDirection::Col2Row => Key::new(read as i8, driven as i8),
     }
+}
+
+/* a value for every key of both halves, a fixed array so a chord of every key always fits */
+pub struct KeyStates<T: Copy + Default> {
+    states: [[T; SPLIT_COLS]; ROWS],
+}
 
-    fn set_cols_interrupt(&mut self) {
-        for col in self.cols.iter_mut() {
-            col.set_pull(Pull::Down).unwrap();
-            col.set_interrupt_type(InterruptType::AnyEdge)
-                .expect("Not able to set interrupt type.");
+impl<T: Copy + Default> Default for KeyStates<T> {
+    fn default() -> Self {
+        KeyStates {
+            states: [[T::default(); SPLIT_COLS]; ROWS],
         }
     }
+}
 
-    #[cfg(feature = "sleep-mode")]
-    fn set_light_sleep_enable_interrupts(&mut self) {
-        for col in self.cols.iter_mut() {
-            col.enable_interrupt()
-                .expect("Not able to enable interrput.")
-        }
+impl<T: Copy + Default> KeyStates<T> {
+    /* None for a key outside of the matrix */
+    pub fn get(&self, key: &Key) -> Option<&T> {
+        self.states
+            .get(usize::try_from(key.row).ok()?)?
+            .get(usize::try_from(key.col).ok()?)
     }
 
-    #[cfg(feature = "sleep-mode")]
-    fn set_light_sleep_gpio_wakeup_enable(&mut self) {
-        unsafe {
-            /* set gpios that can wake up the chip */
-            esp_idf_sys::gpio_wakeup_enable(
-                gpio_num_t_GPIO_NUM_20,
-                gpio_int_type_t_GPIO_INTR_HIGH_LEVEL,
-            );
-            esp_idf_sys::gpio_wakeup_enable(
-                gpio_num_t_GPIO_NUM_10,
-                gpio_int_type_t_GPIO_INTR_HIGH_LEVEL,
-            );
-            esp_idf_sys::gpio_wakeup_enable(
-                gpio_num_t_GPIO_NUM_7,
-                gpio_int_type_t_GPIO_INTR_HIGH_LEVEL,
-            );
-            esp_idf_sys::gpio_wakeup_enable(
-                gpio_num_t_GPIO_NUM_6,
-                gpio_int_type_t_GPIO_INTR_HIGH_LEVEL,
-            );
-        }
+    /* None for a key outside of the matrix */
+    pub fn get_mut(&mut self, key: &Key) -> Option<&mut T> {
+        self.states
+            .get_mut(usize::try_from(key.row).ok()?)?
+            .get_mut(usize::try_from(key.col).ok()?)
     }
 
-    #[cfg(feature = "sleep-mode")]
-    fn enter_light_sleep_mode(&mut self) {
-        /* enable interrupts */
-        self.set_light_sleep_enable_interrupts();
+    pub fn iter(&self) -> impl Iterator<Item = (Key, &T)> {
+        self.states.iter().enumerate().flat_map(|(row, cols)| {
+            cols.iter()
+                .enumerate()
+                .map(move |(col, state)| (Key::new(row as i8, col as i8), state))
+        })
+    }
 
-        /* set the home row to high */
-        self.rows[1].set_high().unwrap();
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Key, &mut T)> {
+        self.states.iter_mut().enumerate().flat_map(|(row, cols)| {
+            cols.iter_mut()
+                .enumerate()
+                .map(move |(col, state)| (Key::new(row as i8, col as i8), state))
+        })
+    }
 
-        /* set gpio wakeup enable interrup */
-        self.set_light_sleep_gpio_wakeup_enable();
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
 
-        /* enter sleep mode */
-        unsafe {
-            /* disable bt before entering sleep */
-            esp_bt_controller_disable();
+/* a change of a key seen by the scanner */
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct MatrixEvent {
+    pub key: Key,
+    pub pressed: bool,
+    pub time: Instant,
+    /* the keys of the other half are debounced there, they skip the debounce of this half */
+    pub debounced: bool,
+}
 
-            esp_idf_sys::esp_sleep_enable_gpio_switch(false);
+impl MatrixEvent {
+    pub fn new(key: Key, pressed: bool, time: Instant) -> MatrixEvent {
+        MatrixEvent {
+            key,
+            pressed,
+            time,
+            debounced: false,
+        }
+    }
 
-            esp_idf_sys::esp_sleep_enable_gpio_wakeup();
+    /* an event already debounced by the other half */
+    pub fn debounced(key: Key, pressed: bool, time: Instant) -> MatrixEvent {
+        MatrixEvent {
+            debounced: true,
+            ..MatrixEvent::new(key, pressed, time)
+        }
+    }
+}
 
-            #[cfg(feature = "debug")]
-            log::info!("Entering sleep...");
+/* something that can be scanned for pressed keys (the gpio matrix on the board, a fake on the host) */
+#[allow(async_fn_in_trait)]
+pub trait MatrixSource {
+    /* scan the whole matrix once and return the keys that are pressed */
+    async fn scan(&mut self) -> Vec<Key, MATRIX_KEYS>;
 
-            /* enter sleep */
-            esp_idf_sys::esp_light_sleep_start();
+    /* before a sleep: set the lines so a pressed key wakes the chip */
+    fn arm_wakeup(&mut self) {}
 
-            #[cfg(feature = "debug")]
-            log::info!("Woke up...");
+    /* after a sleep: set the lines back for scanning */
+    fn disarm_wakeup(&mut self) {}
+}
 
-            /* restart the cpu, so we have faster ble connection after sleep */
-            esp_idf_sys::esp_restart();
-        }
+/*
+the changes between two scans: the releases first, then the presses, each in the order of the scan,
+so a key that is released and another that is pressed in the same scan never overlap in the report
+*/
+pub fn scan_events(
+    previous: &[Key],
+    current: &[Key],
+    time: Instant,
+) -> Vec<MatrixEvent, { MATRIX_KEYS * 2 }> {
+    let mut events: Vec<MatrixEvent, { MATRIX_KEYS * 2 }> = Vec::new();
+
+    for key in previous.iter().filter(|key| !current.contains(key)) {
+        events.push(MatrixEvent::new(*key, false, time)).ok();
     }
-}
 
-fn store_key(
-    keys_pressed: &Mutex<FnvIndexMap<Key, Debounce, PRESSED_KEYS_INDEXMAP_SIZE>>,
-    key: &Key,
-) -> Option<()> {
-    /* lock the hashmap */
-    if let Some(mut keys_pressed) = keys_pressed.try_lock() {
-        /* Inserts a key-value pair into the map.
-         * If an equivalent key already exists in the map: the key remains and retains in its place in the order, its corresponding value is updated with value and the older value is returned inside Some(_).
-         * If no equivalent key existed in the map: the new key-value pair is inserted, last in order, and None is returned.
-         */
-        keys_pressed
-            .insert(
-                Key {
-                    row: key.row,
-                    col: key.col,
-                },
-                Debounce {
-                    key_pressed_time: Instant::now(),
-                    key_state: KEY_PRESSED,
-                },
-            )
-            .expect("Error setting new key in the hashmap");
-
-        #[cfg(feature = "debug")]
-        log::info!("Pressed keys stored! X:{}, Y:{}", key.row, key.col);
-
-        /* return true to reset the sleep delay */
-        Some(())
-    } else {
-        /* else return false */
-        None
+    for key in current.iter().filter(|key| !previous.contains(key)) {
+        events.push(MatrixEvent::new(*key, true, time)).ok();
     }
+
+    events
 }
 
-pub async fn scan_grid(
-    keys_pressed: &Mutex<FnvIndexMap<Key, Debounce, PRESSED_KEYS_INDEXMAP_SIZE>>,
+pub async fn scan_grid<M: MatrixSource, P: PowerControl>(
+    matrix: &mut M,
+    power: &mut P,
+    matrix_events: &MatrixChannel,
+    activity: &Signal<RawMutex, ()>,
+    deep_sleep_request: &Signal<RawMutex, ()>,
     ble_status: &Mutex<BleStatus>,
 ) -> ! {
-    /* construct the matrix */
-    let mut matrix = PinMatrix::new();
-
-    /* initialize interrupt */
-    matrix.set_cols_interrupt();
-
-    /* initialize counts */
-    let mut count = Key::new(0, 0);
+    let mut power_tiers = PowerTiers::new(Instant::now());
 
     /* local ble status variable */
     let mut ble_status_local: BleStatus = BleStatus::NotConnected;
 
+    /* the keys held in the previous scan */
+    let mut keys_held: Vec<Key, MATRIX_KEYS> = Vec::new();
+
+    /* the keys that woke the board, published once connected */
+    let mut wake_keys: Vec<Key, MATRIX_KEYS> = power.take_wake_keys();
+
+    /* when the board woke, to measure the reconnection */
+    #[cfg(feature = "sleep-mode")]
+    let mut woke_at: Option<Instant> = None;
+
     loop {
+        /* keys received by other tasks (e.g. the split link) also count as activity */
+        if activity.signaled() {
+            activity.reset();
+            power_tiers.activity(Instant::now());
+        }
+
+        /* the deep sleep key, only with the sleep tiers */
+        if deep_sleep_request.signaled() {
+            deep_sleep_request.reset();
+
+            #[cfg(feature = "sleep-mode")]
+            power_tiers.request_deep_sleep();
+        }
+
+        let power_tier = power_tiers.tier(Instant::now(), &keys_held);
+
         #[cfg(feature = "sleep-mode")]
-        if Instant::now() >= matrix.enter_sleep_delay {
-            matrix.enter_light_sleep_mode();
+        match power_tier {
+            PowerTier::LightSleep => {
+                let timeout = power_tiers.light_sleep_timeout(Instant::now());
+
+                /* a timer wake leaves the tier to the deep sleep */
+                if sleep_until_key(matrix, power, timeout, &mut wake_keys).await {
+                    power_tiers.wake(Instant::now());
+                    woke_at = Some(Instant::now());
+                }
+                continue;
+            }
+            PowerTier::DeepSleep => {
+                #[cfg(feature = "debug")]
+                log::info!("Entering deep sleep");
+
+                /* the chip restarts on a key, this only returns on the host */
+                wake_keys.clear();
+                deep_sleep(matrix, power);
+                power_tiers.wake(Instant::now());
+                continue;
+            }
+            PowerTier::Active | PowerTier::Idle => {}
         }
 
         /* check and store the ble status, then release the lock */
@@ -202,54 +230,265 @@ pub async fn scan_grid(
             ble_status_local = *ble_status;
         }
 
-        /* if a connection is established, run the key matrix */
+        #[cfg(feature = "sleep-mode")]
+        if let (Some(_woke_at), BleStatus::Connected(_)) = (woke_at, ble_status_local) {
+            woke_at = None;
+
+            #[cfg(feature = "debug")]
+            log::info!(
+                "Reconnected {} ms after the wake",
+                _woke_at.elapsed().as_millis()
+            );
+        }
+
+        /* the queued keys come first, nothing is scanned before they are published */
+        if !wake_keys.is_empty() {
+            if let BleStatus::Connected(_) = ble_status_local {
+                for event in scan_events(&keys_held, &wake_keys, Instant::now()) {
+                    #[cfg(feature = "debug")]
+                    log::info!("Wake event: {:?}", event);
+
+                    matrix_events.send(event).await;
+                }
+
+                /* the scan below releases them */
+                keys_held = core::mem::take(&mut wake_keys);
+            }
+        }
+
+        /* once ble is up, run the key matrix, the profile keys also work while no host is connected */
         match ble_status_local {
-            BleStatus::Connected => {
-                /* check rows and cols */
-                for row in matrix.rows.iter_mut() {
-                    /* set row to high */
-                    row.set_high().unwrap();
-
-                    /* delay so pin can propagate */
-                    delay_us(100).await;
-
-                    /* check if a col is high */
-                    for col in matrix.cols.iter() {
-                        /* check if a col is set to high (key pressed) */
-                        if col.is_high() {
-                            /* store the key */
-                            #[cfg(feature = "sleep-mode")]
-                            match store_key(keys_pressed, &count) {
-                                Some(()) => {
-                                    matrix.enter_sleep_delay = Instant::now() + SLEEP_DELAY;
-                                }
-                                None => { /* do nothing */ }
-                            }
-
-                            #[cfg(not(feature = "sleep-mode"))]
-                            store_key(keys_pressed, &count).unwrap();
-                        }
-                        /* increment col */
-                        count.col += 1;
-                    }
-                    /* set row to low */
-                    row.set_low().unwrap();
-
-                    /* increment row */
-                    count.row += 1;
-
-                    /* reset col count */
-                    count.col = 0;
+            BleStatus::Connected(_) | BleStatus::Advertising(_) | BleStatus::Pairing(_)
+                if wake_keys.is_empty() =>
+            {
+                let keys_scanned = matrix.scan().await;
+
+                if !keys_scanned.is_empty() {
+                    power_tiers.activity(Instant::now());
+                }
+
+                /* publish the changes, the processing waits for nothing but the channel */
+                for event in scan_events(&keys_held, &keys_scanned, Instant::now()) {
+                    #[cfg(feature = "debug")]
+                    log::info!("Matrix event: {:?}", event);
+
+                    matrix_events.send(event).await;
+                }
+
+                keys_held = keys_scanned;
+
+                /* an idle matrix is scanned at a lower rate */
+                let scan_period = power_tier.scan_period();
+                if scan_period.as_ticks() > 0 {
+                    delay_ms(scan_period.as_millis()).await;
                 }
+            }
+            _ => {
+                /* no link (e.g. the left half sleeps), the pressed keys wait for it with the waking keys */
+                let keys_scanned = matrix.scan().await;
+
+                if !keys_scanned.is_empty() {
+                    power_tiers.activity(Instant::now());
+                }
+
+                queue_wake_keys(&mut wake_keys, &keys_scanned);
+
+                delay_ms(power_tier.scan_period().as_millis().max(10)).await;
+            }
+        }
+    }
+}
 
-                /* reset row count */
-                count.row = 0;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config::KeyDebouncer;
+    use crate::config::enums::HidKeys;
+    use crate::config::layers::Layer;
+    use crate::debounce::Debouncer;
+    use crate::events::HidEvent;
+    use crate::hid::{NkroReport, ReportMode, NKRO_MAX_USAGE};
+    use crate::testing::*;
+
+    use embassy_futures::block_on;
+
+    #[test]
+    fn releases_before_presses() {
+        let a = Key::new(0, 0);
+        let b = Key::new(0, 1);
+        let c = Key::new(1, 0);
+
+        let events = scan_events(&[a, b], &[b, c], t(5));
+        assert_eq!(
+            events.as_slice(),
+            &[
+                MatrixEvent::new(a, false, t(5)),
+                MatrixEvent::new(c, true, t(5))
+            ]
+        );
+        assert!(scan_events(&[a], &[a], t(5)).is_empty());
+    }
+
+    /* the scans of a fake matrix go through the key processing to the reports */
+    #[test]
+    fn fake_matrix_to_reports() {
+        let a = Key::new(1, 0);
+        let shift = Key::new(2, 0);
+        let mut matrix = FakeMatrix::new(&[&[], &[shift], &[shift, a], &[a], &[]]);
+
+        let mut key_processor = processor(&[
+            (Layer::BASE, a, HidKeys::A),
+            (Layer::BASE, shift, HidKeys::ModifierShift),
+        ]);
+        let mut sink = RecordingSink::default();
+
+        let mut keys_held: Vec<Key, MATRIX_KEYS> = Vec::new();
+        for scan in 0..5 {
+            let keys_scanned = block_on(matrix.scan());
+
+            for event in scan_events(&keys_held, &keys_scanned, t(scan * 10)) {
+                key_processor.process_event(&event, &mut sink);
             }
-            BleStatus::NotConnected => {
-                /* wait till there is a connection */
-                /* sleep for 100ms */
-                delay_ms(100).await;
+            keys_held = keys_scanned;
+        }
+
+        assert_eq!(
+            sink.key_reports(),
+            vec![
+                (0x02, vec![]),
+                (0x02, vec![0x04]),
+                (0x00, vec![0x04]),
+                (0x00, vec![])
+            ]
+        );
+    }
+
+    /* every key of a half pressed in one scan and released in the next one */
+    #[test]
+    fn slam_every_key() {
+        let keys: Vec<Key, MATRIX_KEYS> = (0..ROWS)
+            .flat_map(|row| (0..COLS).map(move |col| Key::new(row as i8, col as i8)))
+            .collect();
+        let usage = |index: usize| 0x04 + index as u8;
+
+        let mut key_processor = processor(&[]);
+        for (index, key) in keys.iter().enumerate() {
+            key_processor
+                .layers_mut()
+                .layer(Layer::BASE)
+                .insert(
+                    (key.row, key.col),
+                    HidKeys::from_code(usage(index) as u16).unwrap(),
+                )
+                .unwrap();
+        }
+
+        let mut sink = RecordingSink::default();
+        key_processor.set_report_mode(ReportMode::Nkro, &mut sink);
+        sink.clear();
+
+        let mut matrix = FakeMatrix::new(&[&[], &keys, &[]]);
+        let mut debouncer = KeyDebouncer::default();
+        let mut keys_held: Vec<Key, MATRIX_KEYS> = Vec::new();
+        let mut settled: std::vec::Vec<MatrixEvent> = std::vec::Vec::new();
+
+        for ms in (0..=200).step_by(10) {
+            let keys_scanned = block_on(matrix.scan());
+
+            for event in scan_events(&keys_held, &keys_scanned, t(ms)) {
+                settled.extend(debouncer.update(&event));
             }
+            settled.extend(debouncer.expired(t(ms)));
+            keys_held = keys_scanned;
+        }
+
+        /* every press, then every release, each in the order of the scan */
+        let expected: std::vec::Vec<(Key, bool)> = [true, false]
+            .iter()
+            .flat_map(|pressed| keys.iter().map(|key| (*key, *pressed)))
+            .collect();
+        let received: std::vec::Vec<(Key, bool)> = settled
+            .iter()
+            .map(|event| (event.key, event.pressed))
+            .collect();
+        assert_eq!(received, expected);
+
+        for event in settled.iter() {
+            key_processor.process_event(event, &mut sink);
+        }
+
+        /* a report per event, the keys of the report go up by one and then down by one */
+        let held: std::vec::Vec<usize> = sink
+            .events
+            .iter()
+            .map(|event| match event {
+                HidEvent::Nkro(report) => (0..=NKRO_MAX_USAGE)
+                    .filter(|usage| report.is_pressed(*usage))
+                    .count(),
+                _ => panic!("a report other than the n-key rollover report"),
+            })
+            .collect();
+        let expected: std::vec::Vec<usize> =
+            (1..=keys.len()).chain((0..keys.len()).rev()).collect();
+        assert_eq!(held, expected);
+
+        assert!(matches!(
+            sink.events.get(keys.len() - 1),
+            Some(HidEvent::Nkro(report)) if (0..keys.len()).all(|index| report.is_pressed(usage(index)))
+        ));
+        assert_eq!(key_processor.reports().nkro_report, NkroReport::new());
+
+        /* the key report keeps the first six keys and ends empty */
+        key_processor.set_report_mode(ReportMode::SixKro, &mut sink);
+        sink.clear();
+        for event in settled.iter() {
+            key_processor.process_event(event, &mut sink);
+        }
+
+        let key_reports = sink.key_reports();
+        assert_eq!(key_reports.len(), keys.len() * 2);
+        assert_eq!(
+            key_reports[keys.len() - 1],
+            (0, (0..6).map(usage).collect::<std::vec::Vec<u8>>())
+        );
+        assert_eq!(key_reports.last(), Some(&(0, vec![])));
+    }
+
+    #[test]
+    fn key_states_outside_of_the_matrix() {
+        let mut states: KeyStates<bool> = KeyStates::default();
+
+        assert!(states.get(&Key::new(-1, 0)).is_none());
+        assert!(states.get_mut(&Key::new(0, SPLIT_COLS as i8)).is_none());
+
+        *states.get_mut(&Key::new(ROWS as i8 - 1, 0)).unwrap() = true;
+        assert_eq!(states.iter().filter(|(_, held)| **held).count(), 1);
+
+        states.clear();
+        assert!(states.iter().all(|(_, held)| !*held));
+    }
+
+    #[test]
+    fn matrix_key_in_both_diode_directions() {
+        assert_eq!(direction_key(DiodeDirection::Row2Col, 3, 5), Key::new(3, 5));
+        assert_eq!(direction_key(DiodeDirection::Col2Row, 5, 3), Key::new(3, 5));
+
+        /* every crossing of the driven and the read lines is a different key of the half */
+        for direction in [DiodeDirection::Row2Col, DiodeDirection::Col2Row] {
+            let mut keys: std::vec::Vec<Key> = (0..direction.driven_pins().len())
+                .flat_map(|driven| {
+                    (0..direction.read_pins().len())
+                        .map(move |read| direction_key(direction, driven, read))
+                })
+                .collect();
+
+            assert!(keys
+                .iter()
+                .all(|key| (key.row as usize) < ROWS && (key.col as usize) < COLS));
+
+            keys.sort_by_key(|key| (key.row, key.col));
+            keys.dedup();
+            assert_eq!(keys.len(), MATRIX_KEYS, "{:?}", direction);
         }
     }
 }
@@ -0,0 +1,114 @@
+use crate::config::config::*;
+use crate::delay::*;
+use crate::matrix::{matrix_key, Key, MatrixSource, MATRIX_KEYS};
+use esp_idf_svc::hal::gpio::*;
+use heapless::Vec;
+
+use esp_idf_sys::{
+    self as _, gpio_int_type_t_GPIO_INTR_HIGH_LEVEL, gpio_wakeup_disable, gpio_wakeup_enable,
+};
+
+/* every line of the matrix */
+const MATRIX_PINS: usize = ROWS + COLS;
+
+pub struct PinMatrix<'a> {
+    /* set high one after the other, the rows or the columns depending on DIODE_DIRECTION */
+    pub driven: Vec<PinDriver<'a, AnyIOPin, Output>, MATRIX_PINS>,
+    /* pulled down, high when a key of the driven line is pressed */
+    pub read: Vec<PinDriver<'a, AnyIOPin, Input>, MATRIX_PINS>,
+}
+
+impl PinMatrix<'_> {
+    pub fn new() -> PinMatrix<'static> {
+        let mut matrix = PinMatrix {
+            driven: Vec::new(),
+            read: Vec::new(),
+        };
+
+        /* the pins are taken by number from the board definition, no other driver uses them */
+        for pin in DIODE_DIRECTION.driven_pins() {
+            let driver = PinDriver::output(unsafe { AnyIOPin::new(*pin) })
+                .expect("Not able to set port as output.");
+            matrix.driven.push(driver).ok();
+        }
+
+        for pin in DIODE_DIRECTION.read_pins() {
+            let driver = PinDriver::input(unsafe { AnyIOPin::new(*pin) })
+                .expect("Not able to set port as input.");
+            matrix.read.push(driver).ok();
+        }
+
+        /* initialize interrupt */
+        matrix.set_read_interrupt();
+
+        matrix
+    }
+
+    fn set_read_interrupt(&mut self) {
+        for pin in self.read.iter_mut() {
+            pin.set_pull(Pull::Down).unwrap();
+            pin.set_interrupt_type(InterruptType::AnyEdge)
+                .expect("Not able to set interrupt type.");
+        }
+    }
+}
+
+impl MatrixSource for PinMatrix<'_> {
+    async fn scan(&mut self) -> Vec<Key, MATRIX_KEYS> {
+        let mut keys: Vec<Key, MATRIX_KEYS> = Vec::new();
+
+        /* check the driven and the read lines */
+        for (driven, driven_pin) in self.driven.iter_mut().enumerate() {
+            /* set the driven line to high */
+            driven_pin.set_high().unwrap();
+
+            /* delay so pin can propagate */
+            delay_us(100).await;
+
+            for (read, read_pin) in self.read.iter().enumerate() {
+                /* check if a read line is set to high (key pressed) */
+                if read_pin.is_high() {
+                    /* the vec holds every key of the matrix */
+                    keys.push(matrix_key(driven, read)).ok();
+                }
+            }
+
+            /* set the driven line to low */
+            driven_pin.set_low().unwrap();
+        }
+
+        keys
+    }
+
+    fn arm_wakeup(&mut self) {
+        for pin in self.read.iter_mut() {
+            pin.enable_interrupt()
+                .expect("Not able to enable interrput.")
+        }
+
+        /* set every driven line to high, so any key wakes the chip */
+        for pin in self.driven.iter_mut() {
+            pin.set_high().unwrap();
+        }
+
+        /* every read line can wake up the chip */
+        for pin in DIODE_DIRECTION.read_pins() {
+            unsafe {
+                gpio_wakeup_enable(*pin, gpio_int_type_t_GPIO_INTR_HIGH_LEVEL);
+            }
+        }
+    }
+
+    fn disarm_wakeup(&mut self) {
+        for pin in DIODE_DIRECTION.read_pins() {
+            unsafe {
+                gpio_wakeup_disable(*pin);
+            }
+        }
+
+        /* the scan sets one driven line at a time */
+        for pin in self.driven.iter_mut() {
+            pin.set_low().unwrap();
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use crate::config::config::{COL_PINS, RESUME_TIMEOUT, ROW_PINS};
+use crate::matrix::{Key, MATRIX_KEYS};
+use crate::power::{
+    decode_wake_keys, encode_wake_keys, PowerControl, WakeCause, DEEP_SLEEP_WAKE_MASK,
+    WAKE_KEYS_SIZE,
+};
+
+use core::ptr::{addr_of, addr_of_mut};
+use embassy_time::{Duration, Instant};
+use esp32_nimble::{BLEDevice, BLEError};
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_sys::{
+    self as _, ble_hs_sched_reset, ble_hs_synced, esp, esp_bt_controller_disable,
+    esp_bt_controller_enable, esp_bt_mode_t_ESP_BT_MODE_BLE,
+    esp_deepsleep_gpio_wake_up_mode_t_ESP_GPIO_WAKEUP_GPIO_HIGH,
+    esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER, gpio_deep_sleep_hold_dis, gpio_deep_sleep_hold_en,
+    gpio_hold_dis, gpio_hold_en, EspError, BLE_HS_ECONTROLLER,
+};
+use heapless::Vec;
+
+/* kept in the rtc memory, it is not cleared by a restart */
+#[link_section = ".rtc_noinit"]
+static mut WAKE_KEYS: [u8; WAKE_KEYS_SIZE] = [0; WAKE_KEYS_SIZE];
+
+#[derive(Debug)]
+pub enum ResumeError {
+    Controller(EspError),
+    HostSync,
+    Advertising(BLEError),
+}
+
+/* poll the ble host till it is (or is not) synced with the controller */
+fn wait_host_synced(synced: bool, deadline: Instant) -> bool {
+    loop {
+        if (unsafe { ble_hs_synced() } != 0) == synced {
+            return true;
+        }
+
+        if Instant::now() >= deadline {
+            return false;
+        }
+
+        FreeRtos::delay_ms(10);
+    }
+}
+
+/* the controller was disabled for the sleep: enable it, resync the host and advertise again */
+fn resume_ble() -> Result<(), ResumeError> {
+    let deadline = Instant::now() + RESUME_TIMEOUT;
+
+    unsafe {
+        esp!(esp_bt_controller_enable(esp_bt_mode_t_ESP_BT_MODE_BLE))
+            .map_err(ResumeError::Controller)?;
+
+        /* the connections of before the sleep are gone, the host learns it with a reset */
+        ble_hs_sched_reset(BLE_HS_ECONTROLLER as i32);
+    }
+
+    /* the reset is run by the host task, wait for it to start then to finish */
+    wait_host_synced(false, deadline);
+    if !wait_host_synced(true, deadline) {
+        return Err(ResumeError::HostSync);
+    }
+
+    /* the hosts and the left half reconnect to the advertising set before the sleep */
+    BLEDevice::take()
+        .get_advertising()
+        .lock()
+        .start()
+        .map_err(ResumeError::Advertising)
+}
+
+pub struct EspPower;
+
+impl EspPower {
+    pub fn new() -> Self {
+        /* the matrix lines were held through the deep sleep, they are driven again by the matrix */
+        unsafe {
+            gpio_deep_sleep_hold_dis();
+            for pin in ROW_PINS.iter().chain(COL_PINS.iter()) {
+                gpio_hold_dis(*pin);
+            }
+        }
+
+        EspPower
+    }
+}
+
+impl Default for EspPower {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PowerControl for EspPower {
+    fn light_sleep(&mut self, timeout: Duration) -> WakeCause {
+        unsafe {
+            /* disable bt before entering sleep */
+            esp_bt_controller_disable();
+
+            esp_idf_sys::esp_sleep_enable_gpio_switch(false);
+
+            esp_idf_sys::esp_sleep_enable_gpio_wakeup();
+            esp_idf_sys::esp_sleep_enable_timer_wakeup(timeout.as_micros());
+
+            #[cfg(feature = "debug")]
+            log::info!("Entering sleep...");
+
+            /* enter sleep */
+            esp_idf_sys::esp_light_sleep_start();
+
+            let wake_cause = if esp_idf_sys::esp_sleep_get_wakeup_cause()
+                == esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER
+            {
+                WakeCause::Timer
+            } else {
+                WakeCause::Key
+            };
+
+            /* the timer is armed again by the next sleep */
+            esp_idf_sys::esp_sleep_disable_wakeup_source(esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER);
+
+            #[cfg(feature = "debug")]
+            log::info!("Woke up by {:?}...", wake_cause);
+
+            wake_cause
+        }
+    }
+
+    fn resume(&mut self, wake_keys: &[Key]) {
+        let _started = Instant::now();
+
+        match resume_ble() {
+            Ok(()) => {
+                /* the tasks go on, the layers and the held keys are kept */
+                #[cfg(feature = "debug")]
+                log::info!("Ble resumed in {} ms", _started.elapsed().as_millis());
+            }
+            Err(_error) => {
+                #[cfg(feature = "debug")]
+                log::info!("Ble resume failed: {:?}, restarting", _error);
+
+                unsafe {
+                    /* the keys that woke the board are published after the restart */
+                    addr_of_mut!(WAKE_KEYS).write_volatile(encode_wake_keys(wake_keys));
+
+                    esp_idf_sys::esp_restart();
+                }
+            }
+        }
+    }
+
+    fn deep_sleep(&mut self) {
+        unsafe {
+            esp_bt_controller_disable();
+
+            /* keep the driven lines high and the read lines pulled down while the chip is off */
+            for pin in ROW_PINS.iter().chain(COL_PINS.iter()) {
+                gpio_hold_en(*pin);
+            }
+            gpio_deep_sleep_hold_en();
+
+            /* the bonds are in the nvs, a key restarts the chip and the host reconnects */
+            esp_idf_sys::esp_deep_sleep_enable_gpio_wakeup(
+                DEEP_SLEEP_WAKE_MASK,
+                esp_deepsleep_gpio_wake_up_mode_t_ESP_GPIO_WAKEUP_GPIO_HIGH,
+            );
+
+            #[cfg(feature = "debug")]
+            log::info!("Entering deep sleep...");
+
+            esp_idf_sys::esp_deep_sleep_start();
+        }
+    }
+
+    fn take_wake_keys(&mut self) -> Vec<Key, MATRIX_KEYS> {
+        unsafe {
+            let wake_keys = decode_wake_keys(&addr_of!(WAKE_KEYS).read_volatile());
+
+            /* published once */
+            addr_of_mut!(WAKE_KEYS).write_volatile([0; WAKE_KEYS_SIZE]);
+
+            wake_keys
+        }
+    }
+}
@@ -0,0 +1,587 @@
+/*
+Power management of the scanner, in tiers of the time since the last key:
+ - Active: typing, the matrix is scanned without pause
+ - Idle: no key for IDLE_DELAY, the matrix is scanned every IDLE_SCAN_PERIOD
+ - LightSleep: no key for SLEEP_DELAY (SLEEP_DELAY_INIT after boot), light sleep till a key is pressed
+ - DeepSleep: no key for DEEP_SLEEP_DELAY, or the PowerDeepSleep key once every key is released,
+   a key on DEEP_SLEEP_WAKE_PINS restarts the chip, the bonds and the selected profile are kept in the NVS,
+   the keys queued for the link are dropped
+The sleep tiers need the sleep-mode feature, a held key always keeps the scanner active.
+
+Before a sleep the matrix arms its read lines as wake sources, then the PowerControl puts the chip to sleep.
+The matrix is scanned as soon as the chip wakes from a light sleep, so the key that woke the board is not lost:
+it is published once the link (to the host, or to the left half) is connected again, before anything else is scanned.
+A light sleep also ends on a timer when the deep sleep is due.
+
+The radio is off during a light sleep, so the split link drops while the left half sleeps and a key of
+the right half cannot wake it. The right half keeps scanning while the link is down and queues the
+pressed keys with the keys that woke it, they are replayed (press, then release) once the left half
+is woken by one of its keys and connects again. The queue survives a sleep of the right half.
+
+The chip resumes where it went to sleep, the tasks go on with their layers and held keys, only the
+radio is brought back. If that fails the chip restarts, the keys that woke the board are kept over
+the restart in this format:
+
+| 0..4  | 4     | 5..                       | last 2 bytes    |
+| ----- | ----- | ------------------------- | --------------- |
+| MAGIC | count | (row, col) of every key   | CRC-16 of all   |
+*/
+
+#[cfg(feature = "esp")]
+mod esp;
+
+#[cfg(feature = "esp")]
+pub use esp::*;
+
+use crate::config::config::*;
+use crate::matrix::{Key, MatrixSource, MATRIX_KEYS};
+use crate::storage::keymap::crc16;
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+pub const WAKE_KEYS_MAGIC: [u8; 4] = *b"RBWK";
+const HEADER_SIZE: usize = 5;
+const CRC_SIZE: usize = 2;
+pub const WAKE_KEYS_SIZE: usize = HEADER_SIZE + MATRIX_KEYS * 2 + CRC_SIZE;
+
+/* the esp32c3 wakes from deep sleep on gpio 0 - 5 only */
+const DEEP_SLEEP_WAKE_GPIO_MAX: i32 = 5;
+
+/* the wake pins as a gpio mask */
+pub const fn deep_sleep_wake_mask(pins: &[i32]) -> u64 {
+    let mut mask = 0;
+    let mut i = 0;
+    while i < pins.len() {
+        if pins[i] >= 0 && pins[i] <= DEEP_SLEEP_WAKE_GPIO_MAX {
+            mask |= 1 << pins[i];
+        }
+        i += 1;
+    }
+    mask
+}
+
+/* every wake pin is a read line of the matrix, on a gpio the chip wakes on */
+pub const fn deep_sleep_wake_pins_valid(wake_pins: &[i32], read_pins: &[i32]) -> bool {
+    if wake_pins.is_empty() {
+        return false;
+    }
+
+    let mut i = 0;
+    while i < wake_pins.len() {
+        if wake_pins[i] < 0 || wake_pins[i] > DEEP_SLEEP_WAKE_GPIO_MAX {
+            return false;
+        }
+
+        let mut read = false;
+        let mut j = 0;
+        while j < read_pins.len() {
+            read |= read_pins[j] == wake_pins[i];
+            j += 1;
+        }
+        if !read {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+pub const DEEP_SLEEP_WAKE_MASK: u64 = deep_sleep_wake_mask(&DEEP_SLEEP_WAKE_PINS);
+
+/* only the sleep tiers use the deep sleep, the keys on the other read lines do not wake the board */
+#[cfg(feature = "sleep-mode")]
+const _: () = assert!(
+    deep_sleep_wake_pins_valid(&DEEP_SLEEP_WAKE_PINS, DIODE_DIRECTION.read_pins()),
+    "DEEP_SLEEP_WAKE_PINS must be read lines of the matrix on gpio 0 - 5"
+);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PowerTier {
+    Active,
+    Idle,
+    LightSleep,
+    DeepSleep,
+}
+
+impl PowerTier {
+    /* the pause between two scans */
+    pub fn scan_period(self) -> Duration {
+        match self {
+            PowerTier::Active => Duration::from_ticks(0),
+            _ => IDLE_SCAN_PERIOD,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WakeCause {
+    Key,
+    Timer,
+}
+
+/* the chip side of the sleep, a fake on the host */
+pub trait PowerControl {
+    /* enter light sleep, returns once a wake source fired or the timeout passed */
+    fn light_sleep(&mut self, timeout: Duration) -> WakeCause;
+
+    /* get the radio back after a light sleep, a restart is the fallback if it does not come back */
+    fn resume(&mut self, wake_keys: &[Key]);
+
+    /* enter deep sleep, does not return on the chip: a key restarts it */
+    fn deep_sleep(&mut self);
+
+    /* the keys that woke the board and were not published yet, e.g. before a restart */
+    fn take_wake_keys(&mut self) -> Vec<Key, MATRIX_KEYS> {
+        Vec::new()
+    }
+}
+
+/* the power tier from the time since the last key */
+pub struct PowerTiers {
+    last_activity: Instant,
+    sleep_delay: Duration,
+    deep_sleep_requested: bool,
+}
+
+impl PowerTiers {
+    /* a longer delay before the first sleep after boot, to get connected */
+    pub fn new(now: Instant) -> Self {
+        PowerTiers {
+            last_activity: now,
+            sleep_delay: SLEEP_DELAY_INIT,
+            deep_sleep_requested: false,
+        }
+    }
+
+    /* a key was scanned or received */
+    pub fn activity(&mut self, now: Instant) {
+        self.last_activity = now;
+        self.sleep_delay = SLEEP_DELAY;
+    }
+
+    /* the deep sleep key, the half sleeps once every key is released */
+    pub fn request_deep_sleep(&mut self) {
+        self.deep_sleep_requested = true;
+    }
+
+    /* the board woke up from a sleep */
+    pub fn wake(&mut self, now: Instant) {
+        self.activity(now);
+        self.deep_sleep_requested = false;
+    }
+
+    pub fn tier(&self, now: Instant, keys_held: &[Key]) -> PowerTier {
+        if !keys_held.is_empty() {
+            return PowerTier::Active;
+        }
+
+        if self.deep_sleep_requested {
+            return PowerTier::DeepSleep;
+        }
+
+        let idle = now
+            .checked_duration_since(self.last_activity)
+            .unwrap_or_default();
+
+        if idle >= DEEP_SLEEP_DELAY {
+            PowerTier::DeepSleep
+        } else if idle >= self.sleep_delay {
+            PowerTier::LightSleep
+        } else if idle >= IDLE_DELAY {
+            PowerTier::Idle
+        } else {
+            PowerTier::Active
+        }
+    }
+
+    /* how long a light sleep can last before the deep sleep is due */
+    pub fn light_sleep_timeout(&self, now: Instant) -> Duration {
+        (self.last_activity + DEEP_SLEEP_DELAY)
+            .checked_duration_since(now)
+            .unwrap_or_default()
+    }
+}
+
+/* queue the pressed keys while the link is down, every key once and in the order of its first press */
+pub fn queue_wake_keys(wake_keys: &mut Vec<Key, MATRIX_KEYS>, keys: &[Key]) {
+    for key in keys {
+        if !wake_keys.contains(key) {
+            wake_keys.push(*key).ok();
+        }
+    }
+}
+
+/* light sleep till a key is pressed, the keys that woke the board are queued after the pending ones,
+ * false when the timeout passed */
+pub async fn sleep_until_key<M: MatrixSource, P: PowerControl>(
+    matrix: &mut M,
+    power: &mut P,
+    timeout: Duration,
+    wake_keys: &mut Vec<Key, MATRIX_KEYS>,
+) -> bool {
+    matrix.arm_wakeup();
+    let wake_cause = power.light_sleep(timeout);
+    matrix.disarm_wakeup();
+
+    /* the deep sleep follows, the radio stays off */
+    if wake_cause == WakeCause::Timer {
+        return false;
+    }
+
+    /* scan right away, a short tap is still held */
+    let keys = matrix.scan().await;
+
+    #[cfg(feature = "debug")]
+    log::info!("Woke up by {:?}", keys);
+
+    queue_wake_keys(wake_keys, &keys);
+    power.resume(wake_keys);
+
+    true
+}
+
+/* deep sleep till a key is pressed, the chip restarts then */
+pub fn deep_sleep<M: MatrixSource, P: PowerControl>(matrix: &mut M, power: &mut P) {
+    matrix.arm_wakeup();
+    power.deep_sleep();
+    matrix.disarm_wakeup();
+}
+
+pub fn encode_wake_keys(keys: &[Key]) -> [u8; WAKE_KEYS_SIZE] {
+    let mut buffer = [0; WAKE_KEYS_SIZE];
+    let count = keys.len().min(MATRIX_KEYS);
+
+    buffer[0..4].copy_from_slice(&WAKE_KEYS_MAGIC);
+    buffer[4] = count as u8;
+
+    for (index, key) in keys.iter().take(count).enumerate() {
+        buffer[HEADER_SIZE + index * 2] = key.row as u8;
+        buffer[HEADER_SIZE + index * 2 + 1] = key.col as u8;
+    }
+
+    let crc = crc16(&buffer[..WAKE_KEYS_SIZE - CRC_SIZE]);
+    buffer[WAKE_KEYS_SIZE - CRC_SIZE..].copy_from_slice(&crc.to_le_bytes());
+
+    buffer
+}
+
+/* nothing for a buffer that was never written, e.g. after a power on */
+pub fn decode_wake_keys(data: &[u8; WAKE_KEYS_SIZE]) -> Vec<Key, MATRIX_KEYS> {
+    let mut keys: Vec<Key, MATRIX_KEYS> = Vec::new();
+
+    let crc = u16::from_le_bytes([data[WAKE_KEYS_SIZE - 2], data[WAKE_KEYS_SIZE - 1]]);
+    if data[0..4] != WAKE_KEYS_MAGIC || crc != crc16(&data[..WAKE_KEYS_SIZE - CRC_SIZE]) {
+        return keys;
+    }
+
+    let count = (data[4] as usize).min(MATRIX_KEYS);
+    for index in 0..count {
+        let key = Key::new(
+            data[HEADER_SIZE + index * 2] as i8,
+            data[HEADER_SIZE + index * 2 + 1] as i8,
+        );
+        keys.push(key).ok();
+    }
+
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    use embassy_futures::block_on;
+
+    /* the matrix lines are armed only while the chip sleeps */
+    #[derive(Default)]
+    struct WakeMatrix {
+        matrix: FakeMatrix,
+        armed: bool,
+        arms: usize,
+    }
+
+    impl MatrixSource for WakeMatrix {
+        async fn scan(&mut self) -> Vec<Key, MATRIX_KEYS> {
+            assert!(!self.armed, "scanned while armed for the wake");
+            self.matrix.scan().await
+        }
+
+        fn arm_wakeup(&mut self) {
+            self.armed = true;
+            self.arms += 1;
+        }
+
+        fn disarm_wakeup(&mut self) {
+            self.armed = false;
+        }
+    }
+
+    /* the chip side, a light sleep ends with the scripted cause */
+    struct FakePower {
+        wake_cause: WakeCause,
+        timeouts: std::vec::Vec<Duration>,
+        resumed: std::vec::Vec<std::vec::Vec<Key>>,
+        deep_sleeps: usize,
+    }
+
+    impl FakePower {
+        fn new(wake_cause: WakeCause) -> Self {
+            FakePower {
+                wake_cause,
+                timeouts: std::vec::Vec::new(),
+                resumed: std::vec::Vec::new(),
+                deep_sleeps: 0,
+            }
+        }
+    }
+
+    impl PowerControl for FakePower {
+        fn light_sleep(&mut self, timeout: Duration) -> WakeCause {
+            self.timeouts.push(timeout);
+            self.wake_cause
+        }
+
+        fn resume(&mut self, wake_keys: &[Key]) {
+            self.resumed.push(wake_keys.to_vec());
+        }
+
+        fn deep_sleep(&mut self) {
+            self.deep_sleeps += 1;
+        }
+    }
+
+    /* the key that woke the board is scanned before the radio is back, so it is kept */
+    #[test]
+    fn light_sleep_woken_by_a_key() {
+        let key = Key::new(2, 3);
+        let mut matrix = WakeMatrix {
+            matrix: FakeMatrix::new(&[&[key]]),
+            ..Default::default()
+        };
+        let mut power = FakePower::new(WakeCause::Key);
+        let mut wake_keys = Vec::new();
+
+        let woken = block_on(sleep_until_key(
+            &mut matrix,
+            &mut power,
+            Duration::from_secs(60),
+            &mut wake_keys,
+        ));
+
+        assert!(woken);
+        assert_eq!(wake_keys.as_slice(), &[key]);
+        assert_eq!(power.timeouts, vec![Duration::from_secs(60)]);
+        assert_eq!(power.resumed, vec![vec![key]]);
+        assert_eq!((matrix.arms, matrix.armed), (1, false));
+    }
+
+    /* the keys queued while the link was down are kept over the sleep, before the waking key */
+    #[test]
+    fn queued_keys_kept_over_a_sleep() {
+        let queued = Key::new(0, 1);
+        let key = Key::new(2, 3);
+        let mut matrix = WakeMatrix {
+            matrix: FakeMatrix::new(&[&[key, queued]]),
+            ..Default::default()
+        };
+        let mut power = FakePower::new(WakeCause::Key);
+        let mut wake_keys = Vec::from_slice(&[queued]).unwrap();
+
+        block_on(sleep_until_key(
+            &mut matrix,
+            &mut power,
+            Duration::from_secs(60),
+            &mut wake_keys,
+        ));
+
+        assert_eq!(wake_keys.as_slice(), &[queued, key]);
+        assert_eq!(power.resumed, vec![vec![queued, key]]);
+    }
+
+    /* the timer ends the light sleep for the deep sleep, the radio stays off */
+    #[test]
+    fn light_sleep_timed_out() {
+        let mut matrix = WakeMatrix::default();
+        let mut power = FakePower::new(WakeCause::Timer);
+        let mut wake_keys = Vec::new();
+
+        let woken = block_on(sleep_until_key(
+            &mut matrix,
+            &mut power,
+            Duration::from_secs(1),
+            &mut wake_keys,
+        ));
+
+        assert!(!woken);
+        assert!(wake_keys.is_empty());
+        assert!(power.resumed.is_empty());
+        assert_eq!((matrix.arms, matrix.armed), (1, false));
+    }
+
+    #[test]
+    fn deep_sleep_arms_the_matrix() {
+        let mut matrix = WakeMatrix::default();
+        let mut power = FakePower::new(WakeCause::Key);
+
+        deep_sleep(&mut matrix, &mut power);
+
+        assert_eq!(power.deep_sleeps, 1);
+        assert_eq!((matrix.arms, matrix.armed), (1, false));
+    }
+
+    #[test]
+    fn keys_queued_once_in_press_order() {
+        let a = Key::new(0, 0);
+        let b = Key::new(1, 4);
+        let c = Key::new(3, 2);
+        let mut wake_keys = Vec::new();
+
+        /* scans while the link is down, a held key is seen by every scan */
+        for keys in [&[a][..], &[a, b], &[], &[c, a]] {
+            queue_wake_keys(&mut wake_keys, keys);
+        }
+
+        assert_eq!(wake_keys.as_slice(), &[a, b, c]);
+    }
+
+    /* the tiers follow each other with the time since the last key */
+    #[test]
+    fn idle_tiers() {
+        let power_tiers = PowerTiers::new(t(0));
+        let at = |delay: Duration| t(0) + delay;
+        let before = |delay: Duration| at(delay - Duration::from_millis(1));
+
+        assert_eq!(power_tiers.tier(t(0), &[]), PowerTier::Active);
+        assert_eq!(power_tiers.tier(before(IDLE_DELAY), &[]), PowerTier::Active);
+        assert_eq!(power_tiers.tier(at(IDLE_DELAY), &[]), PowerTier::Idle);
+
+        /* the first sleep after boot comes after SLEEP_DELAY_INIT */
+        assert_eq!(
+            power_tiers.tier(before(SLEEP_DELAY_INIT), &[]),
+            PowerTier::Idle
+        );
+        assert_eq!(
+            power_tiers.tier(at(SLEEP_DELAY_INIT), &[]),
+            PowerTier::LightSleep
+        );
+        assert_eq!(
+            power_tiers.tier(before(DEEP_SLEEP_DELAY), &[]),
+            PowerTier::LightSleep
+        );
+        assert_eq!(
+            power_tiers.tier(at(DEEP_SLEEP_DELAY), &[]),
+            PowerTier::DeepSleep
+        );
+
+        /* the scan rate drops from the idle tier */
+        assert_eq!(PowerTier::Active.scan_period(), Duration::from_ticks(0));
+        assert_eq!(PowerTier::Idle.scan_period(), IDLE_SCAN_PERIOD);
+    }
+
+    /* a key starts the tiers over, the later sleeps come after SLEEP_DELAY */
+    #[test]
+    fn activity_restarts_the_tiers() {
+        let mut power_tiers = PowerTiers::new(t(0));
+        let key_time = t(10_000);
+
+        assert_eq!(power_tiers.tier(key_time, &[]), PowerTier::Idle);
+        power_tiers.activity(key_time);
+        assert_eq!(power_tiers.tier(key_time, &[]), PowerTier::Active);
+
+        assert_eq!(
+            power_tiers.tier(key_time + IDLE_DELAY, &[]),
+            PowerTier::Idle
+        );
+        assert_eq!(
+            power_tiers.tier(key_time + SLEEP_DELAY - Duration::from_millis(1), &[]),
+            PowerTier::Idle
+        );
+        assert_eq!(
+            power_tiers.tier(key_time + SLEEP_DELAY, &[]),
+            PowerTier::LightSleep
+        );
+
+        /* the light sleep ends on a timer when the deep sleep is due */
+        assert_eq!(
+            power_tiers.light_sleep_timeout(key_time + SLEEP_DELAY),
+            DEEP_SLEEP_DELAY - SLEEP_DELAY
+        );
+        assert_eq!(
+            power_tiers.light_sleep_timeout(key_time + DEEP_SLEEP_DELAY * 2),
+            Duration::from_ticks(0)
+        );
+    }
+
+    /* a held key keeps the scanner active, the deep sleep key waits for the release */
+    #[test]
+    fn held_keys_and_the_deep_sleep_key() {
+        let mut power_tiers = PowerTiers::new(t(0));
+        let held = [Key::new(1, 1)];
+        let long_after = t(0) + DEEP_SLEEP_DELAY * 2;
+
+        assert_eq!(power_tiers.tier(long_after, &held), PowerTier::Active);
+
+        power_tiers.request_deep_sleep();
+        assert_eq!(power_tiers.tier(t(10), &held), PowerTier::Active);
+        assert_eq!(power_tiers.tier(t(10), &[]), PowerTier::DeepSleep);
+
+        /* woken up, the request is done */
+        power_tiers.wake(t(20));
+        assert_eq!(power_tiers.tier(t(20), &[]), PowerTier::Active);
+    }
+
+    /* the keys that woke the board over a restart */
+    #[test]
+    fn wake_keys_round_trip() {
+        let keys = [Key::new(0, 0), Key::new(3, 5), Key::new(1, 2)];
+
+        let data = encode_wake_keys(&keys);
+        assert_eq!(decode_wake_keys(&data).as_slice(), &keys);
+
+        /* never written, or corrupted */
+        assert!(decode_wake_keys(&[0; WAKE_KEYS_SIZE]).is_empty());
+        let mut corrupted = data;
+        corrupted[HEADER_SIZE] ^= 0x01;
+        assert!(decode_wake_keys(&corrupted).is_empty());
+    }
+
+    #[test]
+    fn wake_mask() {
+        assert_eq!(deep_sleep_wake_mask(&[0, 3, 5]), 0b101001);
+        assert_eq!(DEEP_SLEEP_WAKE_MASK, 1 << DEEP_SLEEP_WAKE_PINS[0]);
+    }
+
+    /* (wake pins, read pins, valid) */
+    type WakePinsCase = (&'static [i32], &'static [i32], bool);
+
+    #[test]
+    fn wake_pins_checked() {
+        let cases: [WakePinsCase; 6] = [
+            (&[5], &[21, 20, 10, 7, 6, 5], true),
+            (&[0, 3], &[0, 1, 2, 3], true),
+            /* no key would wake the board */
+            (&[], &[0, 1, 2, 3], false),
+            /* a driven line, or no line of the matrix */
+            (&[4], &[0, 1, 2, 3], false),
+            /* the chip does not wake on gpio 6 and above */
+            (&[6], &[21, 20, 10, 7, 6, 5], false),
+            (&[-1], &[-1, 0], false),
+        ];
+
+        for (wake_pins, read_pins, valid) in cases {
+            assert_eq!(
+                deep_sleep_wake_pins_valid(wake_pins, read_pins),
+                valid,
+                "{:?} on {:?}",
+                wake_pins,
+                read_pins
+            );
+        }
+        assert!(deep_sleep_wake_pins_valid(
+            &DEEP_SLEEP_WAKE_PINS,
+            DIODE_DIRECTION.read_pins()
+        ));
+    }
+}
@@ -0,0 +1,351 @@
+use crate::config::config::*;
+use crate::config::enums::KeyType;
+use crate::config::layers::Combos;
+use crate::hid::HidSink;
+use crate::matrix::Key;
+use crate::processor::tap_hold::Decision;
+use crate::processor::KeyProcessor;
+
+use embassy_time::Instant;
+use heapless::Vec;
+
+/* a key of a combo pressed inside the combo term, not applied yet */
+#[derive(Clone, Copy, Debug)]
+pub struct BufferedPress {
+    pub key: Key,
+    pub time: Instant,
+}
+
+/* a combo that was sent, its action is released with the first of its keys */
+#[derive(Clone, Debug)]
+pub struct ActiveCombo {
+    pub index: usize,
+    pub held: Vec<Key, COMBO_KEYS>,
+    pub released: bool,
+}
+
+pub struct ComboState {
+    pub combos: Combos,
+    pub buffered: Vec<BufferedPress, COMBO_KEYS>,
+    pub active: Vec<ActiveCombo, COMBOS>,
+}
+
+impl ComboState {
+    pub fn new(combos: Combos) -> Self {
+        ComboState {
+            combos,
+            buffered: Vec::new(),
+            active: Vec::new(),
+        }
+    }
+
+    /* when the buffered keys are decided */
+    pub fn deadline(&self) -> Option<Instant> {
+        self.buffered.first().map(|press| press.time + COMBO_TERM)
+    }
+
+    fn is_buffered(&self, key: &Key) -> bool {
+        self.buffered.iter().any(|press| press.key == *key)
+    }
+}
+
+impl KeyProcessor {
+    /* returns true when the key waits for the other keys of a combo */
+    pub(super) fn combo_pressed<H: HidSink>(
+        &mut self,
+        key: &Key,
+        now: Instant,
+        hid: &mut H,
+    ) -> bool {
+        let combo = &self.combo;
+        let layer_state = &self.layer_state;
+
+        /* the buffered keys and this key are all keys of a combo */
+        let mut candidates = combo.combos.iter().filter(|candidate| {
+            candidate.is_active(layer_state)
+                && candidate.keys.contains(key)
+                && combo
+                    .buffered
+                    .iter()
+                    .all(|press| candidate.keys.contains(&press.key))
+        });
+
+        if candidates.next().is_none() {
+            if self.combo.buffered.is_empty() {
+                return false;
+            }
+
+            /* the key ends the window, it may start another combo */
+            self.resolve_combo(now, hid);
+            return self.combo_pressed(key, now, hid);
+        }
+
+        self.combo
+            .buffered
+            .push(BufferedPress {
+                key: *key,
+                time: now,
+            })
+            .expect("Error buffering a combo key, COMBO_KEYS is too small!");
+
+        /* no longer combo left to wait for */
+        let buffered = &self.combo.buffered;
+        let complete = self
+            .combo
+            .combos
+            .iter()
+            .filter(|candidate| {
+                candidate.is_active(&self.layer_state)
+                    && buffered
+                        .iter()
+                        .all(|press| candidate.keys.contains(&press.key))
+            })
+            .all(|candidate| candidate.keys.len() == buffered.len());
+
+        if complete {
+            self.resolve_combo(now, hid);
+        }
+
+        true
+    }
+
+    /* returns true when the key was one of a combo that was sent */
+    pub(super) fn combo_released<H: HidSink>(
+        &mut self,
+        key: &Key,
+        now: Instant,
+        hid: &mut H,
+    ) -> bool {
+        /* released inside the combo term */
+        if self.combo.is_buffered(key) {
+            self.resolve_combo(now, hid);
+        }
+
+        let Some(position) = self
+            .combo
+            .active
+            .iter()
+            .position(|active| active.held.contains(key))
+        else {
+            return false;
+        };
+
+        let active = &mut self.combo.active[position];
+        active.held.retain(|held| held != key);
+
+        if !active.released {
+            active.released = true;
+
+            let action = self.combo.combos[active.index].action;
+            self.release_key(&action, hid);
+        }
+
+        if self.combo.active[position].held.is_empty() {
+            self.combo.active.swap_remove(position);
+        }
+
+        true
+    }
+
+    /* send the combo with the most buffered keys, the first declared on a tie, the other keys are applied unchanged */
+    pub(super) fn resolve_combo<H: HidSink>(&mut self, now: Instant, hid: &mut H) {
+        let buffered = core::mem::take(&mut self.combo.buffered);
+
+        let fired = self
+            .combo
+            .combos
+            .iter()
+            .enumerate()
+            .filter(|(_, candidate)| {
+                candidate.is_active(&self.layer_state)
+                    && candidate
+                        .keys
+                        .iter()
+                        .all(|key| buffered.iter().any(|press| press.key == *key))
+            })
+            .max_by_key(|(index, candidate)| (candidate.keys.len(), core::cmp::Reverse(*index)))
+            .map(|(index, _)| index);
+
+        if let Some(index) = fired {
+            let combo = self.combo.combos[index].clone();
+
+            #[cfg(feature = "debug")]
+            log::info!("Combo {:?} sent {:?}", combo.keys, combo.action);
+
+            /* the combo is another key for an undecided tap hold or tap dance key */
+            if self.tap_hold.pending.is_some() {
+                self.resolve_tap_hold(Decision::Hold, now, hid);
+            }
+            self.resolve_tap_dance(hid);
+
+            if !matches!(KeyType::check_type(&combo.action), KeyType::OneShot) {
+                self.release_one_shot_layer();
+            }
+
+            self.press_key(&combo.action, now, hid);
+
+            self.combo
+                .active
+                .push(ActiveCombo {
+                    index,
+                    held: combo.keys,
+                    released: false,
+                })
+                .ok();
+        }
+
+        /* the keys that are not part of the combo are applied in the order they were pressed */
+        for press in buffered.iter() {
+            let sent =
+                fired.is_some_and(|index| self.combo.combos[index].keys.contains(&press.key));
+            if !sent {
+                self.tap_hold_pressed(&press.key, press.time, hid);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::enums::HidKeys;
+    use crate::config::layers::{Combo, Layer};
+    use crate::testing::*;
+
+    const J: Key = Key { row: 1, col: 0 };
+    const K: Key = Key { row: 1, col: 1 };
+    const L: Key = Key { row: 1, col: 2 };
+    const LAYER: Key = Key { row: 3, col: 0 };
+
+    /* J K sends escape, J K L enter, K L tab on the layer 1 only */
+    fn combo_processor() -> KeyProcessor {
+        let mut key_processor = processor(&[
+            (Layer::BASE, J, HidKeys::J),
+            (Layer::BASE, K, HidKeys::K),
+            (Layer::BASE, L, HidKeys::L),
+            (Layer::BASE, LAYER, HidKeys::LayerMomentary1),
+        ]);
+
+        let combos = key_processor.combos_mut();
+        combos.clear();
+        for combo in [
+            Combo::new(&[J, K], HidKeys::Escape, Combo::ALL_LAYERS),
+            Combo::new(&[J, K, L], HidKeys::Enter, Combo::ALL_LAYERS),
+            Combo::new(&[K, L], HidKeys::Tab, 1 << 1),
+        ] {
+            combos.push(combo).ok();
+        }
+
+        key_processor
+    }
+
+    /* J K waits for L till the end of the combo term, then escape is sent */
+    #[test]
+    fn pressed_inside_the_term() {
+        let mut key_processor = combo_processor();
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, J, 0);
+        press(&mut key_processor, &mut sink, K, 10);
+        assert!(sink.events.is_empty());
+        assert_eq!(key_processor.next_deadline(), Some(t(0) + COMBO_TERM));
+
+        run_until(&mut key_processor, &mut sink, COMBO_TERM.as_millis());
+        assert_eq!(sink.key_reports(), vec![(0, vec![0x29])]);
+
+        /* released with the first of its keys */
+        release(&mut key_processor, &mut sink, J, 100);
+        release(&mut key_processor, &mut sink, K, 110);
+        assert_eq!(sink.key_reports(), vec![(0, vec![0x29]), (0, vec![])]);
+    }
+
+    /* the second key comes after the combo term, both keys are typed */
+    #[test]
+    fn pressed_outside_the_term() {
+        let mut key_processor = combo_processor();
+        let mut sink = RecordingSink::default();
+
+        let late = COMBO_TERM.as_millis() + 10;
+        press(&mut key_processor, &mut sink, J, 0);
+        press(&mut key_processor, &mut sink, K, late);
+        run_until(&mut key_processor, &mut sink, late * 2);
+        release(&mut key_processor, &mut sink, J, 200);
+        release(&mut key_processor, &mut sink, K, 210);
+
+        assert_eq!(
+            sink.key_reports(),
+            vec![
+                (0, vec![0x0D]),
+                (0, vec![0x0D, 0x0E]),
+                (0, vec![0x0E]),
+                (0, vec![])
+            ]
+        );
+    }
+
+    /* a key released inside the term ends it, the key is typed */
+    #[test]
+    fn released_inside_the_term() {
+        let mut key_processor = combo_processor();
+        let mut sink = RecordingSink::default();
+
+        tap(&mut key_processor, &mut sink, J, 0);
+        run_until(&mut key_processor, &mut sink, 100);
+
+        assert_eq!(sink.key_reports(), vec![(0, vec![0x0D]), (0, vec![])]);
+    }
+
+    /* the combo with the most keys wins, it is sent as soon as no larger combo is left */
+    #[test]
+    fn largest_match_wins() {
+        let mut key_processor = combo_processor();
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, J, 0);
+        press(&mut key_processor, &mut sink, K, 5);
+        press(&mut key_processor, &mut sink, L, 10);
+        assert_eq!(sink.key_reports(), vec![(0, vec![0x28])]);
+
+        release(&mut key_processor, &mut sink, L, 100);
+        release(&mut key_processor, &mut sink, K, 110);
+        release(&mut key_processor, &mut sink, J, 120);
+        assert_eq!(sink.key_reports(), vec![(0, vec![0x28]), (0, vec![])]);
+    }
+
+    /* K L is a combo on the layer 1 only */
+    #[test]
+    fn layer_restriction() {
+        let mut key_processor = combo_processor();
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, K, 0);
+        press(&mut key_processor, &mut sink, L, 5);
+        run_until(&mut key_processor, &mut sink, 100);
+        release(&mut key_processor, &mut sink, K, 100);
+        release(&mut key_processor, &mut sink, L, 110);
+
+        assert_eq!(
+            sink.key_reports(),
+            vec![
+                (0, vec![0x0E]),
+                (0, vec![0x0E, 0x0F]),
+                (0, vec![0x0F]),
+                (0, vec![])
+            ]
+        );
+
+        sink.clear();
+        press(&mut key_processor, &mut sink, LAYER, 200);
+        press(&mut key_processor, &mut sink, K, 300);
+        press(&mut key_processor, &mut sink, L, 305);
+        release(&mut key_processor, &mut sink, K, 400);
+        release(&mut key_processor, &mut sink, L, 410);
+        release(&mut key_processor, &mut sink, LAYER, 500);
+
+        /* the layer key sends an empty report when pressed and when released */
+        assert_eq!(
+            sink.key_reports(),
+            vec![(0, vec![]), (0, vec![0x2B]), (0, vec![]), (0, vec![])]
+        );
+    }
+}
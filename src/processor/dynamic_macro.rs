@@ -0,0 +1,301 @@
+use crate::config::config::*;
+use crate::config::enums::{DynamicMacroAction, HidKeys, HidModifiers, KeyType};
+use crate::config::layers::{DynamicMacro, RecordedKey};
+use crate::events::DropCounter;
+use crate::processor::macros::MacroIndex;
+use crate::processor::KeyProcessor;
+
+use embassy_time::Instant;
+use heapless::Vec;
+
+/* the modifier keys of the HidModifiers bits, recorded for the modifiers set without a key */
+const MODIFIER_KEYS: [HidKeys; 4] = [
+    HidKeys::ModifierControl,
+    HidKeys::ModifierShift,
+    HidKeys::ModifierAlt,
+    HidKeys::ModifierSuper,
+];
+
+/* the slot that is recorded, it replaces the slot when the recording stops */
+pub struct Recording {
+    pub slot: usize,
+    pub keys: DynamicMacro,
+}
+
+impl Recording {
+    /* the recorded keys not released yet, in the order they were pressed */
+    fn held_keys(&self) -> Vec<HidKeys, DYNAMIC_MACRO_SIZE> {
+        let mut held: Vec<HidKeys, DYNAMIC_MACRO_SIZE> = Vec::new();
+
+        for recorded in self.keys.iter() {
+            if recorded.pressed {
+                held.push(recorded.key).ok();
+            } else if let Some(position) = held.iter().rposition(|key| *key == recorded.key) {
+                held.remove(position);
+            }
+        }
+
+        held
+    }
+}
+
+pub struct DynamicMacroState {
+    pub recording: Option<Recording>,
+    /* a slot was recorded, the processing task saves the slots */
+    pub recorded: bool,
+    pub dropped: DropCounter,
+}
+
+impl DynamicMacroState {
+    pub fn new() -> Self {
+        DynamicMacroState {
+            recording: None,
+            recorded: false,
+            dropped: DropCounter::new("Dynamic macro"),
+        }
+    }
+}
+
+impl KeyProcessor {
+    /* a record key starts the recording of its slot, any record key or the stop key ends it */
+    pub(super) fn press_dynamic_macro(&mut self, valid_key: &HidKeys, now: Instant) {
+        match DynamicMacroAction::get_dynamic_macro_action(valid_key) {
+            Some(DynamicMacroAction::Record(slot)) => {
+                if self.dynamic_macro.recording.is_some() {
+                    self.stop_recording();
+                } else if self.macros.is_playing(MacroIndex::Dynamic(slot)) {
+                    #[cfg(feature = "debug")]
+                    log::info!("Dynamic macro {} is playing, not recorded", slot);
+                } else {
+                    self.dynamic_macro.recording = Some(Recording {
+                        slot,
+                        keys: DynamicMacro::new(),
+                    });
+
+                    #[cfg(feature = "debug")]
+                    log::info!("Recording dynamic macro {}", slot);
+                }
+            }
+            Some(DynamicMacroAction::Stop) => self.stop_recording(),
+            Some(DynamicMacroAction::Play(slot)) => {
+                /* a slot does not play into its own recording */
+                if self
+                    .dynamic_macro
+                    .recording
+                    .as_ref()
+                    .is_some_and(|recording| recording.slot == slot)
+                {
+                    #[cfg(feature = "debug")]
+                    log::info!("Dynamic macro {} is recorded, not played", slot);
+                } else {
+                    self.macros.start(MacroIndex::Dynamic(slot), now);
+                }
+            }
+            None => { /* not a dynamic macro key */ }
+        }
+    }
+
+    /* the keys still held are released at the end of the slot */
+    fn stop_recording(&mut self) {
+        let Some(mut recording) = self.dynamic_macro.recording.take() else {
+            return;
+        };
+
+        for key in recording.held_keys().iter().rev() {
+            recording
+                .keys
+                .push(RecordedKey {
+                    key: *key,
+                    pressed: false,
+                })
+                .expect("Error recording a dynamic macro, no room kept for the releases!");
+        }
+
+        #[cfg(feature = "debug")]
+        log::info!(
+            "Dynamic macro {} recorded, {} key events",
+            recording.slot,
+            recording.keys.len()
+        );
+
+        self.macros.dynamic_macros[recording.slot] = recording.keys;
+        self.dynamic_macro.recorded = true;
+    }
+
+    /* add a key sent while recording, a press is dropped when there is no room left for it and its release */
+    pub(super) fn record_key(&mut self, valid_key: &HidKeys, pressed: bool) {
+        let Some(recording) = self.dynamic_macro.recording.as_mut() else {
+            return;
+        };
+
+        /* the keys that are sent, the layer and the special keys are resolved before they get recorded */
+        if !matches!(
+            KeyType::check_type(valid_key),
+            KeyType::Key | KeyType::Modifier | KeyType::Consumer | KeyType::Macro
+        ) {
+            return;
+        }
+
+        let recorded = RecordedKey {
+            key: *valid_key,
+            pressed,
+        };
+
+        let held = recording.held_keys();
+
+        /* every recorded press keeps room for its release */
+        if pressed {
+            let room = recording.keys.capacity() - recording.keys.len() - held.len();
+
+            if room < 2 {
+                self.dynamic_macro.dropped.count();
+                return;
+            }
+
+            recording.keys.push(recorded).ok();
+        } else if held.contains(valid_key) {
+            recording
+                .keys
+                .push(recorded)
+                .expect("Error recording a dynamic macro, no room kept for the releases!");
+        }
+    }
+
+    /* the modifiers set in the report without their keys, by the one shot keys and the text of the macros */
+    pub(super) fn record_modifiers(&mut self, modifiers: u8, pressed: bool) {
+        for key in MODIFIER_KEYS.iter() {
+            if modifiers & HidModifiers::get_modifier(key) != 0 {
+                self.record_key(key, pressed);
+            }
+        }
+    }
+
+    /* a slot was recorded since the last call */
+    pub fn take_recorded_macro(&mut self) -> bool {
+        core::mem::take(&mut self.dynamic_macro.recorded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::layers::Layer;
+    use crate::matrix::Key;
+    use crate::testing::*;
+
+    const A: Key = Key { row: 1, col: 0 };
+    const B: Key = Key { row: 1, col: 1 };
+    const RECORD: Key = Key { row: 3, col: 0 };
+    const RECORD_1: Key = Key { row: 3, col: 1 };
+    const PLAY: Key = Key { row: 3, col: 2 };
+
+    fn dynamic_macro_processor() -> KeyProcessor {
+        processor(&[
+            (Layer::BASE, A, HidKeys::A),
+            (Layer::BASE, B, HidKeys::B),
+            (Layer::BASE, RECORD, HidKeys::DynamicMacroRecord0),
+            (Layer::BASE, RECORD_1, HidKeys::DynamicMacroRecord1),
+            (Layer::BASE, PLAY, HidKeys::DynamicMacroPlay0),
+        ])
+    }
+
+    fn recorded(events: &[(HidKeys, bool)]) -> DynamicMacro {
+        events
+            .iter()
+            .map(|(key, pressed)| RecordedKey {
+                key: *key,
+                pressed: *pressed,
+            })
+            .collect()
+    }
+
+    /* the keys sent while recording are played back by the play key */
+    #[test]
+    fn record_and_play() {
+        let mut key_processor = dynamic_macro_processor();
+        let mut sink = RecordingSink::default();
+
+        tap(&mut key_processor, &mut sink, RECORD, 0);
+        tap(&mut key_processor, &mut sink, A, 100);
+        tap(&mut key_processor, &mut sink, B, 200);
+        tap(&mut key_processor, &mut sink, RECORD, 300);
+
+        assert!(key_processor.take_recorded_macro());
+        assert_eq!(
+            key_processor.dynamic_macros()[0],
+            recorded(&[
+                (HidKeys::A, true),
+                (HidKeys::A, false),
+                (HidKeys::B, true),
+                (HidKeys::B, false)
+            ])
+        );
+
+        sink.clear();
+        tap(&mut key_processor, &mut sink, PLAY, 400);
+        run_until(&mut key_processor, &mut sink, 1000);
+
+        assert_eq!(
+            sink.key_reports(),
+            vec![(0, vec![0x04]), (0, vec![]), (0, vec![0x05]), (0, vec![])]
+        );
+    }
+
+    /* any record key pressed while recording stops it, the keys still held are released in the slot */
+    #[test]
+    fn record_pressed_while_recording() {
+        let mut key_processor = dynamic_macro_processor();
+        let mut sink = RecordingSink::default();
+
+        tap(&mut key_processor, &mut sink, RECORD, 0);
+        press(&mut key_processor, &mut sink, A, 100);
+        tap(&mut key_processor, &mut sink, RECORD_1, 200);
+        release(&mut key_processor, &mut sink, A, 300);
+
+        assert!(key_processor.dynamic_macro.recording.is_none());
+        assert_eq!(
+            key_processor.dynamic_macros()[0],
+            recorded(&[(HidKeys::A, true), (HidKeys::A, false)])
+        );
+        /* the other slot was not recorded */
+        assert!(key_processor.dynamic_macros()[1].is_empty());
+    }
+
+    /* a full slot drops the presses, it keeps the room for the releases of the held keys */
+    #[test]
+    fn slot_overflow() {
+        let mut key_processor = dynamic_macro_processor();
+        let mut sink = RecordingSink::default();
+
+        tap(&mut key_processor, &mut sink, RECORD, 0);
+        press(&mut key_processor, &mut sink, B, 50);
+        for index in 0..DYNAMIC_MACRO_SIZE as u64 {
+            tap(&mut key_processor, &mut sink, A, 100 + index * 20);
+        }
+        tap(&mut key_processor, &mut sink, RECORD, 5000);
+
+        let slot = &key_processor.dynamic_macros()[0];
+        assert_eq!(slot.len(), DYNAMIC_MACRO_SIZE);
+        assert_eq!(
+            slot.last(),
+            Some(&RecordedKey {
+                key: HidKeys::B,
+                pressed: false
+            })
+        );
+        assert_eq!(
+            slot.iter().filter(|recorded| recorded.pressed).count(),
+            DYNAMIC_MACRO_SIZE / 2
+        );
+        assert!(key_processor.dynamic_macro.dropped.dropped() > 0);
+
+        /* every key was still sent */
+        assert_eq!(
+            sink.key_reports()
+                .iter()
+                .filter(|(_, keys)| keys.contains(&0x04))
+                .count(),
+            DYNAMIC_MACRO_SIZE
+        );
+    }
+}
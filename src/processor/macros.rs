@@ -0,0 +1,339 @@
+use crate::config::config::*;
+use crate::config::enums::{HidKeys, HidModifiers};
+use crate::config::layers::{DynamicMacros, MacroStep, Macros};
+use crate::events::DropCounter;
+use crate::hid::HidSink;
+use crate::processor::KeyProcessor;
+
+use embassy_time::{Duration, Instant};
+use heapless::Deque;
+
+/* a macro of the layout or a recorded slot */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MacroIndex {
+    Layout(usize),
+    Dynamic(usize),
+}
+
+/* the position in the macro that is played */
+#[derive(Clone, Copy, Debug)]
+pub struct PlayingMacro {
+    pub index: MacroIndex,
+    pub step: usize,
+    /* the next character of a Text step */
+    pub character: usize,
+    /* when the next step is due, after a Delay step */
+    pub time: Instant,
+}
+
+pub struct MacroPlayer {
+    pub macros: Macros,
+    /* recorded by the dynamic macro keys */
+    pub dynamic_macros: DynamicMacros,
+    pub playing: Option<PlayingMacro>,
+    /* the macro keys pressed while a macro plays */
+    pub queue: Deque<MacroIndex, MACRO_QUEUE_SIZE>,
+    pub dropped: DropCounter,
+}
+
+impl MacroPlayer {
+    pub fn new(macros: Macros) -> Self {
+        MacroPlayer {
+            macros,
+            dynamic_macros: Default::default(),
+            playing: None,
+            queue: Deque::new(),
+            dropped: DropCounter::new("Macro queue"),
+        }
+    }
+
+    /* play the macro once the previous ones are done */
+    pub fn start(&mut self, index: MacroIndex, now: Instant) {
+        if self.playing.is_none() {
+            self.playing = Some(PlayingMacro {
+                index,
+                step: 0,
+                character: 0,
+                time: now,
+            });
+        } else if self.queue.push_back(index).is_err() {
+            self.dropped.count();
+        }
+    }
+
+    /* when the next step is due */
+    pub fn deadline(&self) -> Option<Instant> {
+        self.playing.map(|playing| playing.time)
+    }
+
+    /* the macro plays or waits in the queue */
+    pub fn is_playing(&self, index: MacroIndex) -> bool {
+        self.playing.is_some_and(|playing| playing.index == index)
+            || self.queue.iter().any(|queued| *queued == index)
+    }
+
+    /* the recorded key events are Press and Release steps */
+    fn step(&self, playing: &PlayingMacro) -> Option<MacroStep> {
+        match playing.index {
+            MacroIndex::Layout(index) => self.macros[index].get(playing.step).copied(),
+            MacroIndex::Dynamic(slot) => {
+                self.dynamic_macros[slot].get(playing.step).map(|recorded| {
+                    if recorded.pressed {
+                        MacroStep::Press(recorded.key)
+                    } else {
+                        MacroStep::Release(recorded.key)
+                    }
+                })
+            }
+        }
+    }
+
+    fn next_macro(&mut self, now: Instant) {
+        self.playing = None;
+
+        if let Some(index) = self.queue.pop_front() {
+            self.start(index, now);
+        }
+    }
+}
+
+impl KeyProcessor {
+    /* play the due steps, at most MACRO_REPORTS_PER_TICK reports, the rest on the next tick */
+    pub(super) fn tick_macros<H: HidSink>(&mut self, now: Instant, hid: &mut H) {
+        let mut reports = 0;
+
+        while reports < MACRO_REPORTS_PER_TICK {
+            let Some(mut playing) = self.macros.playing else {
+                return;
+            };
+
+            if now < playing.time {
+                return;
+            }
+
+            let Some(step) = self.macros.step(&playing) else {
+                self.macros.next_macro(now);
+                continue;
+            };
+
+            playing.step += 1;
+
+            match step {
+                MacroStep::Tap(key) => {
+                    self.press_key(&key, now, hid);
+                    self.release_key(&key, hid);
+                    reports += 2;
+                }
+                MacroStep::Press(key) => {
+                    self.press_key(&key, now, hid);
+                    reports += 1;
+                }
+                MacroStep::Release(key) => {
+                    self.release_key(&key, hid);
+                    reports += 1;
+                }
+                MacroStep::Text(text) => {
+                    if let Some(character) = text.as_bytes().get(playing.character) {
+                        self.type_character(*character, now, hid);
+                        reports += 2;
+
+                        /* the same step again for the next character */
+                        playing.character += 1;
+                        playing.step -= 1;
+                    } else {
+                        playing.character = 0;
+                    }
+                }
+                MacroStep::Delay(ms) => {
+                    playing.time = now + Duration::from_millis(ms as u64);
+                }
+            }
+
+            self.macros.playing = Some(playing);
+        }
+    }
+
+    /* press and release the key of the character, with shift in the same reports when it is needed */
+    fn type_character<H: HidSink>(&mut self, character: u8, now: Instant, hid: &mut H) {
+        let Some((key, shift)) = HidKeys::from_ascii(character, HOST_LAYOUT) else {
+            #[cfg(feature = "debug")]
+            log::info!("No key for the character {:#04x}", character);
+
+            return;
+        };
+
+        /* a held shift is left to its key */
+        let modifiers = if shift {
+            HidModifiers::Shift as u8 & !self.reports.key_report.modifiers
+        } else {
+            0
+        };
+
+        self.reports.press_modifiers(modifiers);
+        self.record_modifiers(modifiers, true);
+        self.press_key(&key, now, hid);
+
+        self.reports.release_modifiers(modifiers);
+        self.record_modifiers(modifiers, false);
+        self.release_key(&key, hid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::layers::Layer;
+    use crate::matrix::Key;
+    use crate::testing::*;
+
+    const MACRO0: Key = Key { row: 0, col: 0 };
+    const MACRO1: Key = Key { row: 0, col: 1 };
+
+    const CONTROL: u8 = HidModifiers::Control as u8;
+    const SHIFT: u8 = HidModifiers::Shift as u8;
+
+    fn macro_processor(macros: &[&'static [MacroStep]]) -> KeyProcessor {
+        let mut key_processor = processor(&[
+            (Layer::BASE, MACRO0, HidKeys::Macro0),
+            (Layer::BASE, MACRO1, HidKeys::Macro1),
+        ]);
+
+        key_processor.macros_mut()[..macros.len()].copy_from_slice(macros);
+
+        key_processor
+    }
+
+    /* the steps are played in order once the macro key is pressed, the key itself sends nothing */
+    #[test]
+    fn steps_played() {
+        let mut key_processor = macro_processor(&[&[
+            MacroStep::Press(HidKeys::ModifierControl),
+            MacroStep::Tap(HidKeys::K),
+            MacroStep::Release(HidKeys::ModifierControl),
+        ]]);
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, MACRO0, 0);
+        assert!(sink.events.is_empty());
+
+        run_until(&mut key_processor, &mut sink, 0);
+        release(&mut key_processor, &mut sink, MACRO0, 10);
+
+        assert_eq!(
+            sink.key_reports(),
+            vec![
+                (CONTROL, vec![]),
+                (CONTROL, vec![0x0E]),
+                (CONTROL, vec![]),
+                (0, vec![]),
+            ]
+        );
+        assert_eq!(key_processor.next_deadline(), None);
+    }
+
+    /* every character is pressed and released, with shift in the same reports when it is needed */
+    #[test]
+    fn text_typed() {
+        let mut key_processor = macro_processor(&[&[MacroStep::Text("aB!")]]);
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, MACRO0, 0);
+        run_until(&mut key_processor, &mut sink, 0);
+
+        assert_eq!(
+            sink.key_reports(),
+            vec![
+                (0, vec![0x04]),
+                (0, vec![]),
+                (SHIFT, vec![0x05]),
+                (0, vec![]),
+                (SHIFT, vec![0x1E]),
+                (0, vec![]),
+            ]
+        );
+    }
+
+    /* the steps after a delay wait for it */
+    #[test]
+    fn delay_step() {
+        let mut key_processor = macro_processor(&[&[
+            MacroStep::Tap(HidKeys::A),
+            MacroStep::Delay(100),
+            MacroStep::Tap(HidKeys::B),
+        ]]);
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, MACRO0, 0);
+        run_until(&mut key_processor, &mut sink, 0);
+        assert_eq!(sink.key_reports(), vec![(0, vec![0x04]), (0, vec![])]);
+        assert_eq!(key_processor.next_deadline(), Some(t(100)));
+
+        run_until(&mut key_processor, &mut sink, 99);
+        assert_eq!(sink.key_reports().len(), 2);
+
+        run_until(&mut key_processor, &mut sink, 100);
+        assert_eq!(sink.key_reports()[2..], [(0, vec![0x05]), (0, vec![])]);
+        assert_eq!(key_processor.next_deadline(), None);
+    }
+
+    /* a long macro is sent over several ticks, MACRO_REPORTS_PER_TICK reports at a time */
+    #[test]
+    fn split_across_ticks() {
+        const TEXT: &str = "abcdefghijklmnopqrstuvwxyz";
+        assert!(MACRO_REPORTS_PER_TICK < TEXT.len() * 2);
+
+        let mut key_processor = macro_processor(&[&[MacroStep::Text(TEXT)]]);
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, MACRO0, 0);
+
+        key_processor.tick(t(0), &mut sink);
+        assert_eq!(sink.key_reports().len(), MACRO_REPORTS_PER_TICK);
+
+        /* the rest is due right away, on the next tick */
+        assert_eq!(key_processor.next_deadline(), Some(t(0)));
+        run_until(&mut key_processor, &mut sink, 0);
+
+        let typed: Vec<u8> = sink
+            .key_reports()
+            .iter()
+            .filter_map(|(_, keys)| keys.first().copied())
+            .collect();
+        assert_eq!(typed, (0x04..=0x1D).collect::<Vec<u8>>());
+    }
+
+    /* a macro key pressed while a macro plays is played after it */
+    #[test]
+    fn second_macro_queued() {
+        let mut key_processor = macro_processor(&[
+            &[
+                MacroStep::Tap(HidKeys::A),
+                MacroStep::Delay(50),
+                MacroStep::Tap(HidKeys::B),
+            ],
+            &[MacroStep::Tap(HidKeys::C)],
+        ]);
+        let mut sink = RecordingSink::default();
+
+        tap(&mut key_processor, &mut sink, MACRO0, 0);
+        tap(&mut key_processor, &mut sink, MACRO1, 20);
+        assert_eq!(sink.key_reports(), vec![(0, vec![0x04]), (0, vec![])]);
+
+        /* the delay started with the tick of the release */
+        assert_eq!(key_processor.next_deadline(), Some(t(60)));
+        run_until(&mut key_processor, &mut sink, 60);
+
+        assert_eq!(
+            sink.key_reports(),
+            vec![
+                (0, vec![0x04]),
+                (0, vec![]),
+                (0, vec![0x05]),
+                (0, vec![]),
+                (0, vec![0x06]),
+                (0, vec![]),
+            ]
+        );
+        assert_eq!(key_processor.next_deadline(), None);
+    }
+}
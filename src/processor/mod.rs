@@ -0,0 +1,636 @@
+use crate::config::config::*;
+use crate::config::enums::{HidConsumer, HidKeys, HidModifiers, KeyType, ProfileAction};
+use crate::config::layers::*;
+use crate::config::layout::{provide_combos, provide_macros, provide_tap_dances};
+use crate::hid::{HidReports, HidSink, KeyReport, NkroReport, ReportMode};
+use crate::matrix::{Key, KeyStates, MatrixEvent};
+
+use embassy_time::Instant;
+
+mod combo;
+mod dynamic_macro;
+mod macros;
+mod one_shot;
+mod tap_dance;
+mod tap_hold;
+mod task;
+
+pub use task::*;
+
+use combo::ComboState;
+use dynamic_macro::DynamicMacroState;
+use macros::{MacroIndex, MacroPlayer};
+use one_shot::OneShotState;
+use tap_dance::TapDanceState;
+use tap_hold::{Decision, TapHoldState};
+
+pub struct KeyProcessor {
+    layers: Layers,
+    layer_state: LayerState,
+    reports: HidReports,
+    report_mode: ReportMode,
+    /* the host asked for the boot protocol, only the key report is understood */
+    boot_protocol: bool,
+    /* the last profile key pressed, applied by the ble task */
+    profile_action: Option<(ProfileAction, u8)>,
+    /* the deep sleep key was pressed, applied by the scanner */
+    deep_sleep_request: bool,
+    /* the key each pressed matrix position resolved to, so it is released the way it was pressed */
+    keys_resolved: KeyStates<Option<HidKeys>>,
+    tap_hold: TapHoldState,
+    one_shot: OneShotState,
+    combo: ComboState,
+    tap_dance: TapDanceState,
+    macros: MacroPlayer,
+    dynamic_macro: DynamicMacroState,
+}
+
+impl KeyProcessor {
+    pub fn new(layers: Layers) -> Self {
+        KeyProcessor {
+            layers,
+            layer_state: LayerState::new(),
+            reports: HidReports::new(),
+            report_mode: if NKRO {
+                ReportMode::Nkro
+            } else {
+                ReportMode::SixKro
+            },
+            boot_protocol: false,
+            profile_action: None,
+            deep_sleep_request: false,
+            keys_resolved: KeyStates::default(),
+            tap_hold: TapHoldState::new(),
+            one_shot: OneShotState::new(),
+            combo: ComboState::new(provide_combos()),
+            tap_dance: TapDanceState::new(provide_tap_dances()),
+            macros: MacroPlayer::new(provide_macros()),
+            dynamic_macro: DynamicMacroState::new(),
+        }
+    }
+
+    pub fn key_report(&self) -> &KeyReport {
+        &self.reports.key_report
+    }
+
+    pub fn reports(&self) -> &HidReports {
+        &self.reports
+    }
+
+    /* the report the keys are sent in, the key report when the host uses the boot protocol */
+    pub fn report_mode(&self) -> ReportMode {
+        if self.boot_protocol {
+            ReportMode::SixKro
+        } else {
+            self.report_mode
+        }
+    }
+
+    pub fn set_report_mode<H: HidSink>(&mut self, report_mode: ReportMode, hid: &mut H) {
+        self.switch_report_mode(hid, |processor| processor.report_mode = report_mode);
+    }
+
+    pub fn set_boot_protocol<H: HidSink>(&mut self, boot_protocol: bool, hid: &mut H) {
+        self.switch_report_mode(hid, |processor| processor.boot_protocol = boot_protocol);
+    }
+
+    fn switch_report_mode<H: HidSink>(&mut self, hid: &mut H, switch: impl FnOnce(&mut Self)) {
+        let report_mode = self.report_mode();
+        switch(self);
+
+        if report_mode != self.report_mode() {
+            #[cfg(feature = "debug")]
+            log::info!("Report mode: {:?}", self.report_mode());
+
+            /* the held keys move from the previous report to the new one */
+            match report_mode {
+                ReportMode::SixKro => hid.send_report(&KeyReport::new()),
+                ReportMode::Nkro => hid.send_nkro_report(&NkroReport::new()),
+            }
+            self.send_key_report(hid);
+        }
+    }
+
+    /* the keymap, remapped at runtime */
+    pub fn layers_mut(&mut self) -> &mut Layers {
+        &mut self.layers
+    }
+
+    /* the combos of the layout */
+    pub fn combos_mut(&mut self) -> &mut Combos {
+        &mut self.combo.combos
+    }
+
+    /* the tap dance keys of the layout */
+    pub fn tap_dances_mut(&mut self) -> &mut TapDances {
+        &mut self.tap_dance.tap_dances
+    }
+
+    /* the macro table of the layout */
+    pub fn macros_mut(&mut self) -> &mut Macros {
+        &mut self.macros.macros
+    }
+
+    /* the slots recorded by the dynamic macro keys */
+    pub fn dynamic_macros(&self) -> &DynamicMacros {
+        &self.macros.dynamic_macros
+    }
+
+    pub fn dynamic_macros_mut(&mut self) -> &mut DynamicMacros {
+        &mut self.macros.dynamic_macros
+    }
+
+    pub fn layer_state(&self) -> &LayerState {
+        &self.layer_state
+    }
+
+    /* the profile key pressed since the last call */
+    pub fn take_profile_action(&mut self) -> Option<(ProfileAction, u8)> {
+        self.profile_action.take()
+    }
+
+    pub fn take_deep_sleep_request(&mut self) -> bool {
+        core::mem::take(&mut self.deep_sleep_request)
+    }
+
+    /* the key a held matrix position resolved to */
+    pub fn resolved_key(&self, key: &Key) -> Option<&HidKeys> {
+        self.keys_resolved.get(key)?.as_ref()
+    }
+
+    pub fn key_pressed<H: HidSink>(&mut self, key: &Key, now: Instant, hid: &mut H) {
+        /* a key of a combo waits for the other keys */
+        if self.combo_pressed(key, now, hid) {
+            return;
+        }
+
+        self.tap_hold_pressed(key, now, hid);
+    }
+
+    pub fn key_released<H: HidSink>(&mut self, key: &Key, now: Instant, hid: &mut H) {
+        if self.combo_released(key, now, hid) {
+            return;
+        }
+
+        self.tap_hold_released(key, now, hid);
+    }
+
+    fn tap_hold_pressed<H: HidSink>(&mut self, key: &Key, now: Instant, hid: &mut H) {
+        /* a tap hold key is waiting for its decision */
+        if self.tap_hold.pending.is_some() {
+            if !HOLD_ON_OTHER_KEY_PRESS && self.tap_hold.buffer(key, true, now) {
+                return;
+            }
+
+            /* the other key decides the tap hold key */
+            self.resolve_tap_hold(Decision::Hold, now, hid);
+
+            /* the replayed keys may have started a new tap hold */
+            return self.tap_hold_pressed(key, now, hid);
+        }
+
+        self.apply_press(key, now, hid);
+    }
+
+    fn tap_hold_released<H: HidSink>(&mut self, key: &Key, now: Instant, hid: &mut H) {
+        if let Some(pending) = self.tap_hold.pending {
+            /* released inside the tapping term */
+            if pending.key == *key {
+                self.resolve_tap_hold(Decision::Tap, now, hid);
+                return;
+            }
+
+            /* keys pressed after the tap hold key are released after the decision */
+            if self.tap_hold.is_buffered(key) {
+                if !PERMISSIVE_HOLD && self.tap_hold.buffer(key, false, now) {
+                    return;
+                }
+
+                self.resolve_tap_hold(Decision::Hold, now, hid);
+                return self.tap_hold_released(key, now, hid);
+            }
+        }
+
+        self.apply_release(key, now, hid);
+    }
+
+    /* run the time based decisions */
+    pub fn tick<H: HidSink>(&mut self, now: Instant, hid: &mut H) {
+        /* the combo term passed, the buffered keys are decided */
+        if let Some(deadline) = self.combo.deadline() {
+            if now >= deadline {
+                self.resolve_combo(now, hid);
+            }
+        }
+
+        if let Some(pending) = self.tap_hold.pending {
+            /* held past the tapping term */
+            if now >= pending.pressed_time + TAPPING_TERM {
+                self.resolve_tap_hold(Decision::Hold, now, hid);
+            }
+        }
+
+        self.tick_tap_dance(now, hid);
+        self.tick_one_shot(now);
+        self.tick_macros(now, hid);
+    }
+
+    fn apply_press<H: HidSink>(&mut self, key: &Key, now: Instant, hid: &mut H) {
+        /* a key outside of the matrix, or already applied to the report */
+        if !matches!(self.keys_resolved.get(key), Some(None)) {
+            return;
+        }
+
+        /* another key decides a pending tap dance key, before the key is looked up on the layers it may change */
+        self.tap_dance_interrupted(key, hid);
+
+        /* get the pressed key */
+        if let Some(valid_key) = self
+            .layers
+            .get(&key.row, &key.col, &self.layer_state)
+            .copied()
+        {
+            /* the layer keys above the configured layers do nothing */
+            if let KeyType::Unused = KeyType::check_type(&valid_key) {
+                return;
+            }
+
+            if let Some(resolved) = self.keys_resolved.get_mut(key) {
+                *resolved = Some(valid_key);
+            }
+
+            /* the armed one shot layer was used for this key */
+            if !matches!(KeyType::check_type(&valid_key), KeyType::OneShot) {
+                self.release_one_shot_layer();
+            }
+
+            /* the same key, no longer a tap dance key */
+            if !matches!(KeyType::check_type(&valid_key), KeyType::TapDance) {
+                self.resolve_tap_dance(hid);
+            }
+
+            match KeyType::check_type(&valid_key) {
+                KeyType::TapHold => {
+                    /* wait for the release or the tapping term */
+                    if let Some(tap_hold) = self.layers.get_tap_hold(&valid_key).copied() {
+                        self.tap_hold.start(key, tap_hold, now);
+                    }
+                }
+                KeyType::TapDance => {
+                    /* wait for the next tap, the release or the tapping term */
+                    self.tap_dance_pressed(key, &valid_key, now, hid);
+                }
+                _ => {
+                    self.press_key(&valid_key, now, hid);
+                }
+            }
+        }
+    }
+
+    fn apply_release<H: HidSink>(&mut self, key: &Key, now: Instant, hid: &mut H) {
+        /* release the key that was resolved when it was pressed */
+        if let Some(valid_key) = self.keys_resolved.get_mut(key).and_then(Option::take) {
+            if let KeyType::TapDance = KeyType::check_type(&valid_key) {
+                return self.tap_dance_released(key, now, hid);
+            }
+
+            self.release_key(&valid_key, hid);
+        }
+    }
+
+    /* add the key to its report and send the report */
+    fn press_key<H: HidSink>(&mut self, valid_key: &HidKeys, now: Instant, hid: &mut H) {
+        if let KeyType::Profile = KeyType::check_type(valid_key) {
+            self.profile_action = ProfileAction::get_profile_action(valid_key);
+            return;
+        }
+
+        if let KeyType::Power = KeyType::check_type(valid_key) {
+            self.deep_sleep_request = true;
+            return;
+        }
+
+        if let KeyType::ReportMode = KeyType::check_type(valid_key) {
+            let report_mode = match (*valid_key, self.report_mode) {
+                (HidKeys::ReportModeSixKro, _) | (_, ReportMode::Nkro) => ReportMode::SixKro,
+                _ => ReportMode::Nkro,
+            };
+            return self.set_report_mode(report_mode, hid);
+        }
+
+        if let KeyType::OneShot = KeyType::check_type(valid_key) {
+            return self.press_one_shot(valid_key, now, hid);
+        }
+
+        /* played step by step by tick */
+        if let KeyType::MacroSequence = KeyType::check_type(valid_key) {
+            if let Some(index) = HidKeys::get_macro_index(valid_key) {
+                self.macros.start(MacroIndex::Layout(index), now);
+            }
+            return;
+        }
+
+        if let KeyType::DynamicMacro = KeyType::check_type(valid_key) {
+            return self.press_dynamic_macro(valid_key, now);
+        }
+
+        /* the armed one shot modifiers go to the next key, not to the modifiers and the layer keys */
+        if let KeyType::Key | KeyType::Macro = KeyType::check_type(valid_key) {
+            self.apply_one_shot_modifiers(valid_key);
+        }
+
+        self.record_key(valid_key, true);
+
+        send_keys(&mut self.reports, valid_key, &mut self.layer_state);
+        self.send_reports(valid_key, hid);
+    }
+
+    /* remove the key from its report and send the report */
+    fn release_key<H: HidSink>(&mut self, valid_key: &HidKeys, hid: &mut H) {
+        if let KeyType::ReportMode
+        | KeyType::Profile
+        | KeyType::Power
+        | KeyType::OneShot
+        | KeyType::TapDance
+        | KeyType::MacroSequence
+        | KeyType::DynamicMacro = KeyType::check_type(valid_key)
+        {
+            return;
+        }
+
+        /* the one shot modifiers end with the key that took them */
+        if self.one_shot.applied_to == Some(*valid_key) {
+            self.release_one_shot_modifiers();
+        }
+
+        self.record_key(valid_key, false);
+
+        remove_keys(&mut self.reports, valid_key, &mut self.layer_state);
+        self.send_reports(valid_key, hid);
+    }
+
+    fn send_reports<H: HidSink>(&self, valid_key: &HidKeys, hid: &mut H) {
+        match KeyType::check_type(valid_key) {
+            KeyType::Consumer => hid.send_consumer_report(&self.reports.consumer_report),
+            _ => self.send_key_report(hid),
+        }
+    }
+
+    fn send_key_report<H: HidSink>(&self, hid: &mut H) {
+        match self.report_mode() {
+            ReportMode::SixKro => hid.send_report(&self.reports.key_report),
+            ReportMode::Nkro => hid.send_nkro_report(&self.reports.nkro_report),
+        }
+    }
+
+    /* apply a debounced matrix event */
+    pub fn process_event<H: HidSink>(&mut self, event: &MatrixEvent, hid: &mut H) {
+        if event.pressed {
+            self.key_pressed(&event.key, event.time, hid);
+        } else {
+            self.key_released(&event.key, event.time, hid);
+        }
+
+        #[cfg(feature = "debug")]
+        /* debug log */
+        log::info!("key_report.keys: {:?}", self.reports.key_report.keys);
+    }
+
+    /* when a time based decision is due, tick has to run then */
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let tap_hold = self
+            .tap_hold
+            .pending
+            .map(|pending| pending.pressed_time + TAPPING_TERM);
+
+        [
+            tap_hold,
+            self.one_shot.deadline(),
+            self.combo.deadline(),
+            self.tap_dance.deadline(),
+            self.macros.deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+}
+
+fn send_keys(reports: &mut HidReports, valid_key: &HidKeys, layer_state: &mut LayerState) {
+    /* get the key type */
+    match KeyType::check_type(valid_key) {
+        KeyType::Macro => {
+            let macro_valid_keys = HidKeys::get_macro_sequence(valid_key);
+            for valid_key in macro_valid_keys.iter() {
+                send_keys(reports, valid_key, layer_state);
+            }
+        }
+        KeyType::Layer => {
+            /* check and set the layer, the held keys are released the way they were pressed */
+            layer_state.press(valid_key);
+        }
+        KeyType::TapHold
+        | KeyType::ReportMode
+        | KeyType::Profile
+        | KeyType::Power
+        | KeyType::OneShot
+        | KeyType::TapDance
+        | KeyType::MacroSequence
+        | KeyType::DynamicMacro
+        | KeyType::Unused => { /* resolved by the processor before it gets here */ }
+        KeyType::Modifier => {
+            reports.press_modifiers(HidModifiers::get_modifier(valid_key));
+        }
+        KeyType::Consumer => {
+            /* the media keys go to the consumer report */
+            reports
+                .consumer_report
+                .press(HidConsumer::get_consumer_usage(valid_key));
+        }
+        KeyType::Key => {
+            /* the n-key rollover report takes every key, the key report drops the keys past 6 */
+            reports.press_key(*valid_key as u8);
+        }
+    }
+}
+
+fn remove_keys(reports: &mut HidReports, valid_key: &HidKeys, layer_state: &mut LayerState) {
+    /* get the key type */
+    match KeyType::check_type(valid_key) {
+        KeyType::Macro => {
+            let macro_valid_keys = HidKeys::get_macro_sequence(valid_key);
+            for valid_key in macro_valid_keys.iter() {
+                remove_keys(reports, valid_key, layer_state);
+            }
+        }
+        KeyType::Layer => {
+            /* check and set the layer */
+            layer_state.release(valid_key);
+        }
+        KeyType::TapHold
+        | KeyType::ReportMode
+        | KeyType::Profile
+        | KeyType::Power
+        | KeyType::OneShot
+        | KeyType::TapDance
+        | KeyType::MacroSequence
+        | KeyType::DynamicMacro
+        | KeyType::Unused => { /* resolved by the processor before it gets here */ }
+        KeyType::Modifier => {
+            /* remove the modifier */
+            reports.release_modifiers(HidModifiers::get_modifier(valid_key));
+        }
+        KeyType::Consumer => {
+            reports
+                .consumer_report
+                .release(HidConsumer::get_consumer_usage(valid_key));
+        }
+        KeyType::Key => {
+            /* remove the key from the key slot */
+            reports.release_key(*valid_key as u8);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    const A: Key = Key { row: 1, col: 0 };
+    const LAYER: Key = Key { row: 3, col: 0 };
+
+    #[test]
+    fn key_press_and_release() {
+        let mut key_processor = processor(&[(Layer::BASE, A, HidKeys::A)]);
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, A, 0);
+        assert_eq!(key_processor.resolved_key(&A), Some(&HidKeys::A));
+        release(&mut key_processor, &mut sink, A, 50);
+
+        assert_eq!(sink.key_reports(), vec![(0, vec![0x04]), (0, vec![])]);
+        assert_eq!(key_processor.resolved_key(&A), None);
+    }
+
+    /* a key outside of the layout sends nothing */
+    #[test]
+    fn unmapped_key() {
+        let mut key_processor = processor(&[(Layer::BASE, A, HidKeys::A)]);
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, Key::new(2, 2), 0);
+        release(&mut key_processor, &mut sink, Key::new(2, 2), 50);
+
+        assert!(sink.events.is_empty());
+    }
+
+    /* the layer keys above the configured layers neither type a key nor switch the layer */
+    #[test]
+    fn layer_key_above_layers() {
+        let mut key_processor = processor(&[
+            (Layer::BASE, A, HidKeys::A),
+            (Layer::BASE, LAYER, HidKeys::LayerMomentary7),
+        ]);
+        let mut sink = RecordingSink::default();
+
+        assert!(matches!(
+            KeyType::check_type(&HidKeys::LayerToggle7),
+            KeyType::Unused
+        ));
+
+        press(&mut key_processor, &mut sink, LAYER, 0);
+        assert!(sink.events.is_empty());
+
+        press(&mut key_processor, &mut sink, A, 10);
+        release(&mut key_processor, &mut sink, A, 20);
+        release(&mut key_processor, &mut sink, LAYER, 30);
+
+        assert_eq!(sink.key_reports(), vec![(0, vec![0x04]), (0, vec![])]);
+    }
+
+    /* the profile keys above the configured profiles neither type a key nor select a profile */
+    #[test]
+    fn profile_key_above_profiles() {
+        let mut key_processor = processor(&[
+            (Layer::BASE, A, HidKeys::ProfileSelect1),
+            (Layer::BASE, LAYER, HidKeys::ProfileClear4),
+        ]);
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, LAYER, 0);
+        release(&mut key_processor, &mut sink, LAYER, 10);
+        assert_eq!(key_processor.take_profile_action(), None);
+
+        press(&mut key_processor, &mut sink, A, 20);
+        release(&mut key_processor, &mut sink, A, 30);
+        assert_eq!(
+            key_processor.take_profile_action(),
+            Some((ProfileAction::Select, 1))
+        );
+
+        assert!(sink.events.is_empty());
+    }
+
+    /* the key is released the way it was pressed, even once the layer is gone */
+    #[test]
+    fn released_as_pressed() {
+        let mut key_processor = processor(&[
+            (Layer::BASE, A, HidKeys::A),
+            (Layer(1), A, HidKeys::B),
+            (Layer::BASE, LAYER, HidKeys::LayerMomentary1),
+        ]);
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, LAYER, 0);
+        press(&mut key_processor, &mut sink, A, 10);
+        release(&mut key_processor, &mut sink, LAYER, 20);
+        sink.clear();
+
+        release(&mut key_processor, &mut sink, A, 30);
+        assert_eq!(sink.key_reports(), vec![(0, vec![])]);
+
+        press(&mut key_processor, &mut sink, A, 40);
+        assert_eq!(sink.key_reports().last(), Some(&(0, vec![0x04])));
+    }
+
+    const PLAY: Key = Key { row: 0, col: 1 };
+    const VOLUME: Key = Key { row: 0, col: 2 };
+
+    /* the media keys go to the consumer report only */
+    #[test]
+    fn media_key_press_and_release() {
+        let mut key_processor = processor(&[(Layer::BASE, PLAY, HidKeys::MediaPlayPause)]);
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, PLAY, 0);
+        release(&mut key_processor, &mut sink, PLAY, 50);
+
+        let play = HidConsumer::PlayPause as u16;
+        assert_eq!(sink.consumer_usages(), vec![[play, 0], [0, 0]]);
+        assert!(sink.key_reports().is_empty());
+    }
+
+    /* two media keys held together take both slots */
+    #[test]
+    fn two_media_keys_held() {
+        let mut key_processor = processor(&[
+            (Layer::BASE, PLAY, HidKeys::MediaPlayPause),
+            (Layer::BASE, VOLUME, HidKeys::MediaVolumeUp),
+        ]);
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, PLAY, 0);
+        press(&mut key_processor, &mut sink, VOLUME, 10);
+        release(&mut key_processor, &mut sink, PLAY, 20);
+        release(&mut key_processor, &mut sink, VOLUME, 30);
+
+        let play = HidConsumer::PlayPause as u16;
+        let volume = HidConsumer::VolumeIncrement as u16;
+        assert_eq!(
+            sink.consumer_usages(),
+            vec![[play, 0], [play, volume], [0, volume], [0, 0]]
+        );
+        assert!(sink.key_reports().is_empty());
+    }
+}
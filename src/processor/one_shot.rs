@@ -0,0 +1,305 @@
+use crate::config::config::*;
+use crate::config::enums::{HidKeys, OneShot};
+use crate::config::layers::Layer;
+use crate::hid::HidSink;
+use crate::processor::KeyProcessor;
+
+use embassy_time::Instant;
+
+/* the one shot keys tapped and not used yet, and the ones locked by a double tap */
+pub struct OneShotState {
+    /* armed by a tap, applied to the next key */
+    pub modifiers: u8,
+    pub layer: Option<Layer>,
+    /* the armed modifiers the next key took, released with that key */
+    pub applied_modifiers: u8,
+    pub applied_to: Option<HidKeys>,
+    /* locked by a double tap, till the next tap */
+    pub locked_modifiers: u8,
+    pub locked_layer: Option<Layer>,
+    /* the last one shot key tapped, for the double tap */
+    pub last_tap: Option<(HidKeys, Instant)>,
+    /* the last time a one shot key was armed */
+    pub armed_time: Instant,
+}
+
+impl OneShotState {
+    pub fn new() -> Self {
+        OneShotState {
+            modifiers: 0,
+            layer: None,
+            applied_modifiers: 0,
+            applied_to: None,
+            locked_modifiers: 0,
+            locked_layer: None,
+            last_tap: None,
+            armed_time: Instant::from_ticks(0),
+        }
+    }
+
+    /* when the armed keys are cancelled */
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.modifiers != 0 || self.layer.is_some() {
+            Some(self.armed_time + ONE_SHOT_TIMEOUT)
+        } else {
+            None
+        }
+    }
+
+    fn double_tap(&self, key: &HidKeys, now: Instant) -> bool {
+        matches!(self.last_tap, Some((last_key, time)) if last_key == *key && now < time + TAPPING_TERM)
+    }
+}
+
+impl KeyProcessor {
+    /* a tap arms the key, a second tap inside the tapping term locks it, the next tap unlocks it */
+    pub(super) fn press_one_shot<H: HidSink>(
+        &mut self,
+        valid_key: &HidKeys,
+        now: Instant,
+        hid: &mut H,
+    ) {
+        let double_tap = self.one_shot.double_tap(valid_key, now);
+        self.one_shot.last_tap = Some((*valid_key, now));
+
+        match OneShot::get_one_shot(valid_key) {
+            Some(OneShot::Modifiers(modifiers)) => {
+                if self.one_shot.locked_modifiers & modifiers == modifiers {
+                    self.one_shot.locked_modifiers &= !modifiers;
+                    self.one_shot.last_tap = None;
+
+                    self.reports.release_modifiers(modifiers);
+                    self.send_key_report(hid);
+                } else if self.one_shot.modifiers & modifiers == modifiers {
+                    /* tapped again, locked or cancelled */
+                    self.one_shot.modifiers &= !modifiers;
+
+                    if double_tap {
+                        self.one_shot.locked_modifiers |= modifiers;
+
+                        self.reports.press_modifiers(modifiers);
+                        self.send_key_report(hid);
+                    }
+                } else {
+                    self.one_shot.modifiers |= modifiers;
+                    self.one_shot.armed_time = now;
+                }
+            }
+            Some(OneShot::Layer(layer)) => {
+                if self.one_shot.locked_layer == Some(layer) {
+                    self.one_shot.locked_layer = None;
+                    self.one_shot.last_tap = None;
+
+                    self.layer_state.deactivate(layer);
+                } else if self.one_shot.layer == Some(layer) {
+                    /* tapped again, locked or cancelled */
+                    self.one_shot.layer = None;
+
+                    if double_tap {
+                        self.one_shot.locked_layer = Some(layer);
+                    } else {
+                        self.layer_state.deactivate(layer);
+                    }
+                } else {
+                    /* another armed layer gives way */
+                    self.release_one_shot_layer();
+
+                    self.one_shot.layer = Some(layer);
+                    self.one_shot.armed_time = now;
+                    self.layer_state.activate(layer);
+                }
+            }
+            None => { /* not a one shot key */ }
+        }
+
+        #[cfg(feature = "debug")]
+        log::info!(
+            "One shot modifiers {:#04x} locked {:#04x}, layer {:?} locked {:?}",
+            self.one_shot.modifiers,
+            self.one_shot.locked_modifiers,
+            self.one_shot.layer,
+            self.one_shot.locked_layer
+        );
+    }
+
+    /* the next key takes the armed modifiers, they are released with it */
+    pub(super) fn apply_one_shot_modifiers(&mut self, valid_key: &HidKeys) {
+        /* only the key that took them had the modifiers */
+        self.release_one_shot_modifiers();
+
+        /* the modifiers already held are left to their keys */
+        let modifiers = self.one_shot.modifiers & !self.reports.key_report.modifiers;
+        self.one_shot.modifiers = 0;
+
+        if modifiers != 0 {
+            self.reports.press_modifiers(modifiers);
+            self.record_modifiers(modifiers, true);
+            self.one_shot.applied_modifiers = modifiers;
+            self.one_shot.applied_to = Some(*valid_key);
+        }
+    }
+
+    pub(super) fn release_one_shot_modifiers(&mut self) {
+        if self.one_shot.applied_modifiers != 0 {
+            self.reports
+                .release_modifiers(self.one_shot.applied_modifiers);
+            self.record_modifiers(self.one_shot.applied_modifiers, false);
+            self.one_shot.applied_modifiers = 0;
+            self.one_shot.applied_to = None;
+        }
+    }
+
+    /* the armed layer was used by the next key */
+    pub(super) fn release_one_shot_layer(&mut self) {
+        if let Some(layer) = self.one_shot.layer.take() {
+            if self.one_shot.locked_layer != Some(layer) {
+                self.layer_state.deactivate(layer);
+            }
+        }
+    }
+
+    /* the armed keys not used before the timeout are cancelled */
+    pub(super) fn tick_one_shot(&mut self, now: Instant) {
+        if let Some(deadline) = self.one_shot.deadline() {
+            if now >= deadline {
+                self.one_shot.modifiers = 0;
+                self.release_one_shot_layer();
+
+                #[cfg(feature = "debug")]
+                log::info!("One shot keys cancelled");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::enums::KeyType;
+    use crate::matrix::Key;
+    use crate::processor::KeyProcessor;
+    use crate::testing::*;
+
+    const A: Key = Key { row: 1, col: 0 };
+    const ONE_SHOT: Key = Key { row: 3, col: 0 };
+
+    fn one_shot_processor(one_shot: HidKeys) -> KeyProcessor {
+        processor(&[
+            (Layer::BASE, A, HidKeys::A),
+            (Layer(1), A, HidKeys::B),
+            (Layer::BASE, ONE_SHOT, one_shot),
+        ])
+    }
+
+    /* a tap arms the modifier for the next key only */
+    #[test]
+    fn tapped() {
+        let mut key_processor = one_shot_processor(HidKeys::OneShotShift);
+        let mut sink = RecordingSink::default();
+
+        tap(&mut key_processor, &mut sink, ONE_SHOT, 0);
+        assert!(sink.events.is_empty());
+
+        tap(&mut key_processor, &mut sink, A, 300);
+        tap(&mut key_processor, &mut sink, A, 400);
+
+        assert_eq!(
+            sink.key_reports(),
+            vec![
+                (0x02, vec![0x04]),
+                (0x00, vec![]),
+                (0x00, vec![0x04]),
+                (0x00, vec![])
+            ]
+        );
+    }
+
+    /* held like a modifier, the key pressed meanwhile takes it */
+    #[test]
+    fn held() {
+        let mut key_processor = one_shot_processor(HidKeys::OneShotShift);
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, ONE_SHOT, 0);
+        tap(&mut key_processor, &mut sink, A, 300);
+        release(&mut key_processor, &mut sink, ONE_SHOT, 400);
+
+        assert_eq!(sink.key_reports(), vec![(0x02, vec![0x04]), (0x00, vec![])]);
+    }
+
+    /* a double tap locks the modifier till the next tap */
+    #[test]
+    fn double_tap_lock() {
+        let mut key_processor = one_shot_processor(HidKeys::OneShotShift);
+        let mut sink = RecordingSink::default();
+
+        tap(&mut key_processor, &mut sink, ONE_SHOT, 0);
+        tap(&mut key_processor, &mut sink, ONE_SHOT, 50);
+        tap(&mut key_processor, &mut sink, A, 300);
+        tap(&mut key_processor, &mut sink, A, 400);
+        tap(&mut key_processor, &mut sink, ONE_SHOT, 1000);
+
+        assert_eq!(
+            sink.key_reports(),
+            vec![
+                (0x02, vec![]),
+                (0x02, vec![0x04]),
+                (0x02, vec![]),
+                (0x02, vec![0x04]),
+                (0x02, vec![]),
+                (0x00, vec![])
+            ]
+        );
+    }
+
+    /* the armed keys not used by then are cancelled */
+    #[test]
+    fn timeout() {
+        let mut key_processor = one_shot_processor(HidKeys::OneShotShift);
+        let mut sink = RecordingSink::default();
+
+        tap(&mut key_processor, &mut sink, ONE_SHOT, 0);
+        assert_eq!(key_processor.next_deadline(), Some(t(0) + ONE_SHOT_TIMEOUT));
+
+        let timeout = ONE_SHOT_TIMEOUT.as_millis();
+        run_until(&mut key_processor, &mut sink, timeout);
+        tap(&mut key_processor, &mut sink, A, timeout + 10);
+
+        assert_eq!(sink.key_reports(), vec![(0, vec![0x04]), (0, vec![])]);
+    }
+
+    /* the layer is active for the next key only */
+    #[test]
+    fn layer() {
+        let mut key_processor = one_shot_processor(HidKeys::OneShotLayer1);
+        let mut sink = RecordingSink::default();
+
+        tap(&mut key_processor, &mut sink, ONE_SHOT, 0);
+        tap(&mut key_processor, &mut sink, A, 300);
+        tap(&mut key_processor, &mut sink, A, 400);
+
+        assert_eq!(
+            sink.key_reports(),
+            vec![(0, vec![0x05]), (0, vec![]), (0, vec![0x04]), (0, vec![])]
+        );
+    }
+
+    /* the one shot layer keys above the configured layers do nothing */
+    #[test]
+    fn layer_above_layers() {
+        let mut key_processor = one_shot_processor(HidKeys::OneShotLayer7);
+        let mut sink = RecordingSink::default();
+
+        assert!(matches!(
+            KeyType::check_type(&HidKeys::OneShotLayer7),
+            KeyType::Unused
+        ));
+
+        tap(&mut key_processor, &mut sink, ONE_SHOT, 0);
+        assert!(sink.events.is_empty());
+        assert_eq!(key_processor.next_deadline(), None);
+
+        tap(&mut key_processor, &mut sink, A, 300);
+        assert_eq!(sink.key_reports(), vec![(0, vec![0x04]), (0, vec![])]);
+    }
+}
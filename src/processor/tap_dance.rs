@@ -0,0 +1,287 @@
+use crate::config::config::*;
+use crate::config::enums::HidKeys;
+use crate::config::layers::{TapDance, TapDances};
+use crate::hid::HidSink;
+use crate::matrix::Key;
+use crate::processor::KeyProcessor;
+
+use embassy_time::Instant;
+
+/* a tap dance key counting its taps */
+#[derive(Clone, Copy, Debug)]
+pub struct PendingTapDance {
+    pub key: Key,
+    pub index: usize,
+    pub count: usize,
+    pub pressed: bool,
+    /* the last press or release */
+    pub time: Instant,
+}
+
+pub struct TapDanceState {
+    pub tap_dances: TapDances,
+    pub pending: Option<PendingTapDance>,
+}
+
+impl TapDanceState {
+    pub fn new(tap_dances: TapDances) -> Self {
+        TapDanceState {
+            tap_dances,
+            pending: None,
+        }
+    }
+
+    /* when the pending key is decided, held past the tapping term or not tapped again */
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.map(|pending| pending.time + TAPPING_TERM)
+    }
+}
+
+impl KeyProcessor {
+    pub(super) fn tap_dance_pressed<H: HidSink>(
+        &mut self,
+        key: &Key,
+        valid_key: &HidKeys,
+        now: Instant,
+        hid: &mut H,
+    ) {
+        let Some(index) = TapDance::get_index(valid_key) else {
+            return;
+        };
+
+        match self.tap_dance.pending.as_mut() {
+            /* tapped again inside the tapping term */
+            Some(pending) if pending.key == *key && pending.index == index => {
+                pending.count += 1;
+                pending.pressed = true;
+                pending.time = now;
+            }
+            _ => {
+                /* another tap dance key decides the pending one */
+                self.resolve_tap_dance(hid);
+
+                self.tap_dance.pending = Some(PendingTapDance {
+                    key: *key,
+                    index,
+                    count: 1,
+                    pressed: true,
+                    time: now,
+                });
+            }
+        }
+    }
+
+    pub(super) fn tap_dance_released<H: HidSink>(&mut self, key: &Key, now: Instant, hid: &mut H) {
+        let Some(pending) = self.tap_dance.pending.as_mut() else {
+            return;
+        };
+
+        if pending.key != *key {
+            return;
+        }
+
+        pending.pressed = false;
+        pending.time = now;
+
+        /* the last tap count, no further tap to wait for */
+        if pending.count >= self.tap_dance.tap_dances[pending.index].tap_count() {
+            self.resolve_tap_dance(hid);
+        }
+    }
+
+    /* another key is pressed, the pending key is decided before the other key is looked up on the layers */
+    pub(super) fn tap_dance_interrupted<H: HidSink>(&mut self, key: &Key, hid: &mut H) {
+        if self
+            .tap_dance
+            .pending
+            .is_some_and(|pending| pending.key != *key)
+        {
+            self.resolve_tap_dance(hid);
+        }
+    }
+
+    /* run the time based decision */
+    pub(super) fn tick_tap_dance<H: HidSink>(&mut self, now: Instant, hid: &mut H) {
+        if let Some(deadline) = self.tap_dance.deadline() {
+            if now >= deadline {
+                self.resolve_tap_dance(hid);
+            }
+        }
+    }
+
+    /*
+    a released key sends the key of its tap count, a held key holds it till the release,
+    or the hold key when it is held on the first tap
+    */
+    pub(super) fn resolve_tap_dance<H: HidSink>(&mut self, hid: &mut H) {
+        if let Some(pending) = self.tap_dance.pending.take() {
+            let tap_dance = self.tap_dance.tap_dances[pending.index];
+            let tap = tap_dance.tap(pending.count);
+
+            if pending.pressed {
+                let action = if pending.count == 1 && tap_dance.hold != HidKeys::None {
+                    tap_dance.hold
+                } else {
+                    tap
+                };
+
+                /* the key is released as the decided key */
+                if let Some(resolved) = self.keys_resolved.get_mut(&pending.key) {
+                    *resolved = Some(action);
+                }
+
+                self.press_key(&action, pending.time, hid);
+            } else {
+                self.press_key(&tap, pending.time, hid);
+                self.release_key(&tap, hid);
+            }
+
+            #[cfg(feature = "debug")]
+            log::info!(
+                "Tap dance {} decided after {} taps, held: {}",
+                pending.index,
+                pending.count,
+                pending.pressed
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::layers::Layer;
+    use crate::testing::*;
+
+    const DANCE: Key = Key { row: 1, col: 0 };
+    const X: Key = Key { row: 1, col: 1 };
+
+    /* a, b and c on one, two and three taps, control when held on the first tap */
+    fn tap_dance_processor() -> KeyProcessor {
+        let mut key_processor = processor(&[
+            (Layer::BASE, DANCE, HidKeys::TapDance0),
+            (Layer::BASE, X, HidKeys::X),
+        ]);
+
+        key_processor.tap_dances_mut()[0] = TapDance::new(
+            &[HidKeys::A, HidKeys::B, HidKeys::C],
+            HidKeys::ModifierControl,
+        )
+        .unwrap();
+
+        key_processor
+    }
+
+    #[test]
+    fn tap_counts() {
+        let term = TAPPING_TERM.as_millis();
+
+        for (taps, usage) in [(1, 0x04), (2, 0x05)] {
+            let mut key_processor = tap_dance_processor();
+            let mut sink = RecordingSink::default();
+
+            for tap_index in 0..taps {
+                tap(&mut key_processor, &mut sink, DANCE, tap_index * 100);
+            }
+
+            /* decided once the tapping term passed after the last release */
+            let released = (taps - 1) * 100 + 10;
+            run_until(&mut key_processor, &mut sink, released + term - 1);
+            assert!(sink.events.is_empty(), "{} taps", taps);
+
+            run_until(&mut key_processor, &mut sink, released + term);
+            assert_eq!(
+                sink.key_reports(),
+                vec![(0, vec![usage]), (0, vec![])],
+                "{} taps",
+                taps
+            );
+        }
+    }
+
+    /* the last tap count is sent at the release, there is nothing left to wait for */
+    #[test]
+    fn last_tap_count() {
+        let mut key_processor = tap_dance_processor();
+        let mut sink = RecordingSink::default();
+
+        tap(&mut key_processor, &mut sink, DANCE, 0);
+        tap(&mut key_processor, &mut sink, DANCE, 50);
+        tap(&mut key_processor, &mut sink, DANCE, 100);
+
+        assert_eq!(sink.key_reports(), vec![(0, vec![0x06]), (0, vec![])]);
+        assert_eq!(key_processor.next_deadline(), None);
+    }
+
+    /* held past the tapping term on the first tap: the hold key, on a later tap: the key of that tap */
+    #[test]
+    fn held() {
+        let term = TAPPING_TERM.as_millis();
+
+        let mut key_processor = tap_dance_processor();
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, DANCE, 0);
+        run_until(&mut key_processor, &mut sink, term);
+        release(&mut key_processor, &mut sink, DANCE, 500);
+        assert_eq!(sink.key_reports(), vec![(0x01, vec![]), (0x00, vec![])]);
+
+        let mut key_processor = tap_dance_processor();
+        let mut sink = RecordingSink::default();
+
+        tap(&mut key_processor, &mut sink, DANCE, 0);
+        press(&mut key_processor, &mut sink, DANCE, 100);
+        run_until(&mut key_processor, &mut sink, 100 + term);
+        release(&mut key_processor, &mut sink, DANCE, 500);
+        assert_eq!(sink.key_reports(), vec![(0, vec![0x05]), (0, vec![])]);
+    }
+
+    /* another key decides the tap dance before it is sent */
+    #[test]
+    fn interrupted() {
+        let mut key_processor = tap_dance_processor();
+        let mut sink = RecordingSink::default();
+
+        tap(&mut key_processor, &mut sink, DANCE, 0);
+        tap(&mut key_processor, &mut sink, X, 50);
+
+        assert_eq!(
+            sink.key_reports(),
+            vec![(0, vec![0x04]), (0, vec![]), (0, vec![0x1B]), (0, vec![])]
+        );
+
+        /* held on the first tap, the hold key is applied to the other key */
+        let mut key_processor = tap_dance_processor();
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, DANCE, 0);
+        tap(&mut key_processor, &mut sink, X, 50);
+        release(&mut key_processor, &mut sink, DANCE, 100);
+
+        assert_eq!(
+            sink.key_reports(),
+            vec![
+                (0x01, vec![]),
+                (0x01, vec![0x1B]),
+                (0x01, vec![]),
+                (0x00, vec![])
+            ]
+        );
+    }
+
+    /* what the tap_dances! macro checks when compiled */
+    #[test]
+    fn tap_dance_limits() {
+        let taps = [HidKeys::A; TAP_DANCE_TAPS + 1];
+
+        assert!(TapDance::new(&taps[..TAP_DANCE_TAPS], HidKeys::None).is_some());
+        assert_eq!(TapDance::new(&taps, HidKeys::None), None);
+
+        assert_eq!(TapDance::get_index(&HidKeys::TapDance0), Some(0));
+        assert_eq!(
+            TapDance::get_index(&HidKeys::TapDance7),
+            Some(TAP_DANCE_KEYS - 1)
+        );
+        assert_eq!(TapDance::get_index(&HidKeys::A), None);
+    }
+}
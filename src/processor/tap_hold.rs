@@ -0,0 +1,204 @@
+use crate::config::config::*;
+use crate::config::layers::TapHold;
+use crate::hid::HidSink;
+use crate::matrix::Key;
+use crate::processor::KeyProcessor;
+
+use embassy_time::Instant;
+use heapless::Vec;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PendingTapHold {
+    pub key: Key,
+    pub tap_hold: TapHold,
+    pub pressed_time: Instant,
+}
+
+/* a key event that happened while the tap hold key was undecided */
+#[derive(Clone, Copy, Debug)]
+pub struct BufferedEvent {
+    pub key: Key,
+    pub pressed: bool,
+    pub time: Instant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    Tap,
+    Hold,
+}
+
+pub struct TapHoldState {
+    pub pending: Option<PendingTapHold>,
+    pub buffered: Vec<BufferedEvent, TAP_HOLD_BUFFER_SIZE>,
+}
+
+impl TapHoldState {
+    pub fn new() -> Self {
+        TapHoldState {
+            pending: None,
+            buffered: Vec::new(),
+        }
+    }
+
+    pub fn start(&mut self, key: &Key, tap_hold: TapHold, now: Instant) {
+        self.pending = Some(PendingTapHold {
+            key: *key,
+            tap_hold,
+            pressed_time: now,
+        });
+    }
+
+    /* returns false when the buffer is full and the tap hold key has to be decided */
+    pub fn buffer(&mut self, key: &Key, pressed: bool, now: Instant) -> bool {
+        self.buffered
+            .push(BufferedEvent {
+                key: *key,
+                pressed,
+                time: now,
+            })
+            .is_ok()
+    }
+
+    /* the key was pressed while the tap hold key was undecided */
+    pub fn is_buffered(&self, key: &Key) -> bool {
+        self.buffered
+            .iter()
+            .any(|event| event.key == *key && event.pressed)
+    }
+}
+
+impl KeyProcessor {
+    pub(super) fn resolve_tap_hold<H: HidSink>(
+        &mut self,
+        decision: Decision,
+        now: Instant,
+        hid: &mut H,
+    ) {
+        if let Some(pending) = self.tap_hold.pending.take() {
+            match decision {
+                Decision::Tap => {
+                    /* the tap key is pressed and released right away */
+                    if let Some(resolved) = self.keys_resolved.get_mut(&pending.key) {
+                        *resolved = None;
+                    }
+
+                    self.press_key(&pending.tap_hold.tap, now, hid);
+                    self.release_key(&pending.tap_hold.tap, hid);
+                }
+                Decision::Hold => {
+                    /* the key is released as the hold key */
+                    if let Some(resolved) = self.keys_resolved.get_mut(&pending.key) {
+                        *resolved = Some(pending.tap_hold.hold);
+                    }
+
+                    self.press_key(&pending.tap_hold.hold, now, hid);
+                }
+            }
+
+            #[cfg(feature = "debug")]
+            log::info!("Tap hold {:?} decided: {:?}", pending.tap_hold, decision);
+
+            /* replay the keys that waited for the decision */
+            let buffered = core::mem::take(&mut self.tap_hold.buffered);
+            for event in buffered.iter() {
+                if event.pressed {
+                    self.tap_hold_pressed(&event.key, event.time, hid);
+                } else {
+                    self.tap_hold_released(&event.key, event.time, hid);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::config::TAPPING_TERM;
+    use crate::config::enums::HidKeys;
+    use crate::config::layers::{Layer, TapHold};
+    use crate::matrix::Key;
+    use crate::processor::KeyProcessor;
+    use crate::testing::*;
+
+    const TAP_HOLD: Key = Key { row: 1, col: 0 };
+    const B: Key = Key { row: 1, col: 1 };
+    const TERM: u64 = TAPPING_TERM.as_millis();
+
+    /* A when tapped, control when held */
+    fn tap_hold_processor() -> KeyProcessor {
+        let mut key_processor = processor(&[
+            (Layer::BASE, TAP_HOLD, HidKeys::TapHold0),
+            (Layer::BASE, B, HidKeys::B),
+        ]);
+        key_processor.layers_mut().tap_holds[0] =
+            TapHold::new(HidKeys::A, HidKeys::ModifierControl);
+
+        key_processor
+    }
+
+    #[test]
+    fn tap_inside_the_term() {
+        let mut key_processor = tap_hold_processor();
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, TAP_HOLD, 0);
+        assert!(sink.events.is_empty());
+        assert_eq!(key_processor.next_deadline(), Some(t(TERM)));
+
+        release(&mut key_processor, &mut sink, TAP_HOLD, TERM - 1);
+        assert_eq!(sink.key_reports(), vec![(0, vec![0x04]), (0, vec![])]);
+        assert_eq!(key_processor.next_deadline(), None);
+    }
+
+    #[test]
+    fn hold_past_the_term() {
+        let mut key_processor = tap_hold_processor();
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, TAP_HOLD, 0);
+        run_until(&mut key_processor, &mut sink, TERM - 1);
+        assert!(sink.events.is_empty());
+
+        run_until(&mut key_processor, &mut sink, TERM);
+        assert_eq!(sink.key_reports(), vec![(0x01, vec![])]);
+
+        release(&mut key_processor, &mut sink, TAP_HOLD, TERM + 100);
+        assert_eq!(sink.key_reports().last(), Some(&(0, vec![])));
+    }
+
+    /* a key pressed and released inside the term waits for the decision, a tap sends it after the tap key (PERMISSIVE_HOLD off) */
+    #[test]
+    fn interrupted_and_tapped() {
+        let mut key_processor = tap_hold_processor();
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, TAP_HOLD, 0);
+        press(&mut key_processor, &mut sink, B, 20);
+        release(&mut key_processor, &mut sink, B, 40);
+        assert!(sink.events.is_empty());
+
+        release(&mut key_processor, &mut sink, TAP_HOLD, 60);
+        assert_eq!(
+            sink.key_reports(),
+            vec![(0, vec![0x04]), (0, vec![]), (0, vec![0x05]), (0, vec![])]
+        );
+    }
+
+    /* the term passes with the other key held, it is sent with the hold key */
+    #[test]
+    fn interrupted_and_held() {
+        let mut key_processor = tap_hold_processor();
+        let mut sink = RecordingSink::default();
+
+        press(&mut key_processor, &mut sink, TAP_HOLD, 0);
+        press(&mut key_processor, &mut sink, B, 20);
+        run_until(&mut key_processor, &mut sink, TERM);
+
+        assert_eq!(sink.key_reports(), vec![(0x01, vec![]), (0x01, vec![0x05])]);
+
+        release(&mut key_processor, &mut sink, B, TERM + 10);
+        release(&mut key_processor, &mut sink, TAP_HOLD, TERM + 20);
+        assert_eq!(sink.key_reports()[2..], [(0x01, vec![]), (0, vec![])]);
+    }
+}
@@ -0,0 +1,286 @@
+/*
+The processing task: the only owner of the debounce, the keymap and the reports.
+
+It waits for a matrix event, a host request or the next time based decision, whichever comes first.
+The matrix events are applied in the order they are received, and the reports of one event
+are sent on the hid channel in the order they were built, before the next event is taken.
+*/
+
+use crate::config::config::{KeyDebouncer, DYNAMIC_MACROS_SAVED};
+use crate::config::layers::Layers;
+use crate::debounce::Debouncer;
+use crate::events::*;
+use crate::hid::HidSink;
+use crate::matrix::MatrixEvent;
+use crate::processor::KeyProcessor;
+use crate::storage::{load_dynamic_macros, save_dynamic_macros, KeymapStore};
+use crate::via::Via;
+
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::signal::Signal;
+use embassy_time::{Instant, Timer};
+
+/* wait for the deadline, forever if there is none */
+async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => Timer::at(deadline).await,
+        None => core::future::pending().await,
+    }
+}
+
+/* send the reports of one event, so the buffer only has to hold the reports of a single event */
+async fn send_events(events: &mut HidEvents, hid_events: &HidChannel) {
+    while let Some(event) = events.pop() {
+        hid_events.send(event).await;
+    }
+}
+
+/* the keys of this half go through the debounce, the keys of the other half were debounced there */
+fn apply_matrix_event<D: Debouncer, H: HidSink>(
+    event: &MatrixEvent,
+    debouncer: &mut D,
+    key_processor: &mut KeyProcessor,
+    hid: &mut H,
+) {
+    /* the decisions due before the event come first */
+    key_processor.tick(event.time, hid);
+
+    if event.debounced {
+        key_processor.process_event(event, hid);
+    } else if let Some(event) = debouncer.update(event) {
+        key_processor.process_event(&event, hid);
+    }
+}
+
+fn handle_host_request<S: KeymapStore>(
+    request: HostRequest,
+    key_processor: &mut KeyProcessor,
+    via: &mut Via,
+    store: &mut S,
+    events: &mut HidEvents,
+) {
+    match request {
+        HostRequest::Keymap(command) => {
+            /* the held keys are released the way they were pressed */
+            if let Err(_error) = command.apply(key_processor.layers_mut(), store) {
+                #[cfg(feature = "debug")]
+                log::info!("Keymap command {:?} failed: {:?}", command, _error);
+            }
+        }
+        HostRequest::Via(request) => {
+            let response =
+                via.handle_request(&request, key_processor.layers_mut(), store, Instant::now());
+            events.push(HidEvent::Raw(response));
+        }
+        HostRequest::BootProtocol(boot_protocol) => {
+            /* fall back to the six key report while the host uses the boot protocol */
+            key_processor.set_boot_protocol(boot_protocol, events);
+        }
+    }
+}
+
+pub async fn process_keys<S: KeymapStore>(
+    mut store: S,
+    matrix_events: &MatrixChannel,
+    host_requests: &HostRequestChannel,
+    hid_events: &HidChannel,
+    deep_sleep: &Signal<RawMutex, ()>,
+) -> ! {
+    /* load the stored keymap, or the specified layout */
+    let mut layers = Layers::new();
+    layers.load_layout(&mut store);
+
+    let mut key_processor = KeyProcessor::new(layers);
+
+    /* the slots recorded before the last boot */
+    if DYNAMIC_MACROS_SAVED {
+        if let Some(dynamic_macros) = load_dynamic_macros(&mut store) {
+            *key_processor.dynamic_macros_mut() = dynamic_macros;
+        }
+    }
+
+    /* the VIA configuration, with the macros saved by VIA */
+    let mut via = Via::load(&mut store);
+
+    let mut debouncer = KeyDebouncer::default();
+    let mut events = HidEvents::new();
+
+    loop {
+        /* the next settled debounce change, the next tap hold decision or the VIA save */
+        let deadline = [
+            debouncer.next_deadline(),
+            key_processor.next_deadline(),
+            via.deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+
+        match select3(
+            matrix_events.receive(),
+            host_requests.receive(),
+            wait_until(deadline),
+        )
+        .await
+        {
+            Either3::First(event) => {
+                apply_matrix_event(&event, &mut debouncer, &mut key_processor, &mut events);
+                send_events(&mut events, hid_events).await;
+            }
+            Either3::Second(request) => {
+                handle_host_request(
+                    request,
+                    &mut key_processor,
+                    &mut via,
+                    &mut store,
+                    &mut events,
+                );
+            }
+            Either3::Third(()) => { /* a deadline passed */ }
+        }
+
+        let now = Instant::now();
+
+        /* a chord released at once settles in one go, every key has its own reports */
+        for event in debouncer.expired(now) {
+            key_processor.process_event(&event, &mut events);
+            send_events(&mut events, hid_events).await;
+        }
+
+        key_processor.tick(now, &mut events);
+
+        /* the keymap changed by VIA is saved once the changes stop */
+        via.tick(key_processor.layers_mut(), &mut store, now);
+
+        /* the profile keys are applied by the ble task */
+        if let Some((action, profile)) = key_processor.take_profile_action() {
+            events.push(HidEvent::Profile(action, profile));
+        }
+
+        /* a recording was stopped, the slots are saved right away */
+        if key_processor.take_recorded_macro() && DYNAMIC_MACROS_SAVED {
+            if let Err(_error) = save_dynamic_macros(&mut store, key_processor.dynamic_macros()) {
+                #[cfg(feature = "debug")]
+                log::info!("Error saving the dynamic macros: {:?}", _error);
+            }
+        }
+
+        /* the scanner sleeps once every key is released */
+        if key_processor.take_deep_sleep_request() {
+            deep_sleep.signal(());
+        }
+
+        send_events(&mut events, hid_events).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config::{COLS, DEBOUNCE_DELAY};
+    use crate::config::enums::HidKeys;
+    use crate::config::layers::Layer;
+    use crate::matrix::Key;
+    use crate::testing::*;
+
+    const A: Key = Key { row: 1, col: 0 };
+    const B: Key = Key {
+        row: 1,
+        col: COLS as i8,
+    };
+    const C: Key = Key {
+        row: 2,
+        col: COLS as i8,
+    };
+
+    /* the processing task: the events at their time, then the settled changes and the decisions due */
+    struct Task {
+        debouncer: KeyDebouncer,
+        key_processor: KeyProcessor,
+        sink: RecordingSink,
+    }
+
+    impl Task {
+        fn new() -> Self {
+            Task {
+                debouncer: KeyDebouncer::default(),
+                key_processor: processor(&[
+                    (Layer::BASE, A, HidKeys::A),
+                    (Layer::BASE, B, HidKeys::B),
+                    (Layer::BASE, C, HidKeys::C),
+                ]),
+                sink: RecordingSink::default(),
+            }
+        }
+
+        fn receive(&mut self, event: MatrixEvent) {
+            let now = event.time;
+            apply_matrix_event(
+                &event,
+                &mut self.debouncer,
+                &mut self.key_processor,
+                &mut self.sink,
+            );
+            self.run_until(now);
+        }
+
+        fn run_until(&mut self, now: Instant) {
+            for event in self.debouncer.expired(now) {
+                self.key_processor.process_event(&event, &mut self.sink);
+            }
+            self.key_processor.tick(now, &mut self.sink);
+        }
+    }
+
+    /* a remote tap shorter than the debounce delay is sent as it is received */
+    #[test]
+    fn remote_keys_skip_the_debounce() {
+        let mut task = Task::new();
+
+        task.receive(MatrixEvent::debounced(B, true, t(0)));
+        task.receive(MatrixEvent::debounced(B, false, t(5)));
+
+        assert_eq!(task.sink.key_reports(), vec![(0, vec![0x05]), (0, vec![])]);
+    }
+
+    /* the local release waits for the debounce, the remote keys around it do not */
+    #[test]
+    fn local_and_remote_interleaved() {
+        let mut task = Task::new();
+
+        task.receive(MatrixEvent::new(A, true, t(0)));
+        task.receive(MatrixEvent::debounced(B, true, t(10)));
+        task.receive(MatrixEvent::new(A, false, t(20)));
+        task.receive(MatrixEvent::debounced(B, false, t(30)));
+
+        assert_eq!(
+            task.sink.key_reports(),
+            vec![(0, vec![0x04]), (0, vec![0x04, 0x05]), (0, vec![0x04])]
+        );
+
+        task.run_until(t(20) + DEBOUNCE_DELAY);
+        assert_eq!(task.sink.key_reports().last(), Some(&(0, vec![])));
+        assert_eq!(task.sink.key_reports().len(), 4);
+    }
+
+    /* a burst of remote events received at once keeps its order */
+    #[test]
+    fn remote_burst_in_order() {
+        let mut task = Task::new();
+
+        task.receive(MatrixEvent::debounced(B, true, t(0)));
+        task.receive(MatrixEvent::debounced(C, true, t(0)));
+        task.receive(MatrixEvent::debounced(B, false, t(0)));
+        task.receive(MatrixEvent::debounced(C, false, t(0)));
+
+        assert_eq!(
+            task.sink.key_reports(),
+            vec![
+                (0, vec![0x05]),
+                (0, vec![0x05, 0x06]),
+                (0, vec![0x06]),
+                (0, vec![])
+            ]
+        );
+    }
+}
@@ -0,0 +1,272 @@
+/*
+Host profiles, every profile is bonded to one host and advertised with its own address.
+
+Binary format of the profiles stored in the NVS.
+
+ 4 bytes | byte 4  | byte 5   | byte 6        | 7 bytes per profile          | 2 bytes
+ MAGIC   | FORMAT_ | selected | profile count | bonded | host address (LE)   | CRC-16 of all
+         | VERSION | profile  |               |        |                     | previous bytes (LE)
+*/
+
+use crate::config::config::HOST_PROFILES;
+use crate::hid::BleStatus;
+use crate::storage::{keymap::crc16, keymap::KeymapError, KeymapStore, StoreEntry};
+
+pub const MAGIC: [u8; 4] = *b"RBHP";
+pub const FORMAT_VERSION: u8 = 0x01;
+pub const ADDRESS_SIZE: usize = 6;
+const HEADER_SIZE: usize = 7;
+const PROFILE_ENTRY_SIZE: usize = 1 + ADDRESS_SIZE;
+const CRC_SIZE: usize = 2;
+pub const PROFILES_SIZE: usize = HEADER_SIZE + HOST_PROFILES * PROFILE_ENTRY_SIZE + CRC_SIZE;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Profile {
+    /* the identity address of the bonded host, None until a host pairs */
+    pub host: Option<[u8; ADDRESS_SIZE]>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HostProfiles {
+    selected: u8,
+    profiles: [Profile; HOST_PROFILES],
+    /* a new host may bond to the selected profile */
+    pairing: bool,
+}
+
+impl Default for HostProfiles {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HostProfiles {
+    pub fn new() -> Self {
+        HostProfiles {
+            selected: 0,
+            profiles: [Profile::default(); HOST_PROFILES],
+            pairing: false,
+        }
+    }
+
+    /* load the stored profiles, or start with empty profiles */
+    pub fn load<S: KeymapStore>(store: &mut S) -> Self {
+        let mut buffer = [0; PROFILES_SIZE];
+
+        store
+            .read(StoreEntry::Profiles, &mut buffer)
+            .and_then(|data| {
+                decode(data)
+                    .inspect_err(|_error| {
+                        #[cfg(feature = "debug")]
+                        log::info!("Stored profiles rejected: {:?}", _error);
+                    })
+                    .ok()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn save<S: KeymapStore>(&self, store: &mut S) {
+        let mut buffer = [0; PROFILES_SIZE];
+        encode(self, &mut buffer);
+
+        if let Err(_error) = store.write(StoreEntry::Profiles, &buffer) {
+            #[cfg(feature = "debug")]
+            log::info!("Error saving the profiles: {:?}", _error);
+        }
+    }
+
+    pub fn selected(&self) -> u8 {
+        self.selected
+    }
+
+    pub fn profile(&self, profile: u8) -> Option<&Profile> {
+        self.profiles.get(profile as usize)
+    }
+
+    pub fn pairing(&self) -> bool {
+        self.pairing
+    }
+
+    pub fn status(&self, connected: bool) -> BleStatus {
+        match (connected, self.pairing) {
+            (true, _) => BleStatus::Connected(self.selected),
+            (false, true) => BleStatus::Pairing(self.selected),
+            (false, false) => BleStatus::Advertising(self.selected),
+        }
+    }
+
+    /* returns true when the selected profile changed, pairing ends with the switch */
+    pub fn select(&mut self, profile: u8) -> bool {
+        if profile as usize >= HOST_PROFILES || profile == self.selected {
+            return false;
+        }
+
+        self.selected = profile;
+        self.pairing = false;
+        true
+    }
+
+    /* forget the host of the profile, returns the host whose bond has to be deleted */
+    pub fn clear(&mut self, profile: u8) -> Option<[u8; ADDRESS_SIZE]> {
+        self.profiles.get_mut(profile as usize)?.host.take()
+    }
+
+    pub fn start_pairing(&mut self) {
+        self.pairing = true;
+    }
+
+    /* an empty profile takes any host, a bonded profile only its host unless pairing */
+    pub fn accepts(&self, host: &[u8; ADDRESS_SIZE]) -> bool {
+        match self.profiles[self.selected as usize].host {
+            Some(bonded) => self.pairing || bonded == *host,
+            None => true,
+        }
+    }
+
+    /* bond the host to the selected profile, returns the replaced host whose bond has to be deleted */
+    pub fn bond(&mut self, host: [u8; ADDRESS_SIZE]) -> Option<[u8; ADDRESS_SIZE]> {
+        self.pairing = false;
+
+        let replaced = self.profiles[self.selected as usize].host.replace(host);
+        replaced.filter(|replaced| *replaced != host)
+    }
+}
+
+/* the random static address the profile is advertised with, derived from the base address */
+pub fn profile_address(base: [u8; ADDRESS_SIZE], profile: u8) -> [u8; ADDRESS_SIZE] {
+    let mut address = base;
+    address[0] = address[0].wrapping_add(profile);
+
+    /* the two most significant bits of a random static address are set */
+    address[ADDRESS_SIZE - 1] |= 0xC0;
+    address
+}
+
+pub fn encode(profiles: &HostProfiles, buffer: &mut [u8; PROFILES_SIZE]) {
+    buffer[0..4].copy_from_slice(&MAGIC);
+    buffer[4] = FORMAT_VERSION;
+    buffer[5] = profiles.selected;
+    buffer[6] = HOST_PROFILES as u8;
+
+    for (index, profile) in profiles.profiles.iter().enumerate() {
+        let entry = HEADER_SIZE + index * PROFILE_ENTRY_SIZE;
+        buffer[entry] = profile.host.is_some() as u8;
+        buffer[entry + 1..entry + PROFILE_ENTRY_SIZE]
+            .copy_from_slice(&profile.host.unwrap_or_default());
+    }
+
+    let crc = crc16(&buffer[..PROFILES_SIZE - CRC_SIZE]);
+    buffer[PROFILES_SIZE - CRC_SIZE..].copy_from_slice(&crc.to_le_bytes());
+}
+
+pub fn decode(data: &[u8]) -> Result<HostProfiles, KeymapError> {
+    if data.len() < HEADER_SIZE + CRC_SIZE {
+        return Err(KeymapError::DataTooShort);
+    }
+
+    if data[0..4] != MAGIC {
+        return Err(KeymapError::BadMagic);
+    }
+
+    if data[4] != FORMAT_VERSION {
+        return Err(KeymapError::UnsupportedVersion(data[4]));
+    }
+
+    /* profiles saved with another profile count are not reused */
+    if data[6] as usize != HOST_PROFILES || data.len() != PROFILES_SIZE {
+        return Err(KeymapError::LengthMismatch);
+    }
+
+    let crc = u16::from_le_bytes([data[PROFILES_SIZE - 2], data[PROFILES_SIZE - 1]]);
+    if crc != crc16(&data[..PROFILES_SIZE - CRC_SIZE]) {
+        return Err(KeymapError::ChecksumMismatch);
+    }
+
+    let mut profiles = HostProfiles::new();
+    profiles.selected = data[5].min(HOST_PROFILES as u8 - 1);
+
+    for (index, profile) in profiles.profiles.iter_mut().enumerate() {
+        let entry = HEADER_SIZE + index * PROFILE_ENTRY_SIZE;
+
+        if data[entry] != 0 {
+            let mut host = [0; ADDRESS_SIZE];
+            host.copy_from_slice(&data[entry + 1..entry + PROFILE_ENTRY_SIZE]);
+            profile.host = Some(host);
+        }
+    }
+
+    Ok(profiles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MemoryStore;
+
+    const HOST: [u8; ADDRESS_SIZE] = [0x11, 0x22, 0x33, 0x44, 0x55, 0xC6];
+
+    fn bonded_profiles() -> HostProfiles {
+        let mut profiles = HostProfiles::new();
+        profiles.bond(HOST);
+        profiles.select(HOST_PROFILES as u8 - 1);
+
+        profiles
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let profiles = bonded_profiles();
+        let mut buffer = [0; PROFILES_SIZE];
+        encode(&profiles, &mut buffer);
+
+        assert_eq!(decode(&buffer), Ok(profiles));
+        assert_eq!(
+            decode(&buffer).unwrap().profile(0).unwrap().host,
+            Some(HOST)
+        );
+    }
+
+    #[test]
+    fn saved_and_loaded() {
+        let profiles = bonded_profiles();
+        let mut store = MemoryStore::default();
+        profiles.save(&mut store);
+
+        assert_eq!(HostProfiles::load(&mut store), profiles);
+    }
+
+    #[test]
+    fn corrupted_crc_rejected() {
+        let mut buffer = [0; PROFILES_SIZE];
+        encode(&bonded_profiles(), &mut buffer);
+        buffer[HEADER_SIZE + 1] ^= 0xFF;
+
+        assert_eq!(decode(&buffer), Err(KeymapError::ChecksumMismatch));
+    }
+
+    #[test]
+    fn wrong_version_rejected() {
+        let mut buffer = [0; PROFILES_SIZE];
+        encode(&bonded_profiles(), &mut buffer);
+        buffer[4] = FORMAT_VERSION + 1;
+
+        assert_eq!(
+            decode(&buffer),
+            Err(KeymapError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+    }
+
+    /* rejected profiles are replaced by empty ones */
+    #[test]
+    fn rejected_profiles_load_empty() {
+        let mut buffer = [0; PROFILES_SIZE];
+        encode(&bonded_profiles(), &mut buffer);
+        buffer[PROFILES_SIZE - 1] ^= 0xFF;
+
+        let mut store = MemoryStore::default();
+        store.write(StoreEntry::Profiles, &buffer).unwrap();
+
+        assert_eq!(HostProfiles::load(&mut store), HostProfiles::new());
+    }
+}
@@ -0,0 +1,352 @@
+/*
+Split link between the two halves of the keyboard.
+
+The left half is the central: it is the only half that talks to the host as a HID keyboard,
+and it connects to the right half over a private GATT service. The right half is the peripheral:
+it scans its own matrix and notifies the central of every press and release.
+The central publishes the received keys with its own matrix events, with the columns offset by COLS.
+
+The link is encrypted and bonded, the split characteristic is only readable over an encrypted link.
+The first half found is bonded and its address is stored, the central then only connects to that half.
+The central connects with its public address, the rotating host profile addresses are only advertised.
+*/
+
+use crate::config::config::*;
+use crate::debounce::Debouncer;
+use crate::delay::*;
+use crate::events::{DropCounter, MatrixChannel, RawMutex};
+use crate::hid::BleStatus;
+use crate::matrix::{Key, KeyStates, MatrixEvent, SPLIT_KEYS};
+use crate::split::protocol::*;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+
+use esp32_nimble::{
+    enums::*, utilities::BleUuid, uuid128, BLEAdvertisementData, BLEClient, BLEDevice, BLEError,
+    BLEScan, NimbleProperties,
+};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use heapless::{Deque, Vec};
+use spin::Mutex as spinMutex;
+use std::sync::Mutex;
+
+/* events received from the peripheral, filled from the NimBLE host task */
+static REMOTE_EVENTS: Mutex<Deque<SplitEvent, SPLIT_EVENTS_QUEUE_SIZE>> = Mutex::new(Deque::new());
+static REMOTE_EVENTS_DROPPED: DropCounter = DropCounter::new("Split event queue");
+
+fn split_service_uuid() -> BleUuid {
+    uuid128!("5d6a0b40-6f3c-4c8e-9e7a-52b1f3a7c001")
+}
+
+fn split_characteristic_uuid() -> BleUuid {
+    uuid128!("5d6a0b40-6f3c-4c8e-9e7a-52b1f3a7c002")
+}
+
+const NVS_NAMESPACE: &str = "split";
+const NVS_PEER_KEY: &str = "peer";
+const ADDRESS_SIZE: usize = 6;
+
+/* both halves pair with secure connections and keep the bond */
+fn set_security(ble_device: &BLEDevice) {
+    ble_device
+        .security()
+        .set_auth(AuthReq::all())
+        .set_io_cap(SecurityIOCap::NoInputNoOutput);
+}
+
+/* the address of the bonded peripheral, None until the first half is bonded */
+fn load_peer(nvs: &EspNvs<NvsDefault>) -> Option<[u8; ADDRESS_SIZE]> {
+    let mut buffer = [0; ADDRESS_SIZE];
+
+    match nvs.get_blob(NVS_PEER_KEY, &mut buffer) {
+        Ok(data) => data.and_then(|data| data.try_into().ok()),
+        Err(_error) => {
+            #[cfg(feature = "debug")]
+            log::info!("Error reading the split peripheral: {:?}", _error);
+
+            None
+        }
+    }
+}
+
+fn on_split_notify(data: &[u8]) {
+    match decode(data) {
+        Ok(events) => {
+            if let Ok(mut remote_events) = REMOTE_EVENTS.lock() {
+                for event in events {
+                    if remote_events.push_back(event).is_err() {
+                        REMOTE_EVENTS_DROPPED.count();
+                    }
+                }
+            }
+        }
+        Err(_error) => {
+            #[cfg(feature = "debug")]
+            log::info!("Invalid split frame: {:?}", _error);
+        }
+    }
+}
+
+async fn connect_peripheral(
+    client: &mut BLEClient,
+    peer: &mut Option<[u8; ADDRESS_SIZE]>,
+    nvs: &mut EspNvs<NvsDefault>,
+) -> Result<bool, BLEError> {
+    let ble_device = BLEDevice::take();
+    let mut ble_scan = BLEScan::new();
+
+    /* look for the half advertising the split service, only the bonded one once there is a bond */
+    let device = ble_scan
+        .active_scan(true)
+        .interval(100)
+        .window(99)
+        .start(ble_device, SPLIT_SCAN_TIMEOUT_MS, |device, data| {
+            let bonded = peer.map_or(true, |peer| device.addr().as_le_bytes() == peer);
+
+            if bonded && data.is_advertising_service(&split_service_uuid()) {
+                Some(*device)
+            } else {
+                None
+            }
+        })
+        .await?;
+
+    if let Some(device) = device {
+        client.on_connect(|client| {
+            /* 7.5ms - 15ms connection interval, keys should not lag behind the central */
+            client.update_conn_params(6, 12, 0, 100).ok();
+        });
+
+        /* the split link keeps the public address, the host profile addresses change with the
+         * selected profile and the bond of the halves would no longer match */
+        ble_device.set_own_addr_type(OwnAddrType::Public);
+        let connected = client.connect(&device.addr()).await;
+        ble_device.set_own_addr_type(OwnAddrType::Random);
+        connected?;
+
+        /* pairs the first time, encrypts with the stored keys afterwards */
+        client.secure_connection().await?;
+
+        let desc = client.desc()?;
+        if !desc.encrypted() || !desc.bonded() {
+            #[cfg(feature = "debug")]
+            log::info!("Split peripheral {:?} not bonded!", desc.address());
+
+            client.disconnect()?;
+            return Ok(false);
+        }
+
+        /* the first half bonded is the only one the central connects to from now on */
+        if peer.is_none() {
+            let address = desc.id_address().as_le_bytes();
+
+            if let Err(_error) = nvs.set_blob(NVS_PEER_KEY, &address) {
+                #[cfg(feature = "debug")]
+                log::info!("Error saving the split peripheral: {:?}", _error);
+            }
+            *peer = Some(address);
+        }
+
+        let service = client.get_service(split_service_uuid()).await?;
+        let characteristic = service
+            .get_characteristic(split_characteristic_uuid())
+            .await?;
+
+        characteristic
+            .on_notify(on_split_notify)
+            .subscribe_notify(false)
+            .await?;
+
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+/* central side: receive the keys of the peripheral half and publish them with the local ones */
+pub async fn split_receive_keys(
+    nvs_partition: EspDefaultNvsPartition,
+    matrix_events: &MatrixChannel,
+    activity: &Signal<RawMutex, ()>,
+) -> ! {
+    let ble_device = BLEDevice::take();
+    set_security(ble_device);
+    let mut client = ble_device.new_client();
+
+    let mut nvs =
+        EspNvs::new(nvs_partition, NVS_NAMESPACE, true).expect("Error opening the NVS namespace!");
+    let mut peer = load_peer(&nvs);
+
+    /* keys currently held on the peripheral half */
+    let mut remote_keys: KeyStates<bool> = KeyStates::default();
+
+    loop {
+        if client.connected() {
+            /* take the received events, then release the lock */
+            let mut events: Vec<SplitEvent, SPLIT_EVENTS_QUEUE_SIZE> = Vec::new();
+            if let Ok(mut remote_events) = REMOTE_EVENTS.lock() {
+                while let Some(event) = remote_events.pop_front() {
+                    events.push(event).ok();
+                }
+            }
+
+            if !events.is_empty() {
+                activity.signal(());
+            }
+
+            for event in events.iter() {
+                /* the peripheral columns follow the central ones */
+                let key = Key::new(event.key.row, event.key.col + COLS as i8);
+
+                let pressed = matches!(event.event, KeyEvent::Pressed);
+
+                /* a key outside of the matrix is not published */
+                match remote_keys.get_mut(&key) {
+                    Some(held) => *held = pressed,
+                    None => continue,
+                }
+
+                /* the peripheral debounced the remote keys, they are applied in the order they were received */
+                matrix_events
+                    .send(MatrixEvent::debounced(key, pressed, Instant::now()))
+                    .await;
+            }
+
+            /* there must be a delay so the WDT in not triggered */
+            delay_ms(1).await;
+        } else {
+            /* release everything that was held on the peripheral */
+            for (key, held) in remote_keys.iter() {
+                if *held {
+                    matrix_events
+                        .send(MatrixEvent::debounced(key, false, Instant::now()))
+                        .await;
+                }
+            }
+            remote_keys.clear();
+
+            if let Ok(mut remote_events) = REMOTE_EVENTS.lock() {
+                remote_events.clear();
+            }
+
+            match connect_peripheral(&mut client, &mut peer, &mut nvs).await {
+                Ok(true) => {
+                    #[cfg(feature = "debug")]
+                    log::info!("Split peripheral connected!");
+                }
+                Ok(false) => {
+                    #[cfg(feature = "debug")]
+                    log::info!("Split peripheral not found!");
+                }
+                Err(_error) => {
+                    #[cfg(feature = "debug")]
+                    log::info!("Split peripheral connection failed: {:?}", _error);
+
+                    client.disconnect().ok();
+                }
+            }
+
+            /* sleep for 100ms */
+            delay_ms(100).await;
+        }
+    }
+}
+
+fn split_event(event: &MatrixEvent) -> SplitEvent {
+    let key_event = if event.pressed {
+        KeyEvent::Pressed
+    } else {
+        KeyEvent::Released
+    };
+
+    SplitEvent::new(Side::Right, key_event, event.key)
+}
+
+/* peripheral side: notify the central of every debounced press and release */
+pub async fn split_send_keys(
+    matrix_events: &MatrixChannel,
+    ble_status: &spinMutex<BleStatus>,
+) -> ! {
+    let ble_device = BLEDevice::take();
+    set_security(ble_device);
+    let server = ble_device.get_server();
+
+    let service = server.create_service(split_service_uuid());
+    let characteristic = service.lock().create_characteristic(
+        split_characteristic_uuid(),
+        NimbleProperties::READ | NimbleProperties::READ_ENC | NimbleProperties::NOTIFY,
+    );
+
+    let ble_advertising = ble_device.get_advertising();
+    ble_advertising
+        .lock()
+        .scan_response(false)
+        .set_data(
+            BLEAdvertisementData::new()
+                .name("RUSTBOARD_RIGHT")
+                .add_service_uuid(split_service_uuid()),
+        )
+        .unwrap();
+    ble_advertising.lock().start().unwrap();
+
+    /* the peripheral debounces its own keys, only the debounced events go over the link */
+    let mut debouncer = KeyDebouncer::default();
+
+    /* one scanned event, and every key settled at once */
+    let mut events: Vec<SplitEvent, { SPLIT_KEYS + 1 }> = Vec::new();
+    let mut frame = [0u8; MAX_FRAME_SIZE];
+
+    loop {
+        if server.connected_count() > 0 {
+            /* the right half has a single profile, the link to the left half */
+            if let Some(mut ble_status) = ble_status.try_lock() {
+                *ble_status = BleStatus::Connected(0);
+            }
+
+            /* wait for a scanned event, or for the next settled debounce change */
+            let deadline = debouncer
+                .next_deadline()
+                .unwrap_or(Instant::now() + Duration::from_millis(100));
+
+            if let Either::First(event) = select(matrix_events.receive(), Timer::at(deadline)).await
+            {
+                if let Some(event) = debouncer.update(&event) {
+                    events.push(split_event(&event)).ok();
+                }
+            }
+
+            for event in debouncer.expired(Instant::now()) {
+                events.push(split_event(&event)).ok();
+            }
+
+            /* a frame holds MAX_EVENTS_PER_FRAME events, the rest goes in the next frames */
+            for chunk in events.chunks(MAX_EVENTS_PER_FRAME) {
+                if let Ok(frame_len) = encode(chunk, &mut frame) {
+                    characteristic
+                        .lock()
+                        .set_value(&frame[..frame_len])
+                        .notify();
+                }
+
+                #[cfg(feature = "debug")]
+                log::info!("Split events sent: {:?}", chunk);
+            }
+
+            events.clear();
+        } else {
+            /* check and store the ble status, then release the lock */
+            if let Some(mut ble_status) = ble_status.try_lock() {
+                *ble_status = BleStatus::NotConnected;
+            }
+
+            /* the central releases everything on disconnect */
+            debouncer.clear();
+            while matrix_events.try_receive().is_ok() {}
+
+            /* sleep for 100ms */
+            delay_ms(100).await;
+        }
+    }
+}
@@ -0,0 +1,7 @@
+pub mod protocol;
+
+#[cfg(feature = "esp")]
+mod link;
+
+#[cfg(feature = "esp")]
+pub use link::*;
@@ -0,0 +1,262 @@
+/*
+Wire protocol between the two halves of the keyboard.
+
+The peripheral half sends its matrix events to the central half as GATT notifications.
+Every notification carries one frame:
+
+ byte 0      | byte 1      | 3 bytes per event            | last byte
+ PROTOCOL_   | event count | flags | row | col            | CRC-8 of all
+ VERSION     |             | (bit 0: pressed, bit 1: side)| previous bytes
+*/
+
+use crate::matrix::Key;
+use heapless::Vec;
+
+pub const PROTOCOL_VERSION: u8 = 0x01;
+pub const MAX_EVENTS_PER_FRAME: usize = 8;
+pub const EVENT_SIZE: usize = 3;
+pub const MAX_FRAME_SIZE: usize = 2 + MAX_EVENTS_PER_FRAME * EVENT_SIZE + 1;
+
+const FLAG_PRESSED: u8 = 0x01;
+const FLAG_RIGHT_SIDE: u8 = 0x02;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyEvent {
+    Pressed,
+    Released,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SplitEvent {
+    pub side: Side,
+    pub event: KeyEvent,
+    pub key: Key,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtocolError {
+    BufferTooSmall,
+    TooManyEvents,
+    FrameTooShort,
+    LengthMismatch,
+    UnsupportedVersion(u8),
+    ChecksumMismatch,
+}
+
+impl SplitEvent {
+    pub fn new(side: Side, event: KeyEvent, key: Key) -> SplitEvent {
+        SplitEvent { side, event, key }
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+
+        if self.event == KeyEvent::Pressed {
+            flags |= FLAG_PRESSED;
+        }
+
+        if self.side == Side::Right {
+            flags |= FLAG_RIGHT_SIDE;
+        }
+
+        flags
+    }
+
+    fn from_bytes(bytes: &[u8]) -> SplitEvent {
+        SplitEvent {
+            side: if bytes[0] & FLAG_RIGHT_SIDE != 0 {
+                Side::Right
+            } else {
+                Side::Left
+            },
+            event: if bytes[0] & FLAG_PRESSED != 0 {
+                KeyEvent::Pressed
+            } else {
+                KeyEvent::Released
+            },
+            key: Key {
+                row: bytes[1] as i8,
+                col: bytes[2] as i8,
+            },
+        }
+    }
+}
+
+/* CRC-8 with polynomial 0x07 (CRC-8/SMBUS) */
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+
+    for byte in data.iter() {
+        crc ^= *byte;
+        for _ in 0..8 {
+            if crc & 0x80 != 0 {
+                crc = (crc << 1) ^ 0x07;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+
+    crc
+}
+
+/* encode the events into the buffer and return the length of the frame */
+pub fn encode(events: &[SplitEvent], buffer: &mut [u8]) -> Result<usize, ProtocolError> {
+    if events.len() > MAX_EVENTS_PER_FRAME {
+        return Err(ProtocolError::TooManyEvents);
+    }
+
+    let frame_len = 2 + events.len() * EVENT_SIZE + 1;
+
+    if buffer.len() < frame_len {
+        return Err(ProtocolError::BufferTooSmall);
+    }
+
+    buffer[0] = PROTOCOL_VERSION;
+    buffer[1] = events.len() as u8;
+
+    for (index, event) in events.iter().enumerate() {
+        let offset = 2 + index * EVENT_SIZE;
+        buffer[offset] = event.flags();
+        buffer[offset + 1] = event.key.row as u8;
+        buffer[offset + 2] = event.key.col as u8;
+    }
+
+    buffer[frame_len - 1] = crc8(&buffer[..frame_len - 1]);
+
+    Ok(frame_len)
+}
+
+/* decode a received frame into the events it carries */
+pub fn decode(frame: &[u8]) -> Result<Vec<SplitEvent, MAX_EVENTS_PER_FRAME>, ProtocolError> {
+    /* the smallest frame is version, count and checksum */
+    if frame.len() < 3 {
+        return Err(ProtocolError::FrameTooShort);
+    }
+
+    if frame[0] != PROTOCOL_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(frame[0]));
+    }
+
+    let count = frame[1] as usize;
+
+    if count > MAX_EVENTS_PER_FRAME {
+        return Err(ProtocolError::TooManyEvents);
+    }
+
+    if frame.len() != 2 + count * EVENT_SIZE + 1 {
+        return Err(ProtocolError::LengthMismatch);
+    }
+
+    if crc8(&frame[..frame.len() - 1]) != frame[frame.len() - 1] {
+        return Err(ProtocolError::ChecksumMismatch);
+    }
+
+    let mut events: Vec<SplitEvent, MAX_EVENTS_PER_FRAME> = Vec::new();
+
+    for bytes in frame[2..frame.len() - 1].chunks_exact(EVENT_SIZE) {
+        /* count has been checked against the capacity above */
+        events.push(SplitEvent::from_bytes(bytes)).ok();
+    }
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events() -> [SplitEvent; 3] {
+        [
+            SplitEvent::new(Side::Right, KeyEvent::Pressed, Key::new(0, 5)),
+            SplitEvent::new(Side::Right, KeyEvent::Released, Key::new(3, 0)),
+            SplitEvent::new(Side::Left, KeyEvent::Pressed, Key::new(2, 4)),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut frame = [0; MAX_FRAME_SIZE];
+        let len = encode(&events(), &mut frame).unwrap();
+
+        assert_eq!(len, 2 + 3 * EVENT_SIZE + 1);
+        assert_eq!(decode(&frame[..len]).unwrap(), events());
+    }
+
+    #[test]
+    fn round_trip_empty_and_full() {
+        let mut frame = [0; MAX_FRAME_SIZE];
+
+        let len = encode(&[], &mut frame).unwrap();
+        assert!(decode(&frame[..len]).unwrap().is_empty());
+
+        let full = [events()[0]; MAX_EVENTS_PER_FRAME];
+        let len = encode(&full, &mut frame).unwrap();
+        assert_eq!(len, MAX_FRAME_SIZE);
+        assert_eq!(decode(&frame[..len]).unwrap(), full);
+    }
+
+    #[test]
+    fn rejects_a_flipped_bit() {
+        let mut frame = [0; MAX_FRAME_SIZE];
+        let len = encode(&events(), &mut frame).unwrap();
+
+        for byte in 2..len {
+            let mut corrupted = frame;
+            corrupted[byte] ^= 0x10;
+            assert_eq!(
+                decode(&corrupted[..len]),
+                Err(ProtocolError::ChecksumMismatch)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        let mut frame = [0; MAX_FRAME_SIZE];
+        let len = encode(&events(), &mut frame).unwrap();
+
+        assert_eq!(decode(&frame[..2]), Err(ProtocolError::FrameTooShort));
+        assert_eq!(
+            decode(&frame[..len - 1]),
+            Err(ProtocolError::LengthMismatch)
+        );
+
+        let mut version = frame;
+        version[0] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            decode(&version[..len]),
+            Err(ProtocolError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+
+        let mut count = frame;
+        count[1] = MAX_EVENTS_PER_FRAME as u8 + 1;
+        assert_eq!(decode(&count[..len]), Err(ProtocolError::TooManyEvents));
+    }
+
+    #[test]
+    fn encode_limits() {
+        let mut frame = [0; MAX_FRAME_SIZE];
+
+        assert_eq!(
+            encode(&[events()[0]; MAX_EVENTS_PER_FRAME + 1], &mut frame),
+            Err(ProtocolError::TooManyEvents)
+        );
+        assert_eq!(
+            encode(&events(), &mut frame[..4]),
+            Err(ProtocolError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn crc8_check_value() {
+        /* the check value of CRC-8/SMBUS */
+        assert_eq!(crc8(b"123456789"), 0xF4);
+    }
+}
@@ -0,0 +1,137 @@
+/*
+Commands that remap the keymap at runtime, without reflashing.
+
+ byte 0 (command)   | following bytes
+ SET_KEY (0x01)     | layer | row | col | key (LE)
+ SET_TAP_HOLD (0x02)| index | tap (LE) | hold (LE)
+ SAVE (0x03)        | -
+ RESET (0x04)       | -
+
+The changes apply right away and are kept after a reboot once saved.
+Reset goes back to the compiled layout and erases the stored keymap.
+*/
+
+use crate::config::{config::*, enums::HidKeys, layers::*, layout::provide_layout};
+use crate::storage::{keymap::KeymapError, save_keymap, KeymapStore, StoreEntry};
+
+const SET_KEY: u8 = 0x01;
+const SET_TAP_HOLD: u8 = 0x02;
+const SAVE: u8 = 0x03;
+const RESET: u8 = 0x04;
+
+pub const MAX_COMMAND_SIZE: usize = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeymapCommand {
+    SetKey {
+        layer: Layer,
+        row: i8,
+        col: i8,
+        key: HidKeys,
+    },
+    SetTapHold {
+        index: u8,
+        tap_hold: TapHold,
+    },
+    Save,
+    Reset,
+}
+
+fn key_from_bytes(low: u8, high: u8) -> Result<HidKeys, KeymapError> {
+    let code = u16::from_le_bytes([low, high]);
+    HidKeys::from_code(code).ok_or(KeymapError::UnknownKey(code))
+}
+
+fn expect_len(data: &[u8], len: usize) -> Result<(), KeymapError> {
+    if data.len() != len {
+        return Err(KeymapError::LengthMismatch);
+    }
+
+    Ok(())
+}
+
+impl KeymapCommand {
+    pub fn decode(data: &[u8]) -> Result<KeymapCommand, KeymapError> {
+        let Some(command) = data.first() else {
+            return Err(KeymapError::DataTooShort);
+        };
+
+        match *command {
+            SET_KEY => {
+                expect_len(data, 6)?;
+
+                if data[1] as usize >= LAYERS {
+                    return Err(KeymapError::TooManyLayers(data[1]));
+                }
+
+                /* the columns of the right half follow the left half columns */
+                if data[2] as usize >= ROWS || data[3] as usize >= COLS * 2 {
+                    return Err(KeymapError::InvalidPosition);
+                }
+
+                Ok(KeymapCommand::SetKey {
+                    layer: Layer(data[1]),
+                    row: data[2] as i8,
+                    col: data[3] as i8,
+                    key: key_from_bytes(data[4], data[5])?,
+                })
+            }
+            SET_TAP_HOLD => {
+                expect_len(data, 6)?;
+
+                if data[1] as usize >= TAP_HOLD_KEYS {
+                    return Err(KeymapError::TooManyTapHolds(data[1]));
+                }
+
+                Ok(KeymapCommand::SetTapHold {
+                    index: data[1],
+                    tap_hold: TapHold::new(
+                        key_from_bytes(data[2], data[3])?,
+                        key_from_bytes(data[4], data[5])?,
+                    ),
+                })
+            }
+            SAVE => {
+                expect_len(data, 1)?;
+                Ok(KeymapCommand::Save)
+            }
+            RESET => {
+                expect_len(data, 1)?;
+                Ok(KeymapCommand::Reset)
+            }
+            unknown => Err(KeymapError::UnknownCommand(unknown)),
+        }
+    }
+
+    pub fn apply<S: KeymapStore>(
+        &self,
+        layers: &mut Layers,
+        store: &mut S,
+    ) -> Result<(), KeymapError> {
+        match *self {
+            KeymapCommand::SetKey {
+                layer,
+                row,
+                col,
+                key,
+            } => {
+                layers
+                    .layer(layer)
+                    .insert((row, col), key)
+                    .map_err(|_| KeymapError::LayerFull)?;
+            }
+            KeymapCommand::SetTapHold { index, tap_hold } => {
+                layers.tap_holds[index as usize] = tap_hold;
+            }
+            KeymapCommand::Save => {
+                save_keymap(store, layers)?;
+            }
+            KeymapCommand::Reset => {
+                *layers = provide_layout();
+                store.erase(StoreEntry::Keymap)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+/*
+Binary format of the dynamic macros stored in the NVS.
+
+ 4 bytes | byte 4  | byte 5     | per slot:                                        | 2 bytes
+ MAGIC   | FORMAT_ | slot count | event count | 3 bytes per key event          | CRC-16 of all
+         | VERSION |            |             | key (LE) | 1 pressed, 0 released | previous bytes (LE)
+
+The keys are stored as their HidKeys code, data that contains an unknown code is rejected as a whole.
+*/
+
+use crate::config::{config::*, enums::HidKeys, layers::*};
+use crate::storage::keymap::{crc16, KeymapError};
+
+pub const MAGIC: [u8; 4] = *b"RBDM";
+pub const FORMAT_VERSION: u8 = 0x01;
+pub const HEADER_SIZE: usize = 6;
+pub const EVENT_ENTRY_SIZE: usize = 3;
+pub const CRC_SIZE: usize = 2;
+pub const MAX_DYNAMIC_MACROS_SIZE: usize =
+    HEADER_SIZE + DYNAMIC_MACROS * (1 + DYNAMIC_MACRO_SIZE * EVENT_ENTRY_SIZE) + CRC_SIZE;
+
+/* the event count of a slot is kept in a byte */
+const _: () = assert!(
+    DYNAMIC_MACRO_SIZE <= 255,
+    "at most 255 key events per dynamic macro are supported"
+);
+
+/* encode the slots into the buffer and return the length of the data */
+pub fn encode(dynamic_macros: &DynamicMacros, buffer: &mut [u8]) -> Result<usize, KeymapError> {
+    let event_count: usize = dynamic_macros.iter().map(|keys| keys.len()).sum();
+    let len = HEADER_SIZE + dynamic_macros.len() + event_count * EVENT_ENTRY_SIZE + CRC_SIZE;
+
+    if buffer.len() < len {
+        return Err(KeymapError::BufferTooSmall);
+    }
+
+    buffer[..4].copy_from_slice(&MAGIC);
+    buffer[4] = FORMAT_VERSION;
+    buffer[5] = dynamic_macros.len() as u8;
+
+    let mut offset = HEADER_SIZE;
+
+    for keys in dynamic_macros.iter() {
+        buffer[offset] = keys.len() as u8;
+        offset += 1;
+
+        for recorded in keys.iter() {
+            buffer[offset..offset + 2].copy_from_slice(&(recorded.key as u16).to_le_bytes());
+            buffer[offset + 2] = recorded.pressed as u8;
+            offset += EVENT_ENTRY_SIZE;
+        }
+    }
+
+    let crc = crc16(&buffer[..offset]);
+    buffer[offset..offset + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+
+    Ok(len)
+}
+
+/* decode the stored data into the slots, the slots past DYNAMIC_MACROS are dropped */
+pub fn decode(data: &[u8]) -> Result<DynamicMacros, KeymapError> {
+    if data.len() < HEADER_SIZE + CRC_SIZE {
+        return Err(KeymapError::DataTooShort);
+    }
+
+    if data[..4] != MAGIC {
+        return Err(KeymapError::BadMagic);
+    }
+
+    if data[4] != FORMAT_VERSION {
+        return Err(KeymapError::UnsupportedVersion(data[4]));
+    }
+
+    let end = data.len() - CRC_SIZE;
+    let crc = u16::from_le_bytes([data[end], data[end + 1]]);
+    if crc16(&data[..end]) != crc {
+        return Err(KeymapError::ChecksumMismatch);
+    }
+
+    let mut dynamic_macros = DynamicMacros::default();
+    let mut offset = HEADER_SIZE;
+
+    for slot in 0..data[5] as usize {
+        let event_count = *data.get(offset).ok_or(KeymapError::LengthMismatch)? as usize;
+        let events_end = offset + 1 + event_count * EVENT_ENTRY_SIZE;
+
+        if events_end > end {
+            return Err(KeymapError::LengthMismatch);
+        }
+
+        for entry in data[offset + 1..events_end].chunks_exact(EVENT_ENTRY_SIZE) {
+            let code = u16::from_le_bytes([entry[0], entry[1]]);
+            let key = HidKeys::from_code(code).ok_or(KeymapError::UnknownKey(code))?;
+
+            if let Some(keys) = dynamic_macros.get_mut(slot) {
+                keys.push(RecordedKey {
+                    key,
+                    pressed: entry[2] != 0,
+                })
+                /* saved with a larger DYNAMIC_MACRO_SIZE */
+                .map_err(|_| KeymapError::LengthMismatch)?;
+            }
+        }
+
+        offset = events_end;
+    }
+
+    if offset != end {
+        return Err(KeymapError::LengthMismatch);
+    }
+
+    Ok(dynamic_macros)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{load_dynamic_macros, save_dynamic_macros, StoreEntry};
+    use crate::testing::*;
+
+    fn dynamic_macros() -> DynamicMacros {
+        let mut dynamic_macros = DynamicMacros::default();
+
+        for (key, pressed) in [
+            (HidKeys::ModifierShift, true),
+            (HidKeys::A, true),
+            (HidKeys::A, false),
+            (HidKeys::ModifierShift, false),
+        ] {
+            dynamic_macros[0]
+                .push(RecordedKey { key, pressed })
+                .unwrap();
+        }
+
+        dynamic_macros
+    }
+
+    #[test]
+    fn round_trip_through_the_store() {
+        let mut store = MemoryStore::default();
+        save_dynamic_macros(&mut store, &dynamic_macros()).unwrap();
+
+        assert_eq!(load_dynamic_macros(&mut store), Some(dynamic_macros()));
+
+        /* a full slot fits the buffer */
+        let mut full = DynamicMacros::default();
+        for _ in 0..DYNAMIC_MACRO_SIZE {
+            full[DYNAMIC_MACROS - 1]
+                .push(RecordedKey {
+                    key: HidKeys::Z,
+                    pressed: true,
+                })
+                .unwrap();
+        }
+        save_dynamic_macros(&mut store, &full).unwrap();
+        assert_eq!(load_dynamic_macros(&mut store), Some(full));
+    }
+
+    #[test]
+    fn rejected_data() {
+        let mut buffer = [0; MAX_DYNAMIC_MACROS_SIZE];
+        let len = encode(&dynamic_macros(), &mut buffer).unwrap();
+        let data = &buffer[..len];
+
+        let mut corrupted = data.to_vec();
+        corrupted[HEADER_SIZE + 1] ^= 0x01;
+        assert_eq!(decode(&corrupted), Err(KeymapError::ChecksumMismatch));
+
+        let mut corrupted = data.to_vec();
+        corrupted[4] = FORMAT_VERSION + 1;
+        assert_eq!(
+            decode(&corrupted),
+            Err(KeymapError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+
+        assert_eq!(decode(&data[..3]), Err(KeymapError::DataTooShort));
+
+        /* rejected data leaves the slots empty */
+        let mut store = MemoryStore {
+            entries: vec![(StoreEntry::DynamicMacros, corrupted)],
+        };
+        assert_eq!(load_dynamic_macros(&mut store), None);
+    }
+}
@@ -0,0 +1,297 @@
+/*
+Binary format of the keymap stored in the NVS.
+
+ 4 bytes | byte 4  | byte 5      | byte 6         | 2 bytes     | 5 bytes per key             | 4 bytes per tap hold | 2 bytes
+ MAGIC   | FORMAT_ | layer count | tap hold count | key count   | layer | row | col | key     | tap | hold           | CRC-16 of all
+         | VERSION |             |                | (LE)        |                     (LE)    | (LE)  (LE)           | previous bytes (LE)
+
+The keys are stored as their HidKeys code, a keymap that contains an unknown code is rejected as a whole.
+*/
+
+use crate::config::{config::*, enums::HidKeys, layers::*};
+
+pub const MAGIC: [u8; 4] = *b"RBKM";
+pub const FORMAT_VERSION: u8 = 0x01;
+pub const HEADER_SIZE: usize = 9;
+pub const KEY_ENTRY_SIZE: usize = 5;
+pub const TAP_HOLD_ENTRY_SIZE: usize = 4;
+pub const CRC_SIZE: usize = 2;
+pub const MAX_KEYMAP_SIZE: usize = HEADER_SIZE
+    + LAYERS * LAYER_INDEXMAP_SIZE * KEY_ENTRY_SIZE
+    + TAP_HOLD_KEYS * TAP_HOLD_ENTRY_SIZE
+    + CRC_SIZE;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeymapError {
+    BufferTooSmall,
+    DataTooShort,
+    BadMagic,
+    UnsupportedVersion(u8),
+    LengthMismatch,
+    ChecksumMismatch,
+    TooManyLayers(u8),
+    TooManyTapHolds(u8),
+    LayerFull,
+    UnknownKey(u16),
+    UnknownCommand(u8),
+    InvalidPosition,
+    StorageFailed,
+}
+
+/* CRC-16 with polynomial 0x1021 and initial value 0xFFFF (CRC-16/CCITT-FALSE) */
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+
+    for byte in data.iter() {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+
+    crc
+}
+
+fn key_from_bytes(bytes: &[u8]) -> Result<HidKeys, KeymapError> {
+    let code = u16::from_le_bytes([bytes[0], bytes[1]]);
+    HidKeys::from_code(code).ok_or(KeymapError::UnknownKey(code))
+}
+
+/* encode the keymap into the buffer and return the length of the data */
+pub fn encode(layers: &Layers, buffer: &mut [u8]) -> Result<usize, KeymapError> {
+    let key_count: usize = layers.layers.iter().map(|keys| keys.len()).sum();
+    let len = HEADER_SIZE
+        + key_count * KEY_ENTRY_SIZE
+        + layers.tap_holds.len() * TAP_HOLD_ENTRY_SIZE
+        + CRC_SIZE;
+
+    if buffer.len() < len {
+        return Err(KeymapError::BufferTooSmall);
+    }
+
+    buffer[..4].copy_from_slice(&MAGIC);
+    buffer[4] = FORMAT_VERSION;
+    buffer[5] = layers.layers.len() as u8;
+    buffer[6] = layers.tap_holds.len() as u8;
+    buffer[7..9].copy_from_slice(&(key_count as u16).to_le_bytes());
+
+    let mut offset = HEADER_SIZE;
+
+    for (layer, keys) in layers.layers.iter().enumerate() {
+        for ((row, col), key) in keys.iter() {
+            buffer[offset] = layer as u8;
+            buffer[offset + 1] = *row as u8;
+            buffer[offset + 2] = *col as u8;
+            buffer[offset + 3..offset + 5].copy_from_slice(&(*key as u16).to_le_bytes());
+            offset += KEY_ENTRY_SIZE;
+        }
+    }
+
+    for tap_hold in layers.tap_holds.iter() {
+        buffer[offset..offset + 2].copy_from_slice(&(tap_hold.tap as u16).to_le_bytes());
+        buffer[offset + 2..offset + 4].copy_from_slice(&(tap_hold.hold as u16).to_le_bytes());
+        offset += TAP_HOLD_ENTRY_SIZE;
+    }
+
+    let crc = crc16(&buffer[..offset]);
+    buffer[offset..offset + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+
+    Ok(len)
+}
+
+/* decode the stored data into a keymap */
+pub fn decode(data: &[u8]) -> Result<Layers, KeymapError> {
+    if data.len() < HEADER_SIZE + CRC_SIZE {
+        return Err(KeymapError::DataTooShort);
+    }
+
+    if data[..4] != MAGIC {
+        return Err(KeymapError::BadMagic);
+    }
+
+    if data[4] != FORMAT_VERSION {
+        return Err(KeymapError::UnsupportedVersion(data[4]));
+    }
+
+    let layer_count = data[5];
+    let tap_hold_count = data[6];
+    let key_count = u16::from_le_bytes([data[7], data[8]]) as usize;
+
+    if layer_count as usize > LAYERS {
+        return Err(KeymapError::TooManyLayers(layer_count));
+    }
+
+    if tap_hold_count as usize > TAP_HOLD_KEYS {
+        return Err(KeymapError::TooManyTapHolds(tap_hold_count));
+    }
+
+    let keys_end = HEADER_SIZE + key_count * KEY_ENTRY_SIZE;
+    let tap_holds_end = keys_end + tap_hold_count as usize * TAP_HOLD_ENTRY_SIZE;
+
+    if data.len() != tap_holds_end + CRC_SIZE {
+        return Err(KeymapError::LengthMismatch);
+    }
+
+    let crc = u16::from_le_bytes([data[tap_holds_end], data[tap_holds_end + 1]]);
+    if crc16(&data[..tap_holds_end]) != crc {
+        return Err(KeymapError::ChecksumMismatch);
+    }
+
+    let mut layers = Layers::new();
+
+    for entry in data[HEADER_SIZE..keys_end].chunks_exact(KEY_ENTRY_SIZE) {
+        if entry[0] >= layer_count {
+            return Err(KeymapError::TooManyLayers(entry[0]));
+        }
+
+        layers
+            .layer(Layer(entry[0]))
+            .insert(
+                (entry[1] as i8, entry[2] as i8),
+                key_from_bytes(&entry[3..5])?,
+            )
+            .map_err(|_| KeymapError::LayerFull)?;
+    }
+
+    for (tap_hold, entry) in layers
+        .tap_holds
+        .iter_mut()
+        .zip(data[keys_end..tap_holds_end].chunks_exact(TAP_HOLD_ENTRY_SIZE))
+    {
+        *tap_hold = TapHold::new(key_from_bytes(&entry[0..2])?, key_from_bytes(&entry[2..4])?);
+    }
+
+    Ok(layers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::layout::provide_layout;
+    use crate::storage::{load_keymap, save_keymap, StoreEntry};
+    use crate::testing::*;
+
+    fn remapped() -> Layers {
+        let mut layers = Layers::new();
+        layers
+            .layer(Layer::BASE)
+            .insert((0, 0), HidKeys::Z)
+            .unwrap();
+        layers
+            .layer(Layer(1))
+            .insert((2, 3), HidKeys::LayerToggle1)
+            .unwrap();
+        layers.tap_holds[0] = TapHold::new(HidKeys::A, HidKeys::ModifierShift);
+        layers
+    }
+
+    fn assert_same_keys(layers: &Layers, expected: &Layers) {
+        for (keys, expected_keys) in layers.layers.iter().zip(expected.layers.iter()) {
+            assert_eq!(keys.len(), expected_keys.len());
+            assert!(keys
+                .iter()
+                .all(|(position, key)| expected_keys.get(position) == Some(key)));
+        }
+    }
+
+    fn encoded(layers: &Layers) -> Vec<u8> {
+        let mut buffer = [0; MAX_KEYMAP_SIZE];
+        let len = encode(layers, &mut buffer).unwrap();
+        buffer[..len].to_vec()
+    }
+
+    #[test]
+    fn round_trip() {
+        let layers = remapped();
+        let decoded = decode(&encoded(&layers)).unwrap();
+
+        assert_same_keys(&decoded, &layers);
+        assert_eq!(decoded.tap_holds, layers.tap_holds);
+    }
+
+    #[test]
+    fn round_trip_through_the_store() {
+        let mut store = MemoryStore::default();
+        save_keymap(&mut store, &remapped()).unwrap();
+
+        assert_same_keys(&load_keymap(&mut store).unwrap(), &remapped());
+    }
+
+    #[test]
+    fn rejected_data() {
+        let data = encoded(&remapped());
+        let end = data.len() - CRC_SIZE;
+
+        /* a changed key or a changed CRC */
+        let mut corrupted = data.clone();
+        corrupted[HEADER_SIZE] ^= 0x01;
+        assert_eq!(
+            decode(&corrupted).err(),
+            Some(KeymapError::ChecksumMismatch)
+        );
+
+        let mut corrupted = data.clone();
+        corrupted[end] ^= 0xFF;
+        assert_eq!(
+            decode(&corrupted).err(),
+            Some(KeymapError::ChecksumMismatch)
+        );
+
+        let mut corrupted = data.clone();
+        corrupted[4] = FORMAT_VERSION + 1;
+        assert_eq!(
+            decode(&corrupted).err(),
+            Some(KeymapError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+
+        let mut corrupted = data.clone();
+        corrupted[0] = b'X';
+        assert_eq!(decode(&corrupted).err(), Some(KeymapError::BadMagic));
+
+        assert_eq!(
+            decode(&data[..end]).err(),
+            Some(KeymapError::LengthMismatch)
+        );
+        assert_eq!(decode(&data[..4]).err(), Some(KeymapError::DataTooShort));
+    }
+
+    #[test]
+    fn unknown_key_rejected() {
+        let mut data = encoded(&remapped());
+        let end = data.len() - CRC_SIZE;
+
+        /* a valid CRC over a code that is not a key */
+        data[HEADER_SIZE + 3..HEADER_SIZE + 5].copy_from_slice(&0xFFFFu16.to_le_bytes());
+        let crc = crc16(&data[..end]);
+        data[end..].copy_from_slice(&crc.to_le_bytes());
+
+        assert_eq!(decode(&data).err(), Some(KeymapError::UnknownKey(0xFFFF)));
+    }
+
+    #[test]
+    fn compiled_layout_fallback() {
+        let mut layers = Layers::new();
+
+        /* nothing stored */
+        layers.load_layout(&mut MemoryStore::default());
+        assert_same_keys(&layers, &provide_layout());
+
+        /* a stored keymap that is rejected */
+        let mut data = encoded(&remapped());
+        data[HEADER_SIZE] ^= 0x01;
+        let mut store = MemoryStore {
+            entries: vec![(StoreEntry::Keymap, data)],
+        };
+        layers.load_layout(&mut store);
+        assert_same_keys(&layers, &provide_layout());
+
+        /* a stored keymap takes precedence */
+        save_keymap(&mut store, &remapped()).unwrap();
+        layers.load_layout(&mut store);
+        assert_same_keys(&layers, &remapped());
+    }
+}
@@ -0,0 +1,84 @@
+pub mod command;
+pub mod dynamic_macros;
+pub mod keymap;
+#[cfg(feature = "esp")]
+mod nvs;
+
+#[cfg(feature = "esp")]
+pub use nvs::*;
+
+use crate::config::layers::{DynamicMacros, Layers};
+use keymap::*;
+
+/* what is kept in the store */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StoreEntry {
+    Keymap,
+    Macros,
+    Profiles,
+    DynamicMacros,
+}
+
+/* where the keymap remapped at runtime is kept between boots */
+pub trait KeymapStore {
+    /* read the stored entry into the buffer, None if the entry is not stored */
+    fn read<'a>(&mut self, entry: StoreEntry, buffer: &'a mut [u8]) -> Option<&'a [u8]>;
+    fn write(&mut self, entry: StoreEntry, data: &[u8]) -> Result<(), KeymapError>;
+    fn erase(&mut self, entry: StoreEntry) -> Result<(), KeymapError>;
+}
+
+/* a store that keeps nothing, the compiled layout is always used */
+pub struct NoStore;
+
+impl KeymapStore for NoStore {
+    fn read<'a>(&mut self, _entry: StoreEntry, _buffer: &'a mut [u8]) -> Option<&'a [u8]> {
+        None
+    }
+
+    fn write(&mut self, _entry: StoreEntry, _data: &[u8]) -> Result<(), KeymapError> {
+        Err(KeymapError::StorageFailed)
+    }
+
+    fn erase(&mut self, _entry: StoreEntry) -> Result<(), KeymapError> {
+        Ok(())
+    }
+}
+
+pub fn load_keymap<S: KeymapStore>(store: &mut S) -> Option<Layers> {
+    let mut buffer = [0; MAX_KEYMAP_SIZE];
+
+    decode(store.read(StoreEntry::Keymap, &mut buffer)?)
+        .inspect_err(|_error| {
+            #[cfg(feature = "debug")]
+            log::info!("Stored keymap rejected: {:?}", _error);
+        })
+        .ok()
+}
+
+pub fn save_keymap<S: KeymapStore>(store: &mut S, layers: &Layers) -> Result<(), KeymapError> {
+    let mut buffer = [0; MAX_KEYMAP_SIZE];
+    let len = encode(layers, &mut buffer)?;
+
+    store.write(StoreEntry::Keymap, &buffer[..len])
+}
+
+pub fn load_dynamic_macros<S: KeymapStore>(store: &mut S) -> Option<DynamicMacros> {
+    let mut buffer = [0; dynamic_macros::MAX_DYNAMIC_MACROS_SIZE];
+
+    dynamic_macros::decode(store.read(StoreEntry::DynamicMacros, &mut buffer)?)
+        .inspect_err(|_error| {
+            #[cfg(feature = "debug")]
+            log::info!("Stored dynamic macros rejected: {:?}", _error);
+        })
+        .ok()
+}
+
+pub fn save_dynamic_macros<S: KeymapStore>(
+    store: &mut S,
+    dynamic_macros: &DynamicMacros,
+) -> Result<(), KeymapError> {
+    let mut buffer = [0; dynamic_macros::MAX_DYNAMIC_MACROS_SIZE];
+    let len = dynamic_macros::encode(dynamic_macros, &mut buffer)?;
+
+    store.write(StoreEntry::DynamicMacros, &buffer[..len])
+}
@@ -0,0 +1,56 @@
+use crate::storage::{keymap::KeymapError, KeymapStore, StoreEntry};
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+const NVS_NAMESPACE: &str = "rustboard";
+
+/* every entry is kept as one blob in the default NVS partition */
+pub struct NvsKeymapStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl NvsKeymapStore {
+    /* the partition is taken once, every task with a store gets a clone of it */
+    pub fn new(partition: EspDefaultNvsPartition) -> Self {
+        Self {
+            nvs: EspNvs::new(partition, NVS_NAMESPACE, true)
+                .expect("Error opening the NVS namespace!"),
+        }
+    }
+}
+
+fn nvs_key(entry: StoreEntry) -> &'static str {
+    match entry {
+        StoreEntry::Keymap => "keymap",
+        StoreEntry::Macros => "macros",
+        StoreEntry::Profiles => "profiles",
+        StoreEntry::DynamicMacros => "dyn_macros",
+    }
+}
+
+impl KeymapStore for NvsKeymapStore {
+    fn read<'a>(&mut self, entry: StoreEntry, buffer: &'a mut [u8]) -> Option<&'a [u8]> {
+        match self.nvs.get_blob(nvs_key(entry), buffer) {
+            Ok(data) => data,
+            Err(_error) => {
+                #[cfg(feature = "debug")]
+                log::info!("Error reading {:?} from the NVS: {:?}", entry, _error);
+
+                None
+            }
+        }
+    }
+
+    fn write(&mut self, entry: StoreEntry, data: &[u8]) -> Result<(), KeymapError> {
+        self.nvs
+            .set_blob(nvs_key(entry), data)
+            .map_err(|_| KeymapError::StorageFailed)
+    }
+
+    fn erase(&mut self, entry: StoreEntry) -> Result<(), KeymapError> {
+        self.nvs
+            .remove(nvs_key(entry))
+            .map(|_| ())
+            .map_err(|_| KeymapError::StorageFailed)
+    }
+}
@@ -0,0 +1,222 @@
+/*
+Fakes for the host tests: a matrix that returns scripted scans, a sink that records the reports,
+a store kept in memory, the bounce waveforms of a switch and a key processor built from a few keys.
+*/
+
+use crate::config::enums::HidKeys;
+use crate::config::layers::{Layer, Layers};
+use crate::debounce::Debouncer;
+use crate::events::HidEvent;
+use crate::hid::{ConsumerReport, HidSink, KeyReport, NkroReport, ReportMode};
+use crate::matrix::{Key, MatrixEvent, MatrixSource, MATRIX_KEYS};
+use crate::processor::KeyProcessor;
+use crate::storage::{keymap::KeymapError, KeymapStore, StoreEntry};
+
+use embassy_time::Instant;
+use std::collections::VecDeque;
+
+pub fn t(ms: u64) -> Instant {
+    Instant::from_millis(ms)
+}
+
+/* returns the scripted scans one after the other, then the last one again */
+#[derive(Default)]
+pub struct FakeMatrix {
+    scans: VecDeque<Vec<Key>>,
+    last: Vec<Key>,
+}
+
+impl FakeMatrix {
+    pub fn new(scans: &[&[Key]]) -> Self {
+        FakeMatrix {
+            scans: scans.iter().map(|keys| keys.to_vec()).collect(),
+            last: Vec::new(),
+        }
+    }
+}
+
+impl MatrixSource for FakeMatrix {
+    async fn scan(&mut self) -> heapless::Vec<Key, MATRIX_KEYS> {
+        if let Some(keys) = self.scans.pop_front() {
+            self.last = keys;
+        }
+
+        heapless::Vec::from_slice(&self.last).unwrap()
+    }
+}
+
+/* the scanned changes of one key, (ms, pressed) */
+pub type Waveform = &'static [(u64, bool)];
+
+/* a press and a release that both bounce for a few milliseconds */
+pub const BOUNCY_TAP: Waveform = &[
+    (0, true),
+    (1, false),
+    (2, true),
+    (3, false),
+    (4, true),
+    (200, false),
+    (201, true),
+    (202, false),
+];
+
+/* a held key that drops out once for 2 ms */
+pub const GLITCH_WHILE_HELD: Waveform = &[(0, true), (30, false), (32, true), (300, false)];
+
+/* a key that is not pressed, seen pressed for 5 ms */
+pub const NOISE_SPIKE: Waveform = &[(0, true), (5, false)];
+
+/* the changes reported by the debouncer, the settled ones are taken every millisecond until then */
+pub fn debounce<D: Debouncer>(waveform: Waveform, until: u64) -> Vec<(u64, bool)> {
+    let key = Key::new(0, 0);
+    let mut debouncer = D::default();
+    let mut reported = Vec::new();
+
+    for ms in 0..=until {
+        for (_, pressed) in waveform.iter().filter(|(time, _)| *time == ms) {
+            if let Some(event) = debouncer.update(&MatrixEvent::new(key, *pressed, t(ms))) {
+                reported.push((ms, event.pressed));
+            }
+        }
+
+        for event in debouncer.expired(t(ms)) {
+            reported.push((ms, event.pressed));
+        }
+
+        /* the next deadline is never one that was already due */
+        assert!(debouncer
+            .next_deadline()
+            .map_or(true, |deadline| deadline > t(ms)));
+    }
+
+    reported
+}
+
+/* keeps every report in the order it was sent */
+#[derive(Default)]
+pub struct RecordingSink {
+    pub events: Vec<HidEvent>,
+}
+
+impl RecordingSink {
+    /* the key reports as the modifiers and the keys held */
+    pub fn key_reports(&self) -> Vec<(u8, Vec<u8>)> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                HidEvent::Keys(report) => Some((
+                    report.modifiers,
+                    report
+                        .keys
+                        .iter()
+                        .copied()
+                        .filter(|key| *key != 0)
+                        .collect(),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /* the consumer reports as the media usages held */
+    pub fn consumer_usages(&self) -> Vec<[u16; 2]> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                HidEvent::Consumer(report) => Some(report.usages),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+}
+
+impl HidSink for RecordingSink {
+    fn send_report(&mut self, key_report: &KeyReport) {
+        self.events.push(HidEvent::Keys(*key_report));
+    }
+
+    fn send_nkro_report(&mut self, nkro_report: &NkroReport) {
+        self.events.push(HidEvent::Nkro(*nkro_report));
+    }
+
+    fn send_consumer_report(&mut self, consumer_report: &ConsumerReport) {
+        self.events.push(HidEvent::Consumer(*consumer_report));
+    }
+}
+
+/* keeps the written entries like the NVS does */
+#[derive(Default)]
+pub struct MemoryStore {
+    pub entries: Vec<(StoreEntry, Vec<u8>)>,
+}
+
+impl KeymapStore for MemoryStore {
+    fn read<'a>(&mut self, entry: StoreEntry, buffer: &'a mut [u8]) -> Option<&'a [u8]> {
+        let (_, data) = self.entries.iter().find(|(stored, _)| *stored == entry)?;
+        let buffer = buffer.get_mut(..data.len())?;
+        buffer.copy_from_slice(data);
+
+        Some(buffer)
+    }
+
+    fn write(&mut self, entry: StoreEntry, data: &[u8]) -> Result<(), KeymapError> {
+        self.erase(entry)?;
+        self.entries.push((entry, data.to_vec()));
+
+        Ok(())
+    }
+
+    fn erase(&mut self, entry: StoreEntry) -> Result<(), KeymapError> {
+        self.entries.retain(|(stored, _)| *stored != entry);
+
+        Ok(())
+    }
+}
+
+/* a processor with the keys on their layers, sending the six key report */
+pub fn processor(keys: &[(Layer, Key, HidKeys)]) -> KeyProcessor {
+    let mut layers = Layers::new();
+
+    for (layer, key, valid_key) in keys.iter() {
+        layers
+            .layer(*layer)
+            .insert((key.row, key.col), *valid_key)
+            .unwrap();
+    }
+
+    let mut key_processor = KeyProcessor::new(layers);
+    key_processor.set_report_mode(ReportMode::SixKro, &mut RecordingSink::default());
+
+    key_processor
+}
+
+/* the decisions due before the event come first, as in the processing task */
+pub fn press(key_processor: &mut KeyProcessor, sink: &mut RecordingSink, key: Key, ms: u64) {
+    key_processor.tick(t(ms), sink);
+    key_processor.process_event(&MatrixEvent::new(key, true, t(ms)), sink);
+}
+
+pub fn release(key_processor: &mut KeyProcessor, sink: &mut RecordingSink, key: Key, ms: u64) {
+    key_processor.tick(t(ms), sink);
+    key_processor.process_event(&MatrixEvent::new(key, false, t(ms)), sink);
+}
+
+/* pressed at ms, released 10 ms later */
+pub fn tap(key_processor: &mut KeyProcessor, sink: &mut RecordingSink, key: Key, ms: u64) {
+    press(key_processor, sink, key, ms);
+    release(key_processor, sink, key, ms + 10);
+}
+
+/* run the time based decisions due by then */
+pub fn run_until(key_processor: &mut KeyProcessor, sink: &mut RecordingSink, ms: u64) {
+    while key_processor
+        .next_deadline()
+        .is_some_and(|deadline| deadline <= t(ms))
+    {
+        key_processor.tick(t(ms), sink);
+    }
+}
@@ -0,0 +1,183 @@
+/*
+Translation between the HidKeys and the 16 bit keycodes used by VIA (QMK keycodes).
+
+The basic keys share their codes, the modifiers, the layer keys, the one shot keys, the tap dance keys,
+the macro keys, the dynamic macro keys and the media keys have their QMK equivalents.
+The fixed macros and the tap hold keys have none, they are sent as custom keyboard keycodes (QK_KB + HidKeys code),
+the report mode keys follow them (QK_KB_REPORT_MODE + the low nibble of the HidKeys code),
+then the power keys (QK_KB_POWER + the low nibble of the HidKeys code),
+the profile keys come before them (QK_KB_PROFILE + the low byte of the HidKeys code).
+*/
+
+use crate::config::enums::{HidKeys, KeyType, LayerAction, OneShot};
+
+pub const KC_NO: u16 = 0x0000;
+pub const KC_TRNS: u16 = 0x0001;
+
+const KC_LEFT_CTRL: u16 = 0x00E0;
+const KC_LEFT_SHIFT: u16 = 0x00E1;
+const KC_LEFT_ALT: u16 = 0x00E2;
+const KC_LEFT_GUI: u16 = 0x00E3;
+const KC_RIGHT_CTRL: u16 = 0x00E4;
+const KC_RIGHT_SHIFT: u16 = 0x00E5;
+const KC_RIGHT_ALT: u16 = 0x00E6;
+const KC_RIGHT_GUI: u16 = 0x00E7;
+
+/* every layer key range holds 32 layers */
+const QK_TO: u16 = 0x5200;
+const QK_MOMENTARY: u16 = 0x5220;
+const QK_DEF_LAYER: u16 = 0x5240;
+const QK_TOGGLE_LAYER: u16 = 0x5260;
+const QK_LAYER_MAX: u16 = 0x527F;
+const QK_ONE_SHOT_LAYER: u16 = 0x5280;
+const QK_ONE_SHOT_LAYER_MAX: u16 = 0x529F;
+
+/* the low 4 bits are the left modifiers, in the order of the HidModifiers bits, bit 4 the right ones */
+const QK_ONE_SHOT_MOD: u16 = 0x52A0;
+const QK_ONE_SHOT_MOD_MAX: u16 = 0x52BF;
+
+const QK_TAP_DANCE: u16 = 0x5700;
+const QK_TAP_DANCE_MAX: u16 = 0x57FF;
+
+const QK_MACRO: u16 = 0x7700;
+const QK_MACRO_MAX: u16 = 0x777F;
+
+/* the dynamic macro keys, QMK records 2 slots */
+const DYNAMIC_MACRO_KEYCODES: [(HidKeys, u16); 5] = [
+    (HidKeys::DynamicMacroRecord0, 0x7C53),
+    (HidKeys::DynamicMacroRecord1, 0x7C54),
+    (HidKeys::DynamicMacroStop, 0x7C55),
+    (HidKeys::DynamicMacroPlay0, 0x7C56),
+    (HidKeys::DynamicMacroPlay1, 0x7C57),
+];
+
+/* the media keys, in the order of the HidKeys media keys */
+const CONSUMER_KEYCODES: [(HidKeys, u16); 16] = [
+    (HidKeys::MediaNextTrack, 0x00AB),
+    (HidKeys::MediaPreviousTrack, 0x00AC),
+    (HidKeys::MediaStop, 0x00AD),
+    (HidKeys::MediaPlayPause, 0x00AE),
+    (HidKeys::MediaMute, 0x00A8),
+    (HidKeys::MediaVolumeUp, 0x00A9),
+    (HidKeys::MediaVolumeDown, 0x00AA),
+    (HidKeys::MediaBrightnessUp, 0x00BD),
+    (HidKeys::MediaBrightnessDown, 0x00BE),
+    (HidKeys::MediaMail, 0x00B1),
+    (HidKeys::MediaCalculator, 0x00B2),
+    (HidKeys::MediaMyComputer, 0x00B3),
+    (HidKeys::MediaWwwSearch, 0x00B4),
+    (HidKeys::MediaWwwHome, 0x00B5),
+    (HidKeys::MediaWwwBack, 0x00B6),
+    (HidKeys::MediaWwwForward, 0x00B7),
+];
+
+const QK_KB: u16 = 0x7E00;
+const QK_KB_PROFILE: u16 = 0x7E80;
+const QK_KB_PROFILE_MAX: u16 = 0x7EBF;
+const QK_KB_REPORT_MODE: u16 = 0x7EE0;
+const QK_KB_REPORT_MODE_MAX: u16 = 0x7EEF;
+const QK_KB_POWER: u16 = 0x7EF0;
+const QK_KB_MAX: u16 = 0x7EFF;
+
+pub fn to_via_keycode(key: &HidKeys) -> u16 {
+    match KeyType::check_type(key) {
+        KeyType::Modifier => match *key {
+            HidKeys::ModifierControl => KC_LEFT_CTRL,
+            HidKeys::ModifierShift => KC_LEFT_SHIFT,
+            HidKeys::ModifierAlt => KC_LEFT_ALT,
+            HidKeys::ModifierSuper => KC_LEFT_GUI,
+            _ => KC_NO,
+        },
+        KeyType::Layer => match LayerAction::get_layer_action(key) {
+            Some((LayerAction::To, layer)) => QK_TO | layer.0 as u16,
+            Some((LayerAction::Momentary, layer)) => QK_MOMENTARY | layer.0 as u16,
+            Some((LayerAction::Default, layer)) => QK_DEF_LAYER | layer.0 as u16,
+            Some((LayerAction::Toggle, layer)) => QK_TOGGLE_LAYER | layer.0 as u16,
+            None => KC_NO,
+        },
+        KeyType::OneShot => match OneShot::get_one_shot(key) {
+            Some(OneShot::Modifiers(modifiers)) => QK_ONE_SHOT_MOD | modifiers as u16,
+            Some(OneShot::Layer(layer)) => QK_ONE_SHOT_LAYER | layer.0 as u16,
+            None => KC_NO,
+        },
+        KeyType::TapDance => QK_TAP_DANCE | (*key as u16 & 0x00FF),
+        KeyType::MacroSequence => QK_MACRO | (*key as u16 & 0x00FF),
+        KeyType::Consumer => CONSUMER_KEYCODES
+            .iter()
+            .find(|(consumer_key, _)| consumer_key == key)
+            .map_or(KC_NO, |(_, keycode)| *keycode),
+        KeyType::DynamicMacro => DYNAMIC_MACRO_KEYCODES
+            .iter()
+            .find(|(dynamic_macro_key, _)| dynamic_macro_key == key)
+            .map_or(KC_NO, |(_, keycode)| *keycode),
+        KeyType::Macro | KeyType::TapHold => QK_KB | *key as u16,
+        KeyType::Profile => QK_KB_PROFILE + (*key as u16 & 0x00FF),
+        KeyType::ReportMode => QK_KB_REPORT_MODE | (*key as u16 & 0x000F),
+        KeyType::Power => QK_KB_POWER | (*key as u16 & 0x000F),
+        KeyType::Key => match *key as u16 {
+            code if code <= 0x00FF => code,
+            _ => KC_NO,
+        },
+        KeyType::Unused => KC_NO,
+    }
+}
+
+pub fn from_via_keycode(keycode: u16) -> Option<HidKeys> {
+    /* the media keycodes are in the basic keycode range */
+    if let Some((consumer_key, _)) = CONSUMER_KEYCODES
+        .iter()
+        .find(|(_, consumer_keycode)| *consumer_keycode == keycode)
+    {
+        return Some(*consumer_key);
+    }
+
+    if let Some((dynamic_macro_key, _)) = DYNAMIC_MACRO_KEYCODES
+        .iter()
+        .find(|(_, dynamic_macro_keycode)| *dynamic_macro_keycode == keycode)
+    {
+        return Some(*dynamic_macro_key)
+            .filter(|key| matches!(KeyType::check_type(key), KeyType::DynamicMacro));
+    }
+
+    match keycode {
+        KC_LEFT_CTRL | KC_RIGHT_CTRL => Some(HidKeys::ModifierControl),
+        KC_LEFT_SHIFT | KC_RIGHT_SHIFT => Some(HidKeys::ModifierShift),
+        KC_LEFT_ALT | KC_RIGHT_ALT => Some(HidKeys::ModifierAlt),
+        KC_LEFT_GUI | KC_RIGHT_GUI => Some(HidKeys::ModifierSuper),
+        0x0000..=0x00FF => HidKeys::from_code(keycode)
+            .filter(|key| matches!(KeyType::check_type(key), KeyType::Key)),
+        QK_TO..=QK_LAYER_MAX => {
+            let action = match keycode & 0xFFE0 {
+                QK_TO => 0x0220,
+                QK_MOMENTARY => 0x0200,
+                QK_DEF_LAYER => 0x0230,
+                _ => 0x0210,
+            };
+
+            HidKeys::from_code(action | (keycode & 0x001F))
+                .filter(|key| LayerAction::get_layer_action(key).is_some())
+        }
+        QK_ONE_SHOT_LAYER..=QK_ONE_SHOT_LAYER_MAX => {
+            HidKeys::from_code(0x0710 | (keycode & 0x001F))
+                .filter(|key| matches!(KeyType::check_type(key), KeyType::OneShot))
+        }
+        /* the right modifiers are sent as the left ones */
+        QK_ONE_SHOT_MOD..=QK_ONE_SHOT_MOD_MAX => HidKeys::from_code(0x0700 | (keycode & 0x000F))
+            .filter(|key| matches!(KeyType::check_type(key), KeyType::OneShot)),
+        QK_TAP_DANCE..=QK_TAP_DANCE_MAX => HidKeys::from_code(0x0800 | (keycode & 0x00FF))
+            .filter(|key| matches!(KeyType::check_type(key), KeyType::TapDance)),
+        QK_MACRO..=QK_MACRO_MAX => HidKeys::from_code(0x0900 | (keycode & 0x00FF))
+            .filter(|key| matches!(KeyType::check_type(key), KeyType::MacroSequence)),
+        QK_KB_PROFILE..=QK_KB_PROFILE_MAX => HidKeys::from_code(0x0500 + keycode - QK_KB_PROFILE)
+            .filter(|key| matches!(KeyType::check_type(key), KeyType::Profile)),
+        QK_KB_REPORT_MODE..=QK_KB_REPORT_MODE_MAX => {
+            HidKeys::from_code(0x0400 | (keycode & 0x000F))
+                .filter(|key| matches!(KeyType::check_type(key), KeyType::ReportMode))
+        }
+        QK_KB_POWER..=QK_KB_MAX => HidKeys::from_code(0x0600 | (keycode & 0x000F))
+            .filter(|key| matches!(KeyType::check_type(key), KeyType::Power)),
+        QK_KB..=QK_KB_MAX => HidKeys::from_code(keycode & 0x00FF)
+            .filter(|key| matches!(KeyType::check_type(key), KeyType::Macro | KeyType::TapHold)),
+        _ => None,
+    }
+}
@@ -0,0 +1,527 @@
+/*
+VIA configuration protocol over the raw HID report.
+
+Every request is one 32 byte report, byte 0 is the command id. The response is the request
+with the requested values filled in, or with byte 0 set to ID_UNHANDLED.
+The keycodes and the offsets are big endian, the keymap is seen as ROWS x (COLS * 2) per layer,
+the columns of the right half follow the left half columns.
+
+The keymap changes are saved to the store once no change came for VIA_SAVE_DELAY, so a keymap
+written in many buffer requests is saved once at the end instead of once per request.
+*/
+
+pub mod keycodes;
+
+use crate::config::{config::*, layers::*, layout::provide_layout};
+use crate::storage::{save_keymap, KeymapStore, StoreEntry};
+
+use embassy_time::Instant;
+use keycodes::*;
+
+pub const RAW_REPORT_SIZE: usize = 32;
+pub const VIA_PROTOCOL_VERSION: u16 = 0x000C;
+
+/* the keymap of both halves */
+const MATRIX_ROWS: usize = ROWS;
+const MATRIX_COLS: usize = COLS * 2;
+const DYNAMIC_KEYMAP_SIZE: usize = LAYERS * MATRIX_ROWS * MATRIX_COLS * 2;
+
+/* the buffer requests carry their data after the command, offset and size */
+const BUFFER_DATA_OFFSET: usize = 4;
+const BUFFER_DATA_MAX: usize = RAW_REPORT_SIZE - BUFFER_DATA_OFFSET;
+
+const ID_GET_PROTOCOL_VERSION: u8 = 0x01;
+const ID_GET_KEYBOARD_VALUE: u8 = 0x02;
+const ID_SET_KEYBOARD_VALUE: u8 = 0x03;
+const ID_DYNAMIC_KEYMAP_GET_KEYCODE: u8 = 0x04;
+const ID_DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+const ID_DYNAMIC_KEYMAP_RESET: u8 = 0x06;
+const ID_EEPROM_RESET: u8 = 0x0A;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT: u8 = 0x0C;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE: u8 = 0x0D;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER: u8 = 0x0E;
+const ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER: u8 = 0x0F;
+const ID_DYNAMIC_KEYMAP_MACRO_RESET: u8 = 0x10;
+const ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+const ID_DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+const ID_DYNAMIC_KEYMAP_SET_BUFFER: u8 = 0x13;
+const ID_UNHANDLED: u8 = 0xFF;
+
+/* the values of the keyboard value commands */
+const ID_UPTIME: u8 = 0x01;
+const ID_LAYOUT_OPTIONS: u8 = 0x02;
+
+pub struct Via {
+    /* the VIA macros, null terminated strings one after the other */
+    macros: [u8; VIA_MACRO_BUFFER_SIZE],
+    /* when the changed keymap is saved, None once it is saved */
+    save_deadline: Option<Instant>,
+}
+
+fn get_keycode(layers: &Layers, layer: usize, row: usize, col: usize) -> u16 {
+    if layer >= LAYERS || row >= MATRIX_ROWS || col >= MATRIX_COLS {
+        return KC_NO;
+    }
+
+    match layers.layers[layer].get(&(row as i8, col as i8)) {
+        Some(key) => to_via_keycode(key),
+        /* the missing positions fall through to the layers below */
+        None if layer > 0 => KC_TRNS,
+        None => KC_NO,
+    }
+}
+
+fn set_keycode(layers: &mut Layers, layer: usize, row: usize, col: usize, keycode: u16) {
+    if layer >= LAYERS || row >= MATRIX_ROWS || col >= MATRIX_COLS {
+        return;
+    }
+
+    let Some(key) = from_via_keycode(keycode) else {
+        #[cfg(feature = "debug")]
+        log::info!("Unsupported keycode {:#06x}", keycode);
+
+        return;
+    };
+
+    if layers
+        .layer(Layer(layer as u8))
+        .insert((row as i8, col as i8), key)
+        .is_err()
+    {
+        #[cfg(feature = "debug")]
+        log::info!("Layer {} full, keycode {:#06x} dropped!", layer, keycode);
+    }
+}
+
+/* the position of a keycode in the dynamic keymap buffer */
+fn buffer_position(index: usize) -> (usize, usize, usize) {
+    let layer = index / (MATRIX_ROWS * MATRIX_COLS);
+    let position = index % (MATRIX_ROWS * MATRIX_COLS);
+
+    (layer, position / MATRIX_COLS, position % MATRIX_COLS)
+}
+
+/* the offset and the size of a buffer request, limited to the buffer and the report */
+fn buffer_range(request: &[u8; RAW_REPORT_SIZE], buffer_size: usize) -> (usize, usize) {
+    /* a request past the end of the buffer gets nothing */
+    let offset = (u16::from_be_bytes([request[1], request[2]]) as usize).min(buffer_size);
+    let size = (request[3] as usize)
+        .min(BUFFER_DATA_MAX)
+        .min(buffer_size - offset);
+
+    (offset, size)
+}
+
+fn save<S: KeymapStore>(layers: &Layers, store: &mut S) {
+    if let Err(_error) = save_keymap(store, layers) {
+        #[cfg(feature = "debug")]
+        log::info!("Error saving the keymap: {:?}", _error);
+    }
+}
+
+impl Via {
+    /* load the macros saved by VIA */
+    pub fn load<S: KeymapStore>(store: &mut S) -> Self {
+        let mut via = Via {
+            macros: [0; VIA_MACRO_BUFFER_SIZE],
+            save_deadline: None,
+        };
+        store.read(StoreEntry::Macros, &mut via.macros);
+        via
+    }
+
+    pub fn macros(&self) -> &[u8] {
+        &self.macros
+    }
+
+    /* when the changed keymap has to be saved */
+    pub fn deadline(&self) -> Option<Instant> {
+        self.save_deadline
+    }
+
+    /* save the changed keymap once the changes stopped */
+    pub fn tick<S: KeymapStore>(&mut self, layers: &Layers, store: &mut S, now: Instant) {
+        if self.save_deadline.is_some_and(|deadline| now >= deadline) {
+            self.save_deadline = None;
+            save(layers, store);
+        }
+    }
+
+    fn save_macros<S: KeymapStore>(&self, store: &mut S) {
+        if let Err(_error) = store.write(StoreEntry::Macros, &self.macros) {
+            #[cfg(feature = "debug")]
+            log::info!("Error saving the macros: {:?}", _error);
+        }
+    }
+
+    /* handle one request and provide the response */
+    pub fn handle_request<S: KeymapStore>(
+        &mut self,
+        request: &[u8; RAW_REPORT_SIZE],
+        layers: &mut Layers,
+        store: &mut S,
+        now: Instant,
+    ) -> [u8; RAW_REPORT_SIZE] {
+        let mut response = *request;
+
+        match request[0] {
+            ID_GET_PROTOCOL_VERSION => {
+                response[1..3].copy_from_slice(&VIA_PROTOCOL_VERSION.to_be_bytes());
+            }
+            ID_GET_KEYBOARD_VALUE => match request[1] {
+                ID_UPTIME => {
+                    response[2..6].copy_from_slice(&(now.as_millis() as u32).to_be_bytes());
+                }
+                ID_LAYOUT_OPTIONS => {
+                    response[2..6].fill(0);
+                }
+                _ => response[0] = ID_UNHANDLED,
+            },
+            ID_SET_KEYBOARD_VALUE => match request[1] {
+                ID_LAYOUT_OPTIONS => { /* there are no layout options */ }
+                _ => response[0] = ID_UNHANDLED,
+            },
+            ID_DYNAMIC_KEYMAP_GET_KEYCODE => {
+                let keycode = get_keycode(
+                    layers,
+                    request[1] as usize,
+                    request[2] as usize,
+                    request[3] as usize,
+                );
+                response[4..6].copy_from_slice(&keycode.to_be_bytes());
+            }
+            ID_DYNAMIC_KEYMAP_SET_KEYCODE => {
+                set_keycode(
+                    layers,
+                    request[1] as usize,
+                    request[2] as usize,
+                    request[3] as usize,
+                    u16::from_be_bytes([request[4], request[5]]),
+                );
+                self.save_deadline = Some(now + VIA_SAVE_DELAY);
+            }
+            ID_DYNAMIC_KEYMAP_RESET => {
+                self.reset_keymap(layers, store);
+            }
+            ID_EEPROM_RESET => {
+                self.reset_keymap(layers, store);
+                self.reset_macros(store);
+            }
+            ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT => {
+                response[1] = VIA_MACRO_COUNT;
+            }
+            ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE => {
+                response[1..3].copy_from_slice(&(VIA_MACRO_BUFFER_SIZE as u16).to_be_bytes());
+            }
+            ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER => {
+                let (offset, size) = buffer_range(request, VIA_MACRO_BUFFER_SIZE);
+                response[BUFFER_DATA_OFFSET..BUFFER_DATA_OFFSET + size]
+                    .copy_from_slice(&self.macros[offset..offset + size]);
+            }
+            ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER => {
+                let (offset, size) = buffer_range(request, VIA_MACRO_BUFFER_SIZE);
+                self.macros[offset..offset + size]
+                    .copy_from_slice(&request[BUFFER_DATA_OFFSET..BUFFER_DATA_OFFSET + size]);
+                self.save_macros(store);
+            }
+            ID_DYNAMIC_KEYMAP_MACRO_RESET => {
+                self.reset_macros(store);
+            }
+            ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT => {
+                response[1] = LAYERS as u8;
+            }
+            ID_DYNAMIC_KEYMAP_GET_BUFFER => {
+                let (offset, size) = buffer_range(request, DYNAMIC_KEYMAP_SIZE);
+
+                for (index, byte) in (offset..offset + size).enumerate() {
+                    let (layer, row, col) = buffer_position(byte / 2);
+                    let keycode = get_keycode(layers, layer, row, col).to_be_bytes();
+                    response[BUFFER_DATA_OFFSET + index] = keycode[byte % 2];
+                }
+            }
+            ID_DYNAMIC_KEYMAP_SET_BUFFER => {
+                let (offset, size) = buffer_range(request, DYNAMIC_KEYMAP_SIZE);
+
+                /* every keycode is set whole, a keycode split by the request keeps its other byte */
+                let keycodes = if size > 0 {
+                    offset / 2..(offset + size).div_ceil(2)
+                } else {
+                    0..0
+                };
+
+                for keycode_index in keycodes {
+                    let (layer, row, col) = buffer_position(keycode_index);
+                    let mut keycode = get_keycode(layers, layer, row, col).to_be_bytes();
+
+                    for (byte_index, byte) in keycode.iter_mut().enumerate() {
+                        let byte_offset = keycode_index * 2 + byte_index;
+                        if (offset..offset + size).contains(&byte_offset) {
+                            *byte = request[BUFFER_DATA_OFFSET + byte_offset - offset];
+                        }
+                    }
+
+                    set_keycode(layers, layer, row, col, u16::from_be_bytes(keycode));
+                }
+
+                self.save_deadline = Some(now + VIA_SAVE_DELAY);
+            }
+            _ => response[0] = ID_UNHANDLED,
+        }
+
+        response
+    }
+
+    fn reset_keymap<S: KeymapStore>(&mut self, layers: &mut Layers, store: &mut S) {
+        *layers = provide_layout();
+        self.save_deadline = None;
+
+        if let Err(_error) = store.erase(StoreEntry::Keymap) {
+            #[cfg(feature = "debug")]
+            log::info!("Error erasing the keymap: {:?}", _error);
+        }
+    }
+
+    fn reset_macros<S: KeymapStore>(&mut self, store: &mut S) {
+        self.macros.fill(0);
+
+        if let Err(_error) = store.erase(StoreEntry::Macros) {
+            #[cfg(feature = "debug")]
+            log::info!("Error erasing the macros: {:?}", _error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::enums::HidKeys;
+    use crate::testing::*;
+
+    fn request(bytes: &[u8]) -> [u8; RAW_REPORT_SIZE] {
+        let mut request = [0; RAW_REPORT_SIZE];
+        request[..bytes.len()].copy_from_slice(bytes);
+        request
+    }
+
+    fn handle(via: &mut Via, layers: &mut Layers, bytes: &[u8]) -> [u8; RAW_REPORT_SIZE] {
+        via.handle_request(&request(bytes), layers, &mut MemoryStore::default(), t(0))
+    }
+
+    #[test]
+    fn protocol_version_and_unhandled() {
+        let mut via = Via::load(&mut MemoryStore::default());
+        let mut layers = Layers::new();
+
+        let response = handle(&mut via, &mut layers, &[ID_GET_PROTOCOL_VERSION]);
+        assert_eq!(response[1..3], VIA_PROTOCOL_VERSION.to_be_bytes());
+
+        let response = handle(&mut via, &mut layers, &[0x42, 1, 2]);
+        assert_eq!(response[..3], [ID_UNHANDLED, 1, 2]);
+
+        /* the macros of VIA are not played, VIA does not offer them */
+        let response = handle(&mut via, &mut layers, &[ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT]);
+        assert_eq!(response[1], 0);
+    }
+
+    #[test]
+    fn set_and_get_keycode() {
+        let mut via = Via::load(&mut MemoryStore::default());
+        let mut layers = Layers::new();
+        let mut store = MemoryStore::default();
+
+        let keycode = to_via_keycode(&HidKeys::B).to_be_bytes();
+        via.handle_request(
+            &request(&[
+                ID_DYNAMIC_KEYMAP_SET_KEYCODE,
+                1,
+                0,
+                2,
+                keycode[0],
+                keycode[1],
+            ]),
+            &mut layers,
+            &mut store,
+            t(0),
+        );
+        assert_eq!(layers.layers[1].get(&(0, 2)), Some(&HidKeys::B));
+        /* the change is saved once no other change came for VIA_SAVE_DELAY */
+        assert_eq!(via.deadline(), Some(t(VIA_SAVE_DELAY.as_millis())));
+        via.tick(&layers, &mut store, t(VIA_SAVE_DELAY.as_millis() - 1));
+        assert!(store.entries.is_empty());
+        via.tick(&layers, &mut store, t(VIA_SAVE_DELAY.as_millis()));
+        assert!(!store.entries.is_empty());
+        assert_eq!(via.deadline(), None);
+
+        let response = handle(
+            &mut via,
+            &mut layers,
+            &[ID_DYNAMIC_KEYMAP_GET_KEYCODE, 1, 0, 2],
+        );
+        assert_eq!(response[4..6], keycode);
+
+        /* the missing positions of the upper layers are transparent, outside of the keymap there is nothing */
+        let response = handle(
+            &mut via,
+            &mut layers,
+            &[ID_DYNAMIC_KEYMAP_GET_KEYCODE, 1, 0, 3],
+        );
+        assert_eq!(response[4..6], KC_TRNS.to_be_bytes());
+        let response = handle(
+            &mut via,
+            &mut layers,
+            &[ID_DYNAMIC_KEYMAP_GET_KEYCODE, 9, 0, 0],
+        );
+        assert_eq!(response[4..6], KC_NO.to_be_bytes());
+    }
+
+    #[test]
+    fn keymap_buffer() {
+        let mut via = Via::load(&mut MemoryStore::default());
+        let mut layers = Layers::new();
+
+        let a = to_via_keycode(&HidKeys::A).to_be_bytes();
+        let b = to_via_keycode(&HidKeys::B).to_be_bytes();
+
+        /* the keycodes of layer 0, row 0, col 1 and 2, starting inside the keycode of col 0 */
+        let response = handle(
+            &mut via,
+            &mut layers,
+            &[
+                ID_DYNAMIC_KEYMAP_SET_BUFFER,
+                0,
+                2,
+                4,
+                a[0],
+                a[1],
+                b[0],
+                b[1],
+            ],
+        );
+        assert_ne!(response[0], ID_UNHANDLED);
+        assert_eq!(layers.layers[0].get(&(0, 1)), Some(&HidKeys::A));
+        assert_eq!(layers.layers[0].get(&(0, 2)), Some(&HidKeys::B));
+
+        let response = handle(
+            &mut via,
+            &mut layers,
+            &[ID_DYNAMIC_KEYMAP_GET_BUFFER, 0, 2, 4],
+        );
+        assert_eq!(response[4..8], [a[0], a[1], b[0], b[1]]);
+    }
+
+    /* a keymap written in many requests is saved once, after the last one */
+    #[test]
+    fn keymap_saved_after_the_transfer() {
+        let mut via = Via::load(&mut MemoryStore::default());
+        let mut layers = Layers::new();
+        let mut store = MemoryStore::default();
+
+        let a = to_via_keycode(&HidKeys::A).to_be_bytes();
+        let delay = VIA_SAVE_DELAY.as_millis();
+
+        for (chunk, offset) in (0..DYNAMIC_KEYMAP_SIZE)
+            .step_by(BUFFER_DATA_MAX)
+            .enumerate()
+        {
+            let offset = (offset as u16).to_be_bytes();
+            let mut bytes = vec![ID_DYNAMIC_KEYMAP_SET_BUFFER, offset[0], offset[1], 2];
+            bytes.extend_from_slice(&a);
+
+            let now = t(chunk as u64 * (delay / 2));
+            via.tick(&layers, &mut store, now);
+            via.handle_request(&request(&bytes), &mut layers, &mut store, now);
+            assert!(store.entries.is_empty());
+        }
+
+        let last = via.deadline().unwrap();
+        via.tick(&layers, &mut store, last);
+        assert_eq!(store.entries.len(), 1);
+
+        /* a reset erases the keymap, there is nothing left to save */
+        handle(
+            &mut via,
+            &mut layers,
+            &[ID_DYNAMIC_KEYMAP_SET_BUFFER, 0, 0, 2, a[0], a[1]],
+        );
+        via.handle_request(
+            &request(&[ID_DYNAMIC_KEYMAP_RESET]),
+            &mut layers,
+            &mut store,
+            last,
+        );
+        assert_eq!(via.deadline(), None);
+        assert!(store.entries.is_empty());
+    }
+
+    /* a request past the end of a buffer gets nothing and changes nothing */
+    #[test]
+    fn buffer_past_the_end() {
+        let mut via = Via::load(&mut MemoryStore::default());
+        let mut layers = Layers::new();
+
+        for offset in [
+            VIA_MACRO_BUFFER_SIZE,
+            VIA_MACRO_BUFFER_SIZE + 1,
+            VIA_MACRO_BUFFER_SIZE - 1,
+            0xFFFF,
+        ] {
+            let offset = (offset as u16).to_be_bytes();
+
+            let response = handle(
+                &mut via,
+                &mut layers,
+                &[
+                    ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER,
+                    offset[0],
+                    offset[1],
+                    28,
+                    0x41,
+                    0x42,
+                ],
+            );
+            assert_ne!(response[0], ID_UNHANDLED);
+
+            let response = handle(
+                &mut via,
+                &mut layers,
+                &[ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER, offset[0], offset[1], 28],
+            );
+            assert_ne!(response[0], ID_UNHANDLED);
+        }
+
+        /* only the last byte of the buffer was in range */
+        assert_eq!(via.macros()[VIA_MACRO_BUFFER_SIZE - 1], 0x41);
+        assert!(via.macros()[..VIA_MACRO_BUFFER_SIZE - 1]
+            .iter()
+            .all(|byte| *byte == 0));
+
+        for offset in [
+            DYNAMIC_KEYMAP_SIZE,
+            DYNAMIC_KEYMAP_SIZE + 1,
+            DYNAMIC_KEYMAP_SIZE + 3,
+            0xFFFF,
+        ] {
+            let offset = (offset as u16).to_be_bytes();
+
+            handle(
+                &mut via,
+                &mut layers,
+                &[
+                    ID_DYNAMIC_KEYMAP_SET_BUFFER,
+                    offset[0],
+                    offset[1],
+                    28,
+                    0,
+                    0x04,
+                ],
+            );
+            handle(
+                &mut via,
+                &mut layers,
+                &[ID_DYNAMIC_KEYMAP_GET_BUFFER, offset[0], offset[1], 28],
+            );
+        }
+
+        assert!(layers.layers.iter().all(|keys| keys.is_empty()));
+    }
+}